@@ -0,0 +1,11 @@
+#version 450 core
+
+out layout(location=0) vec2 uv;
+
+// Covers the screen with a single triangle, without any vertex buffers
+void main()
+{
+    vec2 corner = vec2((gl_VertexID << 1) & 2, gl_VertexID & 2);
+    uv = corner;
+    gl_Position = vec4(corner * 2.0 - 1.0, 0.0, 1.0);
+}
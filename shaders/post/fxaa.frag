@@ -0,0 +1,48 @@
+#version 450 core
+
+in layout(location=0) vec2 uv;
+
+uniform sampler2D image;
+uniform vec2 texelSize;
+
+out vec4 color;
+
+#define FXAA_SPAN_MAX 8.0
+#define FXAA_REDUCE_MUL (1.0 / 8.0)
+#define FXAA_REDUCE_MIN (1.0 / 128.0)
+
+// Blur along the edges found from the luminance of the neighbouring pixels, after Timothy Lottes
+void main()
+{
+    vec3 luma = vec3(0.299, 0.587, 0.114);
+    vec3 rgbNW = texture(image, uv + vec2(-1.0, -1.0) * texelSize).rgb;
+    vec3 rgbNE = texture(image, uv + vec2( 1.0, -1.0) * texelSize).rgb;
+    vec3 rgbSW = texture(image, uv + vec2(-1.0,  1.0) * texelSize).rgb;
+    vec3 rgbSE = texture(image, uv + vec2( 1.0,  1.0) * texelSize).rgb;
+    vec3 rgbM  = texture(image, uv).rgb;
+
+    float lumaNW = dot(rgbNW, luma);
+    float lumaNE = dot(rgbNE, luma);
+    float lumaSW = dot(rgbSW, luma);
+    float lumaSE = dot(rgbSE, luma);
+    float lumaM  = dot(rgbM,  luma);
+    float lumaMin = min(lumaM, min(min(lumaNW, lumaNE), min(lumaSW, lumaSE)));
+    float lumaMax = max(lumaM, max(max(lumaNW, lumaNE), max(lumaSW, lumaSE)));
+
+    vec2 dir = vec2(
+        -((lumaNW + lumaNE) - (lumaSW + lumaSE)),
+         ((lumaNW + lumaSW) - (lumaNE + lumaSE)));
+    float dirReduce = max((lumaNW + lumaNE + lumaSW + lumaSE) * 0.25 * FXAA_REDUCE_MUL, FXAA_REDUCE_MIN);
+    float rcpDirMin = 1.0 / (min(abs(dir.x), abs(dir.y)) + dirReduce);
+    dir = clamp(dir * rcpDirMin, vec2(-FXAA_SPAN_MAX), vec2(FXAA_SPAN_MAX)) * texelSize;
+
+    vec3 rgbA = 0.5 * (
+        texture(image, uv + dir * (1.0 / 3.0 - 0.5)).rgb +
+        texture(image, uv + dir * (2.0 / 3.0 - 0.5)).rgb);
+    vec3 rgbB = rgbA * 0.5 + 0.25 * (
+        texture(image, uv + dir * -0.5).rgb +
+        texture(image, uv + dir *  0.5).rgb);
+    float lumaB = dot(rgbB, luma);
+
+    color = vec4((lumaB < lumaMin || lumaB > lumaMax) ? rgbA : rgbB, 1.0);
+}
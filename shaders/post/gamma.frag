@@ -0,0 +1,14 @@
+#version 450 core
+
+in layout(location=0) vec2 uv;
+
+uniform sampler2D image;
+uniform float gamma = 2.2;
+
+out vec4 color;
+
+void main()
+{
+    vec3 linear = texture(image, uv).rgb;
+    color = vec4(pow(max(linear, 0.0), vec3(1.0 / gamma)), 1.0);
+}
@@ -0,0 +1,20 @@
+#version 450 core
+
+in layout(location=0) vec2 uv;
+
+uniform sampler2D image;
+uniform float exposure = 1.0;
+
+out vec4 color;
+
+// Krzysztof Narkowicz's fit of the ACES filmic curve
+vec3 aces(vec3 x)
+{
+    return clamp((x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14), 0.0, 1.0);
+}
+
+void main()
+{
+    vec3 hdr = texture(image, uv).rgb;
+    color = vec4(aces(hdr * exposure), 1.0);
+}
@@ -0,0 +1,18 @@
+#version 450 core
+
+in layout(location=0) vec2 uv;
+
+uniform sampler2D image;
+uniform float vignetteStrength = 0.35;
+uniform float vignetteRadius = 0.75;
+
+out vec4 color;
+
+void main()
+{
+    vec3 rgb = texture(image, uv).rgb;
+    // 0.0 in the center and 1.0 in the corners
+    float dist = length(uv - 0.5) * sqrt(2.0);
+    float darkening = smoothstep(vignetteRadius, 1.0, dist);
+    color = vec4(rgb * (1.0 - vignetteStrength * darkening), 1.0);
+}
@@ -0,0 +1,6 @@
+#version 450 core
+
+// Depth only, the depth is written by the fixed function pipeline
+void main()
+{
+}
@@ -0,0 +1,10 @@
+#version 450 core
+
+in layout(location=0) vec3 position;
+
+uniform mat4x4 mvp = {{1.0,0.0,0.0,0.0}, {0.0,1.0,0.0,0.0}, {0.0,0.0,1.0,0.0}, {0.0,0.0,0.0,1.0}};
+
+void main()
+{
+    gl_Position = mvp * vec4(position, 1.0f);
+}
@@ -1,15 +1,130 @@
 #version 450 core
 
+#define MAX_LIGHTS 8
+#define MAX_CASCADES 4
+#define MAX_SPOT_SHADOWS 4
+
+#define DIRECTIONAL_LIGHT 0
+#define POINT_LIGHT 1
+#define SPOT_LIGHT 2
+
 in layout(location=3) vec4 inColor;
 in layout(location=4) vec3 inNormals;
+in layout(location=5) vec3 inPosition;
+
+struct Light {
+    int type;
+    vec3 position;
+    vec3 direction;
+    vec3 color;
+    vec3 attenuation; // constant, linear, quadratic
+    float innerCutoff; // cosine of the inner spot angle
+    float outerCutoff; // cosine of the outer spot angle
+    int shadowLayer; // -1 if the light casts no shadows
+};
+
+uniform Light lights[MAX_LIGHTS];
+uniform int lightCount = 0;
+uniform vec3 ambientColor = vec3(0.1);
+uniform vec3 cameraPosition = vec3(0.0);
+uniform float shininess = 32.0;
+uniform float specularStrength = 0.5;
+
+uniform sampler2DArrayShadow sunShadowMap;
+uniform mat4 cascadeMatrices[MAX_CASCADES];
+uniform int cascadeCount = 0;
+uniform sampler2DArrayShadow spotShadowMap;
+uniform mat4 spotShadowMatrices[MAX_SPOT_SHADOWS];
+uniform int pcfRadius = 1;
 
-uniform vec3 uColor = vec3(0.9f, 0.2f, 0.1f);
 out vec4 color;
-vec3 lightDirection = normalize(vec3(0.8, -0.5, 0.6));
+
+// Percentage closer filtering, averaging the depth comparisons of the surrounding texels
+float pcf(sampler2DArrayShadow shadowMap, int layer, vec3 coords, float bias)
+{
+    vec2 texel = 1.0 / vec2(textureSize(shadowMap, 0).xy);
+    float lit = 0.0;
+    int taps = 0;
+    for (int x = -pcfRadius; x <= pcfRadius; x++) {
+        for (int y = -pcfRadius; y <= pcfRadius; y++) {
+            lit += texture(shadowMap, vec4(coords.xy + vec2(x, y) * texel, layer, coords.z - bias));
+            taps++;
+        }
+    }
+    return lit / float(taps);
+}
+
+vec3 shadowCoords(mat4 lightMatrix)
+{
+    vec4 projected = lightMatrix * vec4(inPosition, 1.0);
+    return projected.xyz / projected.w * 0.5 + 0.5;
+}
+
+// Use the first, and thereby sharpest, cascade that covers the fragment
+float sunShadow(float bias)
+{
+    for (int i = 0; i < min(cascadeCount, MAX_CASCADES); i++) {
+        vec3 coords = shadowCoords(cascadeMatrices[i]);
+        if (all(greaterThan(coords, vec3(0.0))) && all(lessThan(coords, vec3(1.0)))) {
+            return pcf(sunShadowMap, i, coords, bias);
+        }
+    }
+    return 1.0;
+}
+
+float spotShadow(int layer, float bias)
+{
+    vec3 coords = shadowCoords(spotShadowMatrices[layer]);
+    if (coords.z > 1.0) {
+        return 1.0;
+    }
+    return pcf(spotShadowMap, layer, coords, bias);
+}
+
+vec3 blinnPhong(Light light, vec3 normal, vec3 viewDirection)
+{
+    vec3 toLight;
+    float falloff = 1.0;
+    if (light.type == DIRECTIONAL_LIGHT) {
+        toLight = -light.direction;
+    } else {
+        vec3 offset = light.position - inPosition;
+        float dist = length(offset);
+        toLight = offset / dist;
+        falloff = 1.0 / (light.attenuation.x + light.attenuation.y * dist + light.attenuation.z * dist * dist);
+    }
+
+    if (light.type == SPOT_LIGHT) {
+        float theta = dot(-toLight, light.direction);
+        falloff *= clamp((theta - light.outerCutoff) / max(light.innerCutoff - light.outerCutoff, 1e-4), 0.0, 1.0);
+    }
+
+    float diffuse = max(dot(normal, toLight), 0.0);
+
+    if (light.shadowLayer >= 0) {
+        float bias = max(0.002 * (1.0 - diffuse), 0.0005);
+        if (light.type == DIRECTIONAL_LIGHT) {
+            falloff *= sunShadow(bias);
+        } else if (light.type == SPOT_LIGHT) {
+            falloff *= spotShadow(light.shadowLayer, bias);
+        }
+    }
+
+    vec3 halfway = normalize(toLight + viewDirection);
+    float specular = diffuse > 0.0 ? pow(max(dot(normal, halfway), 0.0), shininess) : 0.0;
+
+    return falloff * light.color * (diffuse * inColor.rgb + specularStrength * specular);
+}
 
 void main()
 {
-    float scalar =max(dot(-lightDirection, inNormals),0);
-    color = vec4(scalar*inColor.xyz, inColor[3]);
+    vec3 normal = normalize(inNormals);
+    vec3 viewDirection = normalize(cameraPosition - inPosition);
+
+    vec3 lit = ambientColor * inColor.rgb;
+    for (int i = 0; i < min(lightCount, MAX_LIGHTS); i++) {
+        lit += blinnPhong(lights[i], normal, viewDirection);
+    }
+    color = vec4(lit, inColor.a);
     //color = vec4(inNormals, 1.0f);
-}
\ No newline at end of file
+}
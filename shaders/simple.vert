@@ -10,6 +10,8 @@ out layout(location=3) vec4 outColor;
 
 out layout(location=4) vec3 outNormals;
 
+out layout(location=5) vec3 outPosition;
+
 //vec3 mirrorVector = vec3(-1.0, -1.0, 1.0);
 uniform float oscilator = 0.5;
 
@@ -23,4 +25,5 @@ void main()
     gl_Position = mvp * vec4(position, 1.0f) ;
     outColor = color;
     outNormals = normalize(mat3(model)*normals);
+    outPosition = (model * vec4(position, 1.0f)).xyz;
 }
\ No newline at end of file
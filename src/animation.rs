@@ -0,0 +1,371 @@
+extern crate nalgebra_glm as glm;
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::scene_graph::SceneNode;
+
+// What a track animates on its node. The door of a helicopter slides by animating the position
+// of the door node, which is relative to the body.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Property {
+    Position,
+    Rotation, // Euler angles like SceneNode::rotation, in radians
+    Scale,
+}
+
+// How to get from one keyframe to the next
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interpolation {
+    Step,   // Hold each value until the next keyframe
+    Linear,
+    Cubic,  // Smooth curve through the keyframes (Catmull-Rom)
+    Slerp,  // Turn along the shortest arc between two orientations, for rotation tracks
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Keyframe {
+    pub time  : f32, // Seconds from the start of the clip
+    pub value : glm::Vec3,
+}
+
+// The values of one property of one node over time
+#[derive(Clone, Debug)]
+pub struct Track {
+    pub node          : String, // The name the node is bound to in the AnimationPlayer
+    pub property      : Property,
+    pub interpolation : Interpolation,
+    pub keyframes     : Vec<Keyframe>, // Sorted by time
+}
+
+impl Track {
+    pub fn new(node: &str, property: Property, interpolation: Interpolation) -> Self {
+        Track {
+            node: node.to_string(),
+            property,
+            interpolation,
+            keyframes: vec![],
+        }
+    }
+
+    // Add a keyframe, keeping them sorted
+    pub fn key(mut self, time: f32, value: glm::Vec3) -> Self {
+        let index = self.keyframes.iter().position(|keyframe| keyframe.time > time).unwrap_or(self.keyframes.len());
+        self.keyframes.insert(index, Keyframe { time, value });
+        self
+    }
+
+    pub fn duration(&self) -> f32 {
+        self.keyframes.last().map_or(0.0, |keyframe| keyframe.time)
+    }
+
+    // The value at the given time. Before the first keyframe and after the last, the value
+    // stays where it is.
+    pub fn sample(&self, time: f32) -> Option<glm::Vec3> {
+        let keys = &self.keyframes;
+        let first = keys.first()?;
+        let last = keys.last()?;
+        if time <= first.time {
+            return Some(first.value);
+        }
+        if time >= last.time {
+            return Some(last.value);
+        }
+
+        // The keyframes on either side of the time
+        let next = keys.iter().position(|keyframe| keyframe.time > time)?;
+        let (from, to) = (&keys[next - 1], &keys[next]);
+        let length = to.time - from.time;
+        let t = (time - from.time) / length;
+        Some(match self.interpolation {
+            Interpolation::Step => from.value,
+            Interpolation::Linear => glm::lerp(&from.value, &to.value, t),
+            Interpolation::Cubic => {
+                // Tangents from the neighbouring keyframes, taken one sided at the ends. Where
+                // the neighbours share a time, the curve levels off instead of dividing by zero.
+                let tangent = |i: usize| {
+                    let before = &keys[i.saturating_sub(1)];
+                    let after = &keys[(i + 1).min(keys.len() - 1)];
+                    let span = after.time - before.time;
+                    if span > 0.0 {
+                        (after.value - before.value) / span
+                    } else {
+                        glm::zero()
+                    }
+                };
+                hermite(&from.value, &(tangent(next - 1) * length), &to.value, &(tangent(next) * length), t)
+            }
+            Interpolation::Slerp => {
+                let rotation = glm::quat_slerp(&euler_to_quat(&from.value), &euler_to_quat(&to.value), t);
+                quat_to_euler(&rotation)
+            }
+        })
+    }
+}
+
+// A cubic Hermite curve from p0 to p1 with the tangents m0 and m1, at t from 0 to 1
+fn hermite(p0: &glm::Vec3, m0: &glm::Vec3, p1: &glm::Vec3, m1: &glm::Vec3, t: f32) -> glm::Vec3 {
+    let (t2, t3) = (t * t, t * t * t);
+    p0 * (2.0 * t3 - 3.0 * t2 + 1.0)
+        + m0 * (t3 - 2.0 * t2 + t)
+        + p1 * (-2.0 * t3 + 3.0 * t2)
+        + m1 * (t3 - t2)
+}
+
+// SceneNode rotations turn around z, then the turned y axis, then the turned x axis
+// (see renderer::update_node_transformations), which is the rotation Rz * Ry * Rx
+pub fn euler_to_quat(angles: &glm::Vec3) -> glm::Quat {
+    let x = glm::quat_angle_axis(angles.x, &glm::vec3(1.0, 0.0, 0.0));
+    let y = glm::quat_angle_axis(angles.y, &glm::vec3(0.0, 1.0, 0.0));
+    let z = glm::quat_angle_axis(angles.z, &glm::vec3(0.0, 0.0, 1.0));
+    z * y * x
+}
+
+// The y angle comes out between -pi/2 and pi/2. At either end the x and z axes line up, so only
+// the difference between those two angles matters, and z is left at 0.
+pub fn quat_to_euler(rotation: &glm::Quat) -> glm::Vec3 {
+    let m = glm::quat_to_mat3(rotation);
+    let y = (-m[(2, 0)]).clamp(-1.0, 1.0).asin();
+    if m[(0, 0)].hypot(m[(1, 0)]) < 1e-5 {
+        return glm::vec3((-m[(1, 2)]).atan2(m[(1, 1)]), y, 0.0);
+    }
+    glm::vec3(m[(2, 1)].atan2(m[(2, 2)]), y, m[(1, 0)].atan2(m[(0, 0)]))
+}
+
+// Tracks that play together, like the spinning rotors of a helicopter
+#[derive(Clone, Debug)]
+pub struct AnimationClip {
+    pub name   : String,
+    pub tracks : Vec<Track>,
+}
+
+impl AnimationClip {
+    pub fn new(name: &str) -> Self {
+        AnimationClip { name: name.to_string(), tracks: vec![] }
+    }
+
+    pub fn with_track(mut self, track: Track) -> Self {
+        self.tracks.push(track);
+        self
+    }
+
+    // Until the last keyframe of the longest track
+    pub fn duration(&self) -> f32 {
+        self.tracks.iter().map(Track::duration).fold(0.0, f32::max)
+    }
+}
+
+// What happens when a clip reaches its end
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoopMode {
+    Once,     // Stop at the last keyframe
+    Loop,     // Start over from the beginning
+    PingPong, // Play backwards to the beginning, then forwards again
+}
+
+impl LoopMode {
+    // Where in a clip of the given duration the given time since its start falls
+    pub fn clip_time(self, time: f32, duration: f32) -> f32 {
+        if duration <= 0.0 {
+            return 0.0;
+        }
+        match self {
+            LoopMode::Once => time.clamp(0.0, duration),
+            LoopMode::Loop => time.rem_euclid(duration),
+            LoopMode::PingPong => {
+                let time = time.rem_euclid(2.0 * duration);
+                if time > duration {
+                    2.0 * duration - time
+                } else {
+                    time
+                }
+            }
+        }
+    }
+}
+
+// A clip being played, and how
+pub struct PlayingClip {
+    pub clip       : Rc<AnimationClip>,
+    pub mode       : LoopMode,
+    pub speed      : f32, // 2 plays twice as fast, -1 backwards
+    pub start_time : f32, // When the clip was at its beginning
+}
+
+// Plays clips on the nodes it knows by name. Clips refer to nodes by name only, so one clip can
+// drive any number of helicopters, each with a player of its own.
+pub struct AnimationPlayer {
+    pub playing: Vec<PlayingClip>,
+
+    nodes: HashMap<String, *mut SceneNode>, // The nodes have to outlive the player, like for lights
+}
+
+impl AnimationPlayer {
+    pub fn new() -> Self {
+        AnimationPlayer { playing: vec![], nodes: HashMap::new() }
+    }
+
+    // Let tracks animate the node by this name
+    pub fn bind(&mut self, name: &str, node: &mut SceneNode) {
+        self.nodes.insert(name.to_string(), node as *mut SceneNode);
+    }
+
+    pub fn with_node(mut self, name: &str, node: &mut SceneNode) -> Self {
+        self.bind(name, node);
+        self
+    }
+
+    // Start playing the clip at the given time, replacing it if it was already playing
+    pub fn play(&mut self, clip: &Rc<AnimationClip>, mode: LoopMode, start_time: f32) -> &mut PlayingClip {
+        self.stop(&clip.name);
+        self.playing.push(PlayingClip { clip: Rc::clone(clip), mode, speed: 1.0, start_time });
+        self.playing.last_mut().unwrap()
+    }
+
+    #[allow(dead_code)]
+    pub fn stop(&mut self, name: &str) {
+        self.playing.retain(|playing| playing.clip.name != name);
+    }
+
+    // Set every animated property to its value at the given time. Clips played later win where
+    // two of them animate the same property.
+    pub fn apply(&self, time: f32) {
+        for playing in &self.playing {
+            let clip_time = playing.mode.clip_time((time - playing.start_time) * playing.speed, playing.clip.duration());
+            for track in &playing.clip.tracks {
+                let node = match self.nodes.get(&track.node) {
+                    Some(&node) => unsafe { &mut *node },
+                    None => continue,
+                };
+                if let Some(value) = track.sample(clip_time) {
+                    match track.property {
+                        Property::Position => node.position = value,
+                        Property::Rotation => node.rotation = value,
+                        Property::Scale => node.scale = value,
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: glm::Vec3, expected: glm::Vec3) {
+        assert!(glm::distance(&actual, &expected) < 1e-5, "{:?} != {:?}", actual, expected);
+    }
+
+    // A track of the x coordinate only
+    fn track(interpolation: Interpolation, keys: &[(f32, f32)]) -> Track {
+        keys.iter().fold(Track::new("node", Property::Position, interpolation), |track, &(time, x)| {
+            track.key(time, glm::vec3(x, 0.0, 0.0))
+        })
+    }
+
+    fn x(track: &Track, time: f32) -> f32 {
+        track.sample(time).unwrap().x
+    }
+
+    #[test]
+    fn tracks_hold_their_ends_outside_the_keyframes() {
+        for &interpolation in &[Interpolation::Step, Interpolation::Linear, Interpolation::Cubic, Interpolation::Slerp] {
+            let track = track(interpolation, &[(1.0, 0.5), (2.0, 1.0), (3.0, -0.5)]);
+            assert_close(track.sample(-10.0).unwrap(), glm::vec3(0.5, 0.0, 0.0));
+            assert_close(track.sample(1.0).unwrap(), glm::vec3(0.5, 0.0, 0.0));
+            assert_close(track.sample(3.0).unwrap(), glm::vec3(-0.5, 0.0, 0.0));
+            assert_close(track.sample(10.0).unwrap(), glm::vec3(-0.5, 0.0, 0.0));
+            // Every keyframe is hit exactly
+            assert_close(track.sample(2.0).unwrap(), glm::vec3(1.0, 0.0, 0.0));
+        }
+        assert_eq!(Track::new("node", Property::Scale, Interpolation::Linear).sample(0.0), None);
+    }
+
+    #[test]
+    fn step_tracks_hold_each_value_until_the_next_keyframe() {
+        let track = track(Interpolation::Step, &[(0.0, 1.0), (1.0, 2.0), (2.0, 3.0)]);
+        assert_eq!(x(&track, 0.5), 1.0);
+        assert_eq!(x(&track, 0.999), 1.0);
+        assert_eq!(x(&track, 1.0), 2.0);
+        assert_eq!(x(&track, 1.5), 2.0);
+    }
+
+    #[test]
+    fn linear_tracks_go_straight_between_keyframes() {
+        let track = track(Interpolation::Linear, &[(0.0, 1.0), (2.0, 3.0), (3.0, 0.0)]);
+        assert!((x(&track, 0.5) - 1.5).abs() < 1e-6);
+        assert!((x(&track, 1.0) - 2.0).abs() < 1e-6);
+        assert!((x(&track, 2.5) - 1.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn cubic_tracks_curve_through_the_keyframes() {
+        // Evenly spaced keyframes on a line stay on it
+        let line = track(Interpolation::Cubic, &[(0.0, 0.0), (1.0, 1.0), (2.0, 2.0), (3.0, 3.0)]);
+        for &time in &[0.25, 0.5, 1.25, 2.9] {
+            assert!((x(&line, time) - time).abs() < 1e-5);
+        }
+
+        // The peak is flat, and the sides are mirror images of each other
+        let hill = track(Interpolation::Cubic, &[(0.0, 0.0), (1.0, 1.0), (2.0, 0.0)]);
+        assert!((x(&hill, 0.5) - 0.625).abs() < 1e-6);
+        assert!((x(&hill, 1.5) - 0.625).abs() < 1e-6);
+        assert!(x(&hill, 0.99) < 1.0 && x(&hill, 1.01) < 1.0);
+    }
+
+    #[test]
+    fn cubic_tracks_with_keyframes_at_the_same_time_jump_between_them() {
+        let track = track(Interpolation::Cubic, &[(0.0, 0.0), (1.0, 1.0), (1.0, 5.0), (1.0, 6.0), (2.0, 7.0)]);
+        for i in 0..=40 {
+            let value = x(&track, i as f32 * 0.05);
+            assert!(value.is_finite(), "{} at {}", value, i as f32 * 0.05);
+        }
+        assert_eq!(x(&track, 1.0), 6.0);
+        assert!(x(&track, 0.999) < 1.01);
+    }
+
+    #[test]
+    fn slerp_tracks_turn_along_the_shortest_arc() {
+        let quarter = std::f32::consts::FRAC_PI_2;
+        let turn = Track::new("node", Property::Rotation, Interpolation::Slerp)
+            .key(0.0, glm::vec3(0.0, 0.0, 0.0))
+            .key(1.0, glm::vec3(0.0, quarter, 0.0));
+        assert_close(turn.sample(0.5).unwrap(), glm::vec3(0.0, quarter / 2.0, 0.0));
+
+        // From just left of straight back to just right of it, through straight back rather than
+        // all the way around through straight ahead
+        let back = Track::new("node", Property::Rotation, Interpolation::Slerp)
+            .key(0.0, glm::vec3(0.0, 0.0, 3.0))
+            .key(1.0, glm::vec3(0.0, 0.0, -3.0));
+        let halfway = back.sample(0.5).unwrap();
+        assert!((halfway.z.abs() - std::f32::consts::PI).abs() < 1e-4, "{:?}", halfway);
+        let quarter_way = back.sample(0.25).unwrap();
+        assert!(quarter_way.z > 3.0, "{:?}", quarter_way);
+    }
+
+    #[test]
+    fn clip_times_loop_bounce_or_stop() {
+        let close = |a: f32, b: f32| (a - b).abs() < 1e-5;
+        assert!(close(LoopMode::Loop.clip_time(0.5, 2.0), 0.5));
+        assert!(close(LoopMode::Loop.clip_time(2.5, 2.0), 0.5));
+        assert!(close(LoopMode::Loop.clip_time(-0.5, 2.0), 1.5));
+
+        assert!(close(LoopMode::PingPong.clip_time(0.5, 2.0), 0.5));
+        assert!(close(LoopMode::PingPong.clip_time(2.0, 2.0), 2.0));
+        assert!(close(LoopMode::PingPong.clip_time(2.5, 2.0), 1.5));
+        assert!(close(LoopMode::PingPong.clip_time(4.5, 2.0), 0.5));
+        assert!(close(LoopMode::PingPong.clip_time(-0.5, 2.0), 0.5));
+
+        assert!(close(LoopMode::Once.clip_time(0.5, 2.0), 0.5));
+        assert!(close(LoopMode::Once.clip_time(7.0, 2.0), 2.0));
+        assert!(close(LoopMode::Once.clip_time(-1.0, 2.0), 0.0));
+
+        for &mode in &[LoopMode::Once, LoopMode::Loop, LoopMode::PingPong] {
+            assert_eq!(mode.clip_time(3.0, 0.0), 0.0);
+        }
+    }
+}
@@ -0,0 +1,397 @@
+extern crate nalgebra_glm as glm;
+
+use std::ffi::CString;
+use std::{os::raw::c_void, ptr};
+
+use crate::framebuffer::Framebuffer;
+use crate::mesh::Mesh;
+use crate::shader::{Shader, ShaderBuilder};
+use crate::shadow::ShadowMap;
+
+// The calls the renderer makes into the graphics API. Keeping them behind a trait lets the same
+// code draw with OpenGL, or record what it would have drawn.
+pub trait RenderBackend {
+    // Upload a mesh and return the id of its vertex array object
+    fn create_vao(&mut self, mesh: &Mesh) -> u32;
+    // A vertex array object without buffers, for shaders that make up their own vertices
+    fn create_empty_vao(&mut self) -> u32;
+    // Compile and link the given shader files into a program
+    fn create_program(&mut self, shader_paths: &[&str]) -> Shader;
+    // An offscreen target with a color and a depth attachment, multisampled if `samples` > 0
+    fn create_framebuffer(&mut self, width: i32, height: i32, samples: i32) -> Framebuffer;
+    fn delete_framebuffer(&mut self, framebuffer: &Framebuffer);
+    // A layered depth texture to render shadows into, one layer at a time
+    fn create_shadow_map(&mut self, size: i32, layers: usize) -> ShadowMap;
+
+    // Draw into the framebuffer with the given id from now on, 0 being the window
+    fn bind_framebuffer(&mut self, framebuffer_id: u32, width: i32, height: i32);
+    // Make the layer the depth attachment of the shadow map's framebuffer
+    fn attach_shadow_layer(&mut self, shadow_map: &ShadowMap, layer: usize);
+    // Copy the color of `source` into the framebuffer with the given id and size
+    fn blit(&mut self, source: &Framebuffer, target_id: u32, width: i32, height: i32, filter: Filter);
+    // The color of the framebuffer with the given id as 8 bit RGBA, rows bottom to top
+    fn read_pixels(&mut self, framebuffer_id: u32, width: i32, height: i32) -> Vec<u8>;
+    // Clear the bound framebuffer, the color only if one is given
+    fn clear(&mut self, color: Option<[f32; 4]>, depth: bool);
+    fn set_capability(&mut self, capability: Capability, enabled: bool);
+    // The slope scaled and constant offsets added to depths while Capability::DepthBias is on
+    fn set_depth_bias(&mut self, factor: f32, units: f32);
+
+    // Uniforms set from now on go to this program
+    fn use_program(&mut self, shader: &Shader);
+    fn set_uniform(&mut self, name: &str, value: Uniform);
+    fn bind_texture(&mut self, unit: u32, kind: TextureKind, texture_id: u32);
+    fn bind_vao(&mut self, vao_id: u32);
+    // Draw triangles from the bound vertex array object
+    fn draw_elements(&mut self, index_count: i32);
+    // Draw triangles from the first `vertex_count` vertices, without an index buffer
+    fn draw_arrays(&mut self, vertex_count: i32);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Uniform {
+    Int(i32),
+    Float(f32),
+    Vec2(glm::Vec2),
+    Vec3(glm::Vec3),
+    Mat4(glm::Mat4),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Capability {
+    DepthTest,
+    FaceCulling,
+    Blending,
+    DepthBias,
+    Multisampling,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TextureKind {
+    Texture2d,
+    Texture2dArray,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Filter {
+    Nearest,
+    Linear,
+}
+
+// The size of an array in bytes
+fn byte_size_of_array<T>(val: &[T]) -> isize {
+    std::mem::size_of_val(val) as isize
+}
+
+// Get the OpenGL-compatible pointer to an arbitrary array of numbers
+fn pointer_to_array<T>(val: &[T]) -> *const c_void {
+    &val[0] as *const T as *const c_void
+}
+
+// Create a buffer with the given data and bind it
+unsafe fn create_buffer<T>(target: u32, data: &[T]) -> u32 {
+    let mut buffer_id: u32 = 0;
+    gl::GenBuffers(1, &mut buffer_id);
+    gl::BindBuffer(target, buffer_id);
+    gl::BufferData(target, byte_size_of_array(data), pointer_to_array(data), gl::STATIC_DRAW);
+    buffer_id
+}
+
+pub struct GlBackend {
+    program_id: u32,
+}
+
+impl GlBackend {
+    // The OpenGL context has to be current whenever the backend is used
+    pub unsafe fn new() -> Self {
+        GlBackend { program_id: 0 }
+    }
+}
+
+// HDR color, so the lighting can go above 1.0 until the tonemapping pass
+const COLOR_FORMAT: u32 = gl::RGBA16F;
+const DEPTH_FORMAT: u32 = gl::DEPTH_COMPONENT24;
+
+impl RenderBackend for GlBackend {
+    fn create_vao(&mut self, mesh: &Mesh) -> u32 {
+        unsafe {
+            let mut array_id: u32 = 0;
+            gl::GenVertexArrays(1, &mut array_id);
+            gl::BindVertexArray(array_id);
+
+            // Positions, colors and normals go into attributes 0, 1 and 2
+            create_buffer(gl::ARRAY_BUFFER, &mesh.vertices);
+            gl::VertexAttribPointer(0, 3, gl::FLOAT, gl::FALSE, 12, ptr::null());
+            gl::EnableVertexAttribArray(0);
+
+            create_buffer(gl::ARRAY_BUFFER, &mesh.colors);
+            gl::VertexAttribPointer(1, 4, gl::FLOAT, gl::FALSE, 16, ptr::null());
+            gl::EnableVertexAttribArray(1);
+
+            create_buffer(gl::ARRAY_BUFFER, &mesh.normals);
+            gl::VertexAttribPointer(2, 3, gl::FLOAT, gl::FALSE, 12, ptr::null());
+            gl::EnableVertexAttribArray(2);
+
+            create_buffer(gl::ELEMENT_ARRAY_BUFFER, &mesh.indices);
+            array_id
+        }
+    }
+
+    fn create_empty_vao(&mut self) -> u32 {
+        let mut array_id: u32 = 0;
+        unsafe { gl::GenVertexArrays(1, &mut array_id) };
+        array_id
+    }
+
+    fn create_program(&mut self, shader_paths: &[&str]) -> Shader {
+        unsafe {
+            shader_paths
+                .iter()
+                .fold(ShaderBuilder::new(), |builder, path| builder.attach_file(path))
+                .link()
+        }
+    }
+
+    // Single sampled targets keep their color in a texture, so later passes can sample it.
+    // Multisampled targets use renderbuffers instead, and have to be resolved before use.
+    fn create_framebuffer(&mut self, width: i32, height: i32, samples: i32) -> Framebuffer {
+        let mut framebuffer = Framebuffer {
+            id: 0,
+            color_texture: 0,
+            color_renderbuffer: 0,
+            depth_renderbuffer: 0,
+            width,
+            height,
+            samples,
+        };
+        unsafe {
+            gl::GenFramebuffers(1, &mut framebuffer.id);
+            gl::BindFramebuffer(gl::FRAMEBUFFER, framebuffer.id);
+
+            if samples > 0 {
+                gl::GenRenderbuffers(1, &mut framebuffer.color_renderbuffer);
+                gl::BindRenderbuffer(gl::RENDERBUFFER, framebuffer.color_renderbuffer);
+                gl::RenderbufferStorageMultisample(gl::RENDERBUFFER, samples, COLOR_FORMAT, width, height);
+                gl::FramebufferRenderbuffer(gl::FRAMEBUFFER, gl::COLOR_ATTACHMENT0, gl::RENDERBUFFER, framebuffer.color_renderbuffer);
+
+                gl::GenRenderbuffers(1, &mut framebuffer.depth_renderbuffer);
+                gl::BindRenderbuffer(gl::RENDERBUFFER, framebuffer.depth_renderbuffer);
+                gl::RenderbufferStorageMultisample(gl::RENDERBUFFER, samples, DEPTH_FORMAT, width, height);
+            } else {
+                gl::GenTextures(1, &mut framebuffer.color_texture);
+                gl::BindTexture(gl::TEXTURE_2D, framebuffer.color_texture);
+                gl::TexImage2D(
+                    gl::TEXTURE_2D,
+                    0,
+                    COLOR_FORMAT as i32,
+                    width,
+                    height,
+                    0,
+                    gl::RGBA,
+                    gl::FLOAT,
+                    ptr::null(),
+                );
+                gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::LINEAR as i32);
+                gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
+                gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as i32);
+                gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as i32);
+                gl::BindTexture(gl::TEXTURE_2D, 0);
+                gl::FramebufferTexture2D(gl::FRAMEBUFFER, gl::COLOR_ATTACHMENT0, gl::TEXTURE_2D, framebuffer.color_texture, 0);
+
+                gl::GenRenderbuffers(1, &mut framebuffer.depth_renderbuffer);
+                gl::BindRenderbuffer(gl::RENDERBUFFER, framebuffer.depth_renderbuffer);
+                gl::RenderbufferStorage(gl::RENDERBUFFER, DEPTH_FORMAT, width, height);
+            }
+            gl::FramebufferRenderbuffer(gl::FRAMEBUFFER, gl::DEPTH_ATTACHMENT, gl::RENDERBUFFER, framebuffer.depth_renderbuffer);
+            gl::BindRenderbuffer(gl::RENDERBUFFER, 0);
+
+            let status = gl::CheckFramebufferStatus(gl::FRAMEBUFFER);
+            if status != gl::FRAMEBUFFER_COMPLETE {
+                panic!("Framebuffer is incomplete, status 0x{:x}", status);
+            }
+            gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+        }
+        framebuffer
+    }
+
+    fn delete_framebuffer(&mut self, framebuffer: &Framebuffer) {
+        unsafe {
+            gl::DeleteFramebuffers(1, &framebuffer.id);
+            if framebuffer.color_texture != 0 {
+                gl::DeleteTextures(1, &framebuffer.color_texture);
+            }
+            if framebuffer.color_renderbuffer != 0 {
+                gl::DeleteRenderbuffers(1, &framebuffer.color_renderbuffer);
+            }
+            gl::DeleteRenderbuffers(1, &framebuffer.depth_renderbuffer);
+        }
+    }
+
+    fn create_shadow_map(&mut self, size: i32, layers: usize) -> ShadowMap {
+        unsafe {
+            let mut texture_id: u32 = 0;
+            gl::GenTextures(1, &mut texture_id);
+            gl::BindTexture(gl::TEXTURE_2D_ARRAY, texture_id);
+            gl::TexImage3D(
+                gl::TEXTURE_2D_ARRAY,
+                0,
+                gl::DEPTH_COMPONENT32F as i32,
+                size,
+                size,
+                layers as i32,
+                0,
+                gl::DEPTH_COMPONENT,
+                gl::FLOAT,
+                ptr::null(),
+            );
+            gl::TexParameteri(gl::TEXTURE_2D_ARRAY, gl::TEXTURE_MIN_FILTER, gl::LINEAR as i32);
+            gl::TexParameteri(gl::TEXTURE_2D_ARRAY, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
+            gl::TexParameteri(gl::TEXTURE_2D_ARRAY, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_BORDER as i32);
+            gl::TexParameteri(gl::TEXTURE_2D_ARRAY, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_BORDER as i32);
+            let border = [1.0f32, 1.0, 1.0, 1.0];
+            gl::TexParameterfv(gl::TEXTURE_2D_ARRAY, gl::TEXTURE_BORDER_COLOR, border.as_ptr());
+            // Let the hardware do the depth comparison, so sampling gives us a filtered lit factor
+            gl::TexParameteri(gl::TEXTURE_2D_ARRAY, gl::TEXTURE_COMPARE_MODE, gl::COMPARE_REF_TO_TEXTURE as i32);
+            gl::TexParameteri(gl::TEXTURE_2D_ARRAY, gl::TEXTURE_COMPARE_FUNC, gl::LEQUAL as i32);
+
+            let mut framebuffer_id: u32 = 0;
+            gl::GenFramebuffers(1, &mut framebuffer_id);
+            gl::BindFramebuffer(gl::FRAMEBUFFER, framebuffer_id);
+            gl::FramebufferTextureLayer(gl::FRAMEBUFFER, gl::DEPTH_ATTACHMENT, texture_id, 0, 0);
+            gl::DrawBuffer(gl::NONE);
+            gl::ReadBuffer(gl::NONE);
+            if gl::CheckFramebufferStatus(gl::FRAMEBUFFER) != gl::FRAMEBUFFER_COMPLETE {
+                panic!("Shadow map framebuffer is incomplete");
+            }
+            gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+
+            ShadowMap { framebuffer_id, texture_id, size, layers }
+        }
+    }
+
+    fn bind_framebuffer(&mut self, framebuffer_id: u32, width: i32, height: i32) {
+        unsafe {
+            gl::BindFramebuffer(gl::FRAMEBUFFER, framebuffer_id);
+            gl::Viewport(0, 0, width, height);
+        }
+    }
+
+    fn attach_shadow_layer(&mut self, shadow_map: &ShadowMap, layer: usize) {
+        unsafe {
+            gl::FramebufferTextureLayer(gl::FRAMEBUFFER, gl::DEPTH_ATTACHMENT, shadow_map.texture_id, 0, layer as i32);
+        }
+    }
+
+    fn blit(&mut self, source: &Framebuffer, target_id: u32, width: i32, height: i32, filter: Filter) {
+        let filter = match filter {
+            Filter::Nearest => gl::NEAREST,
+            Filter::Linear => gl::LINEAR,
+        };
+        unsafe {
+            gl::BindFramebuffer(gl::READ_FRAMEBUFFER, source.id);
+            gl::BindFramebuffer(gl::DRAW_FRAMEBUFFER, target_id);
+            gl::BlitFramebuffer(
+                0, 0, source.width, source.height,
+                0, 0, width, height,
+                gl::COLOR_BUFFER_BIT,
+                filter,
+            );
+            gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+        }
+    }
+
+    fn read_pixels(&mut self, framebuffer_id: u32, width: i32, height: i32) -> Vec<u8> {
+        let mut pixels = vec![0u8; (width * height * 4) as usize];
+        unsafe {
+            gl::BindFramebuffer(gl::READ_FRAMEBUFFER, framebuffer_id);
+            if framebuffer_id == 0 {
+                gl::ReadBuffer(gl::BACK);
+            }
+            gl::PixelStorei(gl::PACK_ALIGNMENT, 1);
+            gl::ReadPixels(
+                0, 0, width, height,
+                gl::RGBA,
+                gl::UNSIGNED_BYTE,
+                pixels.as_mut_ptr() as *mut c_void,
+            );
+            gl::BindFramebuffer(gl::READ_FRAMEBUFFER, 0);
+        }
+        pixels
+    }
+
+    fn clear(&mut self, color: Option<[f32; 4]>, depth: bool) {
+        let mut mask = 0;
+        if let Some([r, g, b, a]) = color {
+            unsafe { gl::ClearColor(r, g, b, a) };
+            mask |= gl::COLOR_BUFFER_BIT;
+        }
+        if depth {
+            mask |= gl::DEPTH_BUFFER_BIT;
+        }
+        unsafe { gl::Clear(mask) }
+    }
+
+    fn set_capability(&mut self, capability: Capability, enabled: bool) {
+        let capability = match capability {
+            Capability::DepthTest => gl::DEPTH_TEST,
+            Capability::FaceCulling => gl::CULL_FACE,
+            Capability::Blending => gl::BLEND,
+            Capability::DepthBias => gl::POLYGON_OFFSET_FILL,
+            Capability::Multisampling => gl::MULTISAMPLE,
+        };
+        unsafe {
+            if enabled {
+                gl::Enable(capability);
+            } else {
+                gl::Disable(capability);
+            }
+        }
+    }
+
+    fn set_depth_bias(&mut self, factor: f32, units: f32) {
+        unsafe { gl::PolygonOffset(factor, units) }
+    }
+
+    fn use_program(&mut self, shader: &Shader) {
+        self.program_id = shader.program_id;
+        unsafe { shader.activate() }
+    }
+
+    fn set_uniform(&mut self, name: &str, value: Uniform) {
+        let name = CString::new(name).expect("CString::new failed");
+        unsafe {
+            let location = gl::GetUniformLocation(self.program_id, name.as_ptr());
+            match value {
+                Uniform::Int(value) => gl::Uniform1i(location, value),
+                Uniform::Float(value) => gl::Uniform1f(location, value),
+                Uniform::Vec2(value) => gl::Uniform2f(location, value.x, value.y),
+                Uniform::Vec3(value) => gl::Uniform3fv(location, 1, value.as_ptr()),
+                Uniform::Mat4(value) => gl::UniformMatrix4fv(location, 1, gl::FALSE, value.as_ptr()),
+            }
+        }
+    }
+
+    fn bind_texture(&mut self, unit: u32, kind: TextureKind, texture_id: u32) {
+        let target = match kind {
+            TextureKind::Texture2d => gl::TEXTURE_2D,
+            TextureKind::Texture2dArray => gl::TEXTURE_2D_ARRAY,
+        };
+        unsafe {
+            gl::ActiveTexture(gl::TEXTURE0 + unit);
+            gl::BindTexture(target, texture_id);
+            gl::ActiveTexture(gl::TEXTURE0);
+        }
+    }
+
+    fn bind_vao(&mut self, vao_id: u32) {
+        unsafe { gl::BindVertexArray(vao_id) }
+    }
+
+    fn draw_elements(&mut self, index_count: i32) {
+        unsafe { gl::DrawElements(gl::TRIANGLES, index_count, gl::UNSIGNED_INT, ptr::null()) }
+    }
+
+    fn draw_arrays(&mut self, vertex_count: i32) {
+        unsafe { gl::DrawArrays(gl::TRIANGLES, 0, vertex_count) }
+    }
+}
@@ -0,0 +1,665 @@
+extern crate nalgebra_glm as glm;
+
+use std::convert::TryInto;
+
+use crate::mesh::Mesh;
+
+// An axis aligned bounding box
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Aabb {
+    pub min : glm::Vec3,
+    pub max : glm::Vec3,
+}
+
+impl Aabb {
+    // A box around nothing, which grows to fit whatever is added to it
+    pub fn empty() -> Self {
+        Aabb {
+            min : glm::vec3(f32::MAX, f32::MAX, f32::MAX),
+            max : glm::vec3(f32::MIN, f32::MIN, f32::MIN),
+        }
+    }
+
+    pub fn around(points: &[glm::Vec3]) -> Self {
+        points.iter().fold(Aabb::empty(), |bounds, point| bounds.grow(point))
+    }
+
+    pub fn grow(&self, point: &glm::Vec3) -> Self {
+        Aabb { min: glm::min2(&self.min, point), max: glm::max2(&self.max, point) }
+    }
+
+    pub fn union(&self, other: &Aabb) -> Self {
+        Aabb { min: glm::min2(&self.min, &other.min), max: glm::max2(&self.max, &other.max) }
+    }
+
+    pub fn center(&self) -> glm::Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn size(&self) -> glm::Vec3 {
+        self.max - self.min
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        let size = self.size();
+        if size.x < 0.0 {
+            return 0.0;
+        }
+        2.0 * (size.x * size.y + size.y * size.z + size.z * size.x)
+    }
+
+    pub fn intersects(&self, other: &Aabb) -> bool {
+        self.min.x <= other.max.x
+            && self.max.x >= other.min.x
+            && self.min.y <= other.max.y
+            && self.max.y >= other.min.y
+            && self.min.z <= other.max.z
+            && self.max.z >= other.min.z
+    }
+
+    #[allow(dead_code)]
+    pub fn intersects_sphere(&self, center: &glm::Vec3, radius: f32) -> bool {
+        let closest = glm::clamp_vec(center, &self.min, &self.max);
+        glm::distance2(&closest, center) <= radius * radius
+    }
+
+    // Where the ray enters the box, in units of its direction, given one over the direction.
+    // None if it misses, or only gets there after `max_distance`.
+    pub fn ray_distance(&self, origin: &glm::Vec3, inverse_direction: &glm::Vec3, max_distance: f32) -> Option<f32> {
+        let t0 = (self.min - origin).component_mul(inverse_direction);
+        let t1 = (self.max - origin).component_mul(inverse_direction);
+        let near = glm::min2(&t0, &t1).max().max(0.0);
+        let far = glm::max2(&t0, &t1).min().min(max_distance);
+        if near <= far {
+            Some(near)
+        } else {
+            None
+        }
+    }
+}
+
+// Where a ray hit a triangle of the mesh
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug)]
+pub struct BvhHit {
+    pub distance : f32, // In units of the ray direction
+    pub triangle : u32, // Index of the triangle in the mesh, the first of its indices divided by 3
+    pub point    : glm::Vec3,
+    pub normal   : glm::Vec3, // Facing the way the triangle winds, counter clockwise seen from the front
+}
+
+// Interior nodes have their children next to each other, at `first` and `first + 1`. Leaves
+// hold `count` triangles from `first` in the triangle order of the tree.
+#[derive(Clone, Copy, Debug)]
+struct BvhNode {
+    bounds : Aabb,
+    first  : u32,
+    count  : u32, // 0 for interior nodes
+}
+
+const BINS: usize = 12;
+const MAX_LEAF_SIZE: usize = 8; // Larger leaves are always split, however little it gains
+const MAGIC: &[u8; 4] = b"GBVH";
+const VERSION: u32 = 1;
+
+// A bounding volume hierarchy over the triangles of a mesh, to find the few triangles a ray,
+// sphere or box can touch without testing every one of them. Built top down, splitting each node
+// where the surface area heuristic says rays will be cheapest to trace, estimated in bins.
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+    order: Vec<u32>, // Triangle indices in the order the leaves refer to them
+    triangles: Vec<[glm::Vec3; 3]>,
+}
+
+impl Bvh {
+    pub fn build(mesh: &Mesh) -> Self {
+        let triangles = mesh_triangles(mesh);
+        let bounds: Vec<Aabb> = triangles.iter().map(|triangle| Aabb::around(triangle)).collect();
+        let centroids: Vec<glm::Vec3> = bounds.iter().map(Aabb::center).collect();
+        let mut bvh = Bvh {
+            nodes: vec![BvhNode { bounds: Aabb::empty(), first: 0, count: triangles.len() as u32 }],
+            order: (0..triangles.len() as u32).collect(),
+            triangles,
+        };
+
+        let mut pending = vec![0];
+        while let Some(index) = pending.pop() {
+            let node = bvh.nodes[index];
+            let (first, count) = (node.first as usize, node.count as usize);
+            let order = &mut bvh.order[first..first + count];
+            let node_bounds = order.iter().fold(Aabb::empty(), |total, &i| total.union(&bounds[i as usize]));
+            bvh.nodes[index].bounds = node_bounds;
+            if count <= 2 {
+                continue;
+            }
+
+            let split = match best_split(order, &bounds, &centroids) {
+                Some(split) => split,
+                None => continue,
+            };
+            let leaf_cost = count as f32 * node_bounds.surface_area();
+            if split.cost >= leaf_cost && count <= MAX_LEAF_SIZE {
+                continue;
+            }
+
+            // Triangles in the bins before the split go to the left child
+            let (axis, min, scale) = (split.axis, split.centroid_min, split.scale);
+            let bin = |i: u32| (((centroids[i as usize][axis] - min) * scale) as usize).min(BINS - 1);
+            let mut left = 0;
+            for i in 0..count {
+                if bin(order[i]) < split.bin {
+                    order.swap(i, left);
+                    left += 1;
+                }
+            }
+            if left == 0 || left == count {
+                continue;
+            }
+
+            let children = bvh.nodes.len();
+            bvh.nodes.push(BvhNode { bounds: Aabb::empty(), first: first as u32, count: left as u32 });
+            bvh.nodes.push(BvhNode { bounds: Aabb::empty(), first: (first + left) as u32, count: (count - left) as u32 });
+            bvh.nodes[index].first = children as u32;
+            bvh.nodes[index].count = 0;
+            pending.push(children);
+            pending.push(children + 1);
+        }
+        bvh
+    }
+
+    // Read the hierarchy saved for the mesh, or build it and save it for the next time
+    pub fn load_or_build(mesh: &Mesh, path: &str) -> Self {
+        match Bvh::load(mesh, path) {
+            Ok(bvh) => return bvh,
+            Err(e) => println!("Building a new BVH. {}", e),
+        }
+        let bvh = Bvh::build(mesh);
+        match std::fs::write(path, bvh.to_bytes(mesh)) {
+            Ok(()) => println!("Saved the BVH to {}", path),
+            Err(e) => println!("Failed to save the BVH to {}: {}", path, e),
+        }
+        bvh
+    }
+
+    pub fn load(mesh: &Mesh, path: &str) -> Result<Self, String> {
+        let bytes = std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+        Bvh::from_bytes(mesh, &bytes).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    // The tree, saved as
+    //
+    //     "GBVH", version, fingerprint of the mesh (u64)
+    //     node count, then per node min xyz, max xyz, first, count
+    //     triangle count, then the triangle order
+    //
+    // with every number little endian, and four bytes unless noted
+    pub fn to_bytes(&self, mesh: &Mesh) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes.extend_from_slice(&fingerprint(mesh).to_le_bytes());
+        bytes.extend_from_slice(&(self.nodes.len() as u32).to_le_bytes());
+        for node in &self.nodes {
+            for value in node.bounds.min.iter().chain(node.bounds.max.iter()) {
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+            bytes.extend_from_slice(&node.first.to_le_bytes());
+            bytes.extend_from_slice(&node.count.to_le_bytes());
+        }
+        bytes.extend_from_slice(&(self.order.len() as u32).to_le_bytes());
+        for index in &self.order {
+            bytes.extend_from_slice(&index.to_le_bytes());
+        }
+        bytes
+    }
+
+    // Read a tree saved by `to_bytes`, which has to have been built for this very mesh
+    pub fn from_bytes(mesh: &Mesh, bytes: &[u8]) -> Result<Self, String> {
+        let mut reader = Reader { bytes, offset: 0 };
+        if reader.take(4)? != MAGIC {
+            return Err("Not a BVH file".to_string());
+        }
+        let version = reader.u32()?;
+        if version != VERSION {
+            return Err(format!("Unsupported BVH version {}", version));
+        }
+        if reader.u64()? != fingerprint(mesh) {
+            return Err("Built for a different mesh".to_string());
+        }
+
+        let triangles = mesh_triangles(mesh);
+        let node_count = reader.u32()? as usize;
+        let mut nodes = Vec::with_capacity(node_count.min(bytes.len() / 32));
+        for _ in 0..node_count {
+            let mut corner = || Ok::<_, String>(glm::vec3(reader.f32()?, reader.f32()?, reader.f32()?));
+            let bounds = Aabb { min: corner()?, max: corner()? };
+            nodes.push(BvhNode { bounds, first: reader.u32()?, count: reader.u32()? });
+        }
+        let order_count = reader.u32()? as usize;
+        if order_count != triangles.len() {
+            return Err("Wrong number of triangles".to_string());
+        }
+        let order = (0..order_count).map(|_| reader.u32()).collect::<Result<Vec<u32>, String>>()?;
+
+        // Check every reference, so a damaged file can't send a query out of bounds. Children are
+        // always stored after their parent, which also keeps queries from going around in circles.
+        let valid = !nodes.is_empty()
+            && order.iter().all(|&i| (i as usize) < triangles.len())
+            && nodes.iter().enumerate().all(|(index, node)| {
+                let (first, count) = (node.first as usize, node.count as usize);
+                if count == 0 && !order.is_empty() {
+                    first > index && first + 1 < nodes.len()
+                } else {
+                    first + count <= order.len()
+                }
+            });
+        if !valid || reader.offset != bytes.len() {
+            return Err("Damaged BVH file".to_string());
+        }
+        Ok(Bvh { nodes, order, triangles })
+    }
+
+    pub fn triangle_count(&self) -> usize {
+        self.triangles.len()
+    }
+
+    pub fn triangle(&self, index: u32) -> &[glm::Vec3; 3] {
+        &self.triangles[index as usize]
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        self.nodes[0].bounds
+    }
+
+    // The nearest triangle the ray hits within `max_distance` units of its direction, from
+    // either side
+    pub fn raycast(&self, origin: &glm::Vec3, direction: &glm::Vec3, max_distance: f32) -> Option<BvhHit> {
+        let inverse_direction = glm::vec3(1.0 / direction.x, 1.0 / direction.y, 1.0 / direction.z);
+        let mut nearest: Option<(f32, u32)> = None;
+        let mut pending = if self.triangles.is_empty() { vec![] } else { vec![0usize] };
+        while let Some(index) = pending.pop() {
+            let node = &self.nodes[index];
+            let limit = nearest.map_or(max_distance, |(distance, _)| distance);
+            if node.bounds.ray_distance(origin, &inverse_direction, limit).is_none() {
+                continue;
+            }
+            if node.count > 0 {
+                for &triangle in self.leaf(node) {
+                    if let Some(distance) = intersect_triangle(origin, direction, &self.triangles[triangle as usize]) {
+                        if distance <= max_distance && nearest.map_or(true, |(nearest, _)| distance < nearest) {
+                            nearest = Some((distance, triangle));
+                        }
+                    }
+                }
+                continue;
+            }
+
+            // Look in the nearer child first, so the other can often be skipped
+            let (left, right) = (node.first as usize, node.first as usize + 1);
+            let distance = |child: usize| self.nodes[child].bounds.ray_distance(origin, &inverse_direction, limit);
+            match (distance(left), distance(right)) {
+                (Some(l), Some(r)) if l <= r => pending.extend([right, left]),
+                (Some(_), Some(_)) => pending.extend([left, right]),
+                (Some(_), None) => pending.push(left),
+                (None, Some(_)) => pending.push(right),
+                (None, None) => {}
+            }
+        }
+
+        nearest.map(|(distance, triangle)| {
+            let [a, b, c] = self.triangles[triangle as usize];
+            BvhHit {
+                distance,
+                triangle,
+                point  : origin + direction * distance,
+                normal : glm::normalize(&glm::cross(&(b - a), &(c - a))),
+            }
+        })
+    }
+
+    // Every triangle touching the sphere
+    #[allow(dead_code)]
+    pub fn triangles_in_sphere(&self, center: &glm::Vec3, radius: f32) -> Vec<u32> {
+        self.query(
+            |bounds| bounds.intersects_sphere(center, radius),
+            |triangle| glm::distance2(&closest_point_on_triangle(center, triangle), center) <= radius * radius,
+        )
+    }
+
+    // Every triangle whose bounding box overlaps the box
+    #[allow(dead_code)]
+    pub fn triangles_in_aabb(&self, aabb: &Aabb) -> Vec<u32> {
+        self.query(|bounds| bounds.intersects(aabb), |triangle| Aabb::around(triangle).intersects(aabb))
+    }
+
+    fn query(&self, overlaps: impl Fn(&Aabb) -> bool, touches: impl Fn(&[glm::Vec3; 3]) -> bool) -> Vec<u32> {
+        let mut found = vec![];
+        let mut pending = if self.triangles.is_empty() { vec![] } else { vec![0usize] };
+        while let Some(index) = pending.pop() {
+            let node = &self.nodes[index];
+            if !overlaps(&node.bounds) {
+                continue;
+            }
+            if node.count > 0 {
+                found.extend(self.leaf(node).iter().filter(|&&triangle| touches(&self.triangles[triangle as usize])));
+            } else {
+                pending.extend([node.first as usize, node.first as usize + 1]);
+            }
+        }
+        found
+    }
+
+    fn leaf(&self, node: &BvhNode) -> &[u32] {
+        &self.order[node.first as usize..(node.first + node.count) as usize]
+    }
+}
+
+// The triangles of a mesh, with the corners in the order of its indices
+pub fn mesh_triangles(mesh: &Mesh) -> Vec<[glm::Vec3; 3]> {
+    let vertex = |index: u32| {
+        let i = index as usize * 3;
+        glm::vec3(mesh.vertices[i], mesh.vertices[i + 1], mesh.vertices[i + 2])
+    };
+    mesh.indices
+        .chunks_exact(3)
+        .map(|triangle| [vertex(triangle[0]), vertex(triangle[1]), vertex(triangle[2])])
+        .collect()
+}
+
+// Möller-Trumbore, the distance along the ray to where it crosses the triangle from either side
+pub fn intersect_triangle(origin: &glm::Vec3, direction: &glm::Vec3, triangle: &[glm::Vec3; 3]) -> Option<f32> {
+    let edge1 = triangle[1] - triangle[0];
+    let edge2 = triangle[2] - triangle[0];
+    let p = glm::cross(direction, &edge2);
+    let determinant = glm::dot(&edge1, &p);
+    if determinant.abs() < 1e-12 {
+        return None;
+    }
+    let to_origin = origin - triangle[0];
+    let u = glm::dot(&to_origin, &p) / determinant;
+    if !(0.0..=1.0).contains(&u) {
+        return None;
+    }
+    let q = glm::cross(&to_origin, &edge1);
+    let v = glm::dot(direction, &q) / determinant;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+    let distance = glm::dot(&edge2, &q) / determinant;
+    if distance >= 0.0 {
+        Some(distance)
+    } else {
+        None
+    }
+}
+
+// From Real-Time Collision Detection by Christer Ericson, section 5.1.5
+#[allow(dead_code)]
+fn closest_point_on_triangle(point: &glm::Vec3, triangle: &[glm::Vec3; 3]) -> glm::Vec3 {
+    let [a, b, c] = *triangle;
+    let (ab, ac, ap) = (b - a, c - a, point - a);
+    let (d1, d2) = (glm::dot(&ab, &ap), glm::dot(&ac, &ap));
+    if d1 <= 0.0 && d2 <= 0.0 {
+        return a;
+    }
+    let bp = point - b;
+    let (d3, d4) = (glm::dot(&ab, &bp), glm::dot(&ac, &bp));
+    if d3 >= 0.0 && d4 <= d3 {
+        return b;
+    }
+    let vc = d1 * d4 - d3 * d2;
+    if vc <= 0.0 && d1 >= 0.0 && d3 <= 0.0 {
+        return a + ab * (d1 / (d1 - d3));
+    }
+    let cp = point - c;
+    let (d5, d6) = (glm::dot(&ab, &cp), glm::dot(&ac, &cp));
+    if d6 >= 0.0 && d5 <= d6 {
+        return c;
+    }
+    let vb = d5 * d2 - d1 * d6;
+    if vb <= 0.0 && d2 >= 0.0 && d6 <= 0.0 {
+        return a + ac * (d2 / (d2 - d6));
+    }
+    let va = d3 * d6 - d5 * d4;
+    if va <= 0.0 && d4 - d3 >= 0.0 && d5 - d6 >= 0.0 {
+        return b + (c - b) * ((d4 - d3) / ((d4 - d3) + (d5 - d6)));
+    }
+    let denominator = 1.0 / (va + vb + vc);
+    a + ab * (vb * denominator) + ac * (vc * denominator)
+}
+
+struct Split {
+    axis         : usize,
+    bin          : usize, // The first bin on the right
+    cost         : f32,
+    centroid_min : f32,
+    scale        : f32, // Bins per unit along the axis
+}
+
+// The cheapest place to split the triangles, trying the edges between evenly sized bins of
+// their centroids along each axis
+fn best_split(order: &[u32], bounds: &[Aabb], centroids: &[glm::Vec3]) -> Option<Split> {
+    let centroid_bounds = Aabb::around(&order.iter().map(|&i| centroids[i as usize]).collect::<Vec<_>>());
+    let mut best: Option<Split> = None;
+    for axis in [0, 1, 2] {
+        let (min, extent) = (centroid_bounds.min[axis], centroid_bounds.size()[axis]);
+        if extent <= 1e-9 {
+            continue;
+        }
+        let scale = BINS as f32 / extent;
+        let mut bin_bounds = [Aabb::empty(); BINS];
+        let mut bin_counts = [0usize; BINS];
+        for &i in order {
+            let bin = (((centroids[i as usize][axis] - min) * scale) as usize).min(BINS - 1);
+            bin_bounds[bin] = bin_bounds[bin].union(&bounds[i as usize]);
+            bin_counts[bin] += 1;
+        }
+
+        // Sweep from the right to know the cost of everything right of each edge
+        let mut right_costs = [0.0f32; BINS];
+        let (mut right_bounds, mut right_count) = (Aabb::empty(), 0);
+        for bin in (1..BINS).rev() {
+            right_bounds = right_bounds.union(&bin_bounds[bin]);
+            right_count += bin_counts[bin];
+            right_costs[bin] = right_count as f32 * right_bounds.surface_area();
+        }
+        let (mut left_bounds, mut left_count) = (Aabb::empty(), 0);
+        for bin in 1..BINS {
+            left_bounds = left_bounds.union(&bin_bounds[bin - 1]);
+            left_count += bin_counts[bin - 1];
+            let cost = left_count as f32 * left_bounds.surface_area() + right_costs[bin];
+            if left_count > 0 && left_count < order.len() && best.as_ref().map_or(true, |best| cost < best.cost) {
+                best = Some(Split { axis, bin, cost, centroid_min: min, scale });
+            }
+        }
+    }
+    best
+}
+
+// A 64 bit FNV-1a hash of the vertices and indices, to tell whether a saved tree belongs to a mesh
+fn fingerprint(mesh: &Mesh) -> u64 {
+    let mut hash = 0xcbf29ce484222325u64;
+    let bytes = mesh
+        .vertices
+        .iter()
+        .flat_map(|value| value.to_le_bytes())
+        .chain(mesh.indices.iter().flat_map(|index| index.to_le_bytes()));
+    for byte in bytes {
+        hash = (hash ^ byte as u64).wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+struct Reader<'a> {
+    bytes  : &'a [u8],
+    offset : usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], String> {
+        let bytes = self.bytes.get(self.offset..self.offset + count).ok_or("Truncated BVH file")?;
+        self.offset += count;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn f32(&mut self) -> Result<f32, String> {
+        Ok(f32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Hilly ground of two triangles per square, with a few boxes sticking out of it, so the tree
+    // has both flat and steep triangles to sort out
+    fn test_mesh() -> Mesh {
+        let (mut vertices, mut indices) = (vec![], vec![]);
+        let size = 24;
+        for z in 0..=size {
+            for x in 0..=size {
+                let (x, z) = (x as f32 * 4.0 - 48.0, z as f32 * 4.0 - 48.0);
+                vertices.extend_from_slice(&[x, (x * 0.1).sin() * 5.0 + (z * 0.07).cos() * 3.0, z]);
+            }
+        }
+        for z in 0..size {
+            for x in 0..size {
+                let corner = z * (size + 1) + x;
+                let (a, b, c, d) = (corner, corner + 1, corner + size + 1, corner + size + 2);
+                indices.extend_from_slice(&[a, c, b, b, c, d]);
+            }
+        }
+        for (i, center) in [glm::vec3(-20.0, 5.0, 10.0), glm::vec3(15.0, 8.0, -25.0), glm::vec3(30.0, 2.0, 30.0)].iter().enumerate() {
+            let first = (vertices.len() / 3) as u32;
+            for corner in 0..8 {
+                let offset = glm::vec3((corner & 1) as f32, ((corner >> 1) & 1) as f32, ((corner >> 2) & 1) as f32);
+                let point = center + (offset * 2.0 - glm::vec3(1.0, 1.0, 1.0)) * (3.0 + i as f32);
+                vertices.extend_from_slice(&[point.x, point.y, point.z]);
+            }
+            let faces = [[0, 2, 1, 1, 2, 3], [4, 5, 6, 5, 7, 6], [0, 1, 4, 1, 5, 4], [2, 6, 3, 3, 6, 7], [0, 4, 2, 2, 4, 6], [1, 3, 5, 3, 7, 5]];
+            indices.extend(faces.iter().flatten().map(|&index| first + index));
+        }
+        let index_count = indices.len() as i32;
+        Mesh { normals: vec![0.0; vertices.len()], colors: vec![], vertices, indices, index_count }
+    }
+
+    // The same pseudo random numbers in [0, 1) every run
+    fn random_numbers() -> impl FnMut() -> f32 {
+        let mut state = 0x2545f491u32;
+        move || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            (state >> 8) as f32 / (1 << 24) as f32
+        }
+    }
+
+    // The nearest hit by testing every triangle
+    fn brute_force_raycast(triangles: &[[glm::Vec3; 3]], origin: &glm::Vec3, direction: &glm::Vec3, max_distance: f32) -> Option<f32> {
+        triangles
+            .iter()
+            .filter_map(|triangle| intersect_triangle(origin, direction, triangle))
+            .filter(|&distance| distance <= max_distance)
+            .fold(None, |nearest: Option<f32>, distance| Some(nearest.map_or(distance, |nearest| nearest.min(distance))))
+    }
+
+    #[test]
+    fn raycast_finds_the_same_hits_as_testing_every_triangle() {
+        let mesh = test_mesh();
+        let bvh = Bvh::build(&mesh);
+        let triangles = mesh_triangles(&mesh);
+        let mut random = random_numbers();
+        let mut hits = 0;
+        for _ in 0..2000 {
+            let origin = glm::vec3(random() * 120.0 - 60.0, random() * 40.0 - 10.0, random() * 120.0 - 60.0);
+            let direction = glm::vec3(random() * 2.0 - 1.0, random() * 2.0 - 1.0, random() * 2.0 - 1.0);
+            let max_distance = random() * 100.0;
+            let expected = brute_force_raycast(&triangles, &origin, &direction, max_distance);
+            let hit = bvh.raycast(&origin, &direction, max_distance);
+            assert_eq!(hit.map(|hit| hit.distance), expected, "ray from {} along {}", origin, direction);
+            if let Some(hit) = hit {
+                let triangle = bvh.triangle(hit.triangle);
+                assert_eq!(intersect_triangle(&origin, &direction, triangle), Some(hit.distance));
+                hits += 1;
+            }
+        }
+        // Most of the rays should hit something, or the test says little
+        assert!(hits > 200, "only {} hits", hits);
+    }
+
+    #[test]
+    fn queries_find_the_same_triangles_as_testing_every_triangle() {
+        let mesh = test_mesh();
+        let bvh = Bvh::build(&mesh);
+        let triangles = mesh_triangles(&mesh);
+        let mut random = random_numbers();
+        for _ in 0..200 {
+            let center = glm::vec3(random() * 100.0 - 50.0, random() * 20.0 - 5.0, random() * 100.0 - 50.0);
+            let radius = random() * 10.0;
+            let mut found = bvh.triangles_in_sphere(&center, radius);
+            found.sort_unstable();
+            let expected: Vec<u32> = (0..triangles.len() as u32)
+                .filter(|&i| glm::distance2(&closest_point_on_triangle(&center, &triangles[i as usize]), &center) <= radius * radius)
+                .collect();
+            assert_eq!(found, expected);
+
+            let aabb = Aabb { min: center - glm::vec3(radius, radius, radius), max: center + glm::vec3(radius, 2.0, radius) };
+            let mut found = bvh.triangles_in_aabb(&aabb);
+            found.sort_unstable();
+            let expected: Vec<u32> = (0..triangles.len() as u32).filter(|&i| Aabb::around(&triangles[i as usize]).intersects(&aabb)).collect();
+            assert_eq!(found, expected);
+        }
+    }
+
+    #[test]
+    fn saved_trees_read_back_the_same() {
+        let mesh = test_mesh();
+        let bvh = Bvh::build(&mesh);
+        let bytes = bvh.to_bytes(&mesh);
+        let read_back = Bvh::from_bytes(&mesh, &bytes).unwrap();
+        assert_eq!(read_back.to_bytes(&mesh), bytes);
+        assert_eq!(read_back.triangle_count(), bvh.triangle_count());
+        assert_eq!(read_back.bounds(), bvh.bounds());
+
+        let mut random = random_numbers();
+        for _ in 0..200 {
+            let origin = glm::vec3(random() * 100.0 - 50.0, 30.0, random() * 100.0 - 50.0);
+            let direction = glm::vec3(random() - 0.5, -1.0, random() - 0.5);
+            let (expected, hit) = (bvh.raycast(&origin, &direction, 100.0), read_back.raycast(&origin, &direction, 100.0));
+            assert_eq!(hit.map(|hit| (hit.distance, hit.triangle)), expected.map(|hit| (hit.distance, hit.triangle)));
+        }
+    }
+
+    #[test]
+    fn damaged_files_are_rejected() {
+        let mesh = test_mesh();
+        let bytes = Bvh::build(&mesh).to_bytes(&mesh);
+        let mut other = test_mesh();
+        other.vertices[1] += 1.0;
+        assert_eq!(Bvh::from_bytes(&other, &bytes).err().unwrap(), "Built for a different mesh");
+        assert_eq!(Bvh::from_bytes(&mesh, &bytes[..bytes.len() - 1]).err().unwrap(), "Truncated BVH file");
+        assert_eq!(Bvh::from_bytes(&mesh, b"GLTF").err().unwrap(), "Not a BVH file");
+
+        // Point the children of an interior node back at the root, or at the node itself
+        let header = 4 + 4 + 8 + 4;
+        let node_size = 8 * 4;
+        let nodes = u32::from_le_bytes(bytes[16..20].try_into().unwrap()) as usize;
+        let interior = (0..nodes)
+            .filter(|&i| u32::from_le_bytes(bytes[header + i * node_size + 28..header + i * node_size + 32].try_into().unwrap()) == 0)
+            .collect::<Vec<usize>>();
+        assert!(interior.len() > 2);
+        for &(node, first) in &[(interior[0], 0u32), (interior[2], interior[2] as u32), (interior[2], interior[1] as u32)] {
+            let mut damaged = bytes.clone();
+            let offset = header + node * node_size + 24;
+            damaged[offset..offset + 4].copy_from_slice(&first.to_le_bytes());
+            assert_eq!(Bvh::from_bytes(&mesh, &damaged).err().unwrap(), "Damaged BVH file");
+        }
+    }
+}
@@ -0,0 +1,314 @@
+extern crate nalgebra_glm as glm;
+
+use std::rc::Rc;
+
+use crate::scene_graph::SceneNode;
+use crate::terrain::TerrainIndex;
+
+pub const FIELD_OF_VIEW: f32 = 1.0;
+pub const NEAR_PLANE: f32 = 1.0;
+pub const FAR_PLANE: f32 = 1000.0;
+
+// What the player asks the camera to do this frame. The movement and look axes are in [-1, 1],
+// and it is up to the controller what each of them means.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CameraInput {
+    pub movement : glm::Vec3, // Right, up and forward
+    pub look     : glm::Vec2, // Turn right and look up
+    pub turn     : glm::Vec2, // Turn right and look up by this many radians, e.g. from the mouse
+    pub zoom     : f32,       // Zoom in or speed up, in steps
+}
+
+// How mouse movement turns the camera
+#[derive(Clone, Copy, Debug)]
+pub struct MouseLook {
+    pub sensitivity : f32,  // Radians per pixel
+    pub invert_x    : bool,
+    pub invert_y    : bool, // Moving the mouse up looks down, like in a flight simulator
+}
+
+impl Default for MouseLook {
+    fn default() -> Self {
+        MouseLook {
+            sensitivity : 0.003,
+            invert_x    : false,
+            invert_y    : false,
+        }
+    }
+}
+
+impl MouseLook {
+    // The turn for a mouse movement in pixels, with y pointing down the screen
+    pub fn turn(&self, delta: (f32, f32)) -> glm::Vec2 {
+        let x = if self.invert_x { -delta.0 } else { delta.0 };
+        let y = if self.invert_y { delta.1 } else { -delta.1 };
+        glm::vec2(x, y) * self.sensitivity
+    }
+}
+
+// Moves the camera around and decides where it looks
+pub trait CameraController {
+    fn name(&self) -> &'static str;
+    fn update(&mut self, input: &CameraInput, delta_time: f32);
+    fn view(&self) -> glm::Mat4;
+    // Called when the camera switches to this controller, with the view it is switching from
+    fn activate(&mut self, _previous_view: &glm::Mat4) {}
+}
+
+fn world_position(node: *const SceneNode, offset: &glm::Vec3) -> glm::Vec3 {
+    let matrix = unsafe { (*node).current_transformation_matrix };
+    (matrix * glm::vec4(offset.x, offset.y, offset.z, 1.0)).xyz()
+}
+
+// The position of the camera looking through the given view matrix
+pub fn view_position(view: &glm::Mat4) -> glm::Vec3 {
+    (glm::inverse(view) * glm::vec4(0.0, 0.0, 0.0, 1.0)).xyz()
+}
+
+// Flies freely along where it looks, the classic first person debug camera
+pub struct FreeFlyController {
+    pub position : glm::Vec3,
+    pub pitch    : f32,
+    pub yaw      : f32,
+    pub speed    : f32,
+}
+
+impl FreeFlyController {
+    pub fn new() -> Self {
+        FreeFlyController {
+            position : glm::zero(),
+            pitch    : 0.0,
+            yaw      : 0.0,
+            speed    : 70.0,
+        }
+    }
+}
+
+impl CameraController for FreeFlyController {
+    fn name(&self) -> &'static str {
+        "free fly"
+    }
+
+    fn update(&mut self, input: &CameraInput, delta_time: f32) {
+        let (pitch, yaw) = (self.pitch, self.yaw);
+        let step = self.speed * delta_time;
+        let right = input.movement.x;
+        let up = input.movement.y;
+        let forward = input.movement.z;
+
+        self.position.x += step * (-right * yaw.cos() - forward * yaw.sin() * pitch.cos());
+        self.position.y += step * (up + forward * pitch.sin());
+        self.position.z += step * (-right * yaw.sin() + forward * yaw.cos() * pitch.cos());
+
+        self.pitch = (self.pitch - 1.5 * delta_time * input.look.y - input.turn.y).clamp(-1.5, 1.5);
+        self.yaw += 1.5 * delta_time * input.look.x + input.turn.x;
+
+        self.speed = (self.speed + 20.0 * input.zoom).max(20.0);
+    }
+
+    fn view(&self) -> glm::Mat4 {
+        let translation: glm::Mat4 =
+            glm::translation(&glm::vec3(self.position.x, self.position.y, self.position.z - 5.0));
+
+        let mut rotation: glm::Mat4 = glm::identity();
+        rotation = glm::rotation(self.pitch, &(rotation * glm::vec4(1.0, 0.0, 0.0, 1.0)).xyz()) * rotation;
+        rotation = glm::rotation(self.yaw, &(rotation * glm::vec4(0.0, 1.0, 0.0, 1.0)).xyz()) * rotation;
+
+        rotation * translation
+    }
+}
+
+// Circles around a node, always looking at it
+pub struct OrbitController {
+    pub distance  : f32,
+    pub azimuth   : f32, // Angle around the y axis (radians)
+    pub elevation : f32, // Angle above the horizon (radians)
+
+    target: *const SceneNode, // The node has to outlive the controller, like for lights
+}
+
+impl OrbitController {
+    pub fn new(target: &SceneNode) -> Self {
+        OrbitController {
+            distance  : 40.0,
+            azimuth   : 0.0,
+            elevation : 0.4,
+            target    : target as *const SceneNode,
+        }
+    }
+}
+
+impl CameraController for OrbitController {
+    fn name(&self) -> &'static str {
+        "orbit"
+    }
+
+    fn update(&mut self, input: &CameraInput, delta_time: f32) {
+        self.azimuth += 1.5 * delta_time * input.look.x + input.turn.x;
+        self.elevation = (self.elevation + 1.5 * delta_time * input.look.y + input.turn.y).clamp(-1.4, 1.4);
+        self.distance = (self.distance * (1.0 - delta_time * input.movement.z) - 2.0 * input.zoom).max(5.0);
+    }
+
+    fn view(&self) -> glm::Mat4 {
+        let target = world_position(self.target, &glm::zero());
+        let offset = glm::vec3(
+            self.elevation.cos() * self.azimuth.sin(),
+            self.elevation.sin(),
+            self.elevation.cos() * self.azimuth.cos(),
+        ) * self.distance;
+        glm::look_at(&(target + offset), &target, &glm::vec3(0.0, 1.0, 0.0))
+    }
+}
+
+// Follows a node from behind, pulled along by a damped spring so it lags a little in turns
+pub struct ChaseController {
+    pub offset    : glm::Vec3, // Where to be, relative to the target node
+    pub look_at   : glm::Vec3, // Where to look, relative to the target node
+    pub stiffness : f32,
+    pub damping   : f32,       // 2 * sqrt(stiffness) for a critically damped spring
+    pub clearance : f32,       // How close to the terrain the camera may get
+
+    target: *const SceneNode,
+    terrain: Option<Rc<TerrainIndex>>,
+    position: glm::Vec3,
+    velocity: glm::Vec3,
+}
+
+impl ChaseController {
+    pub fn new(target: &SceneNode) -> Self {
+        let stiffness = 30.0;
+        let mut controller = ChaseController {
+            offset    : glm::vec3(0.0, 6.0, 20.0),
+            look_at   : glm::vec3(0.0, 1.0, 0.0),
+            stiffness,
+            damping   : 2.0 * f32::sqrt(stiffness),
+            clearance : 1.5,
+            target    : target as *const SceneNode,
+            terrain   : None,
+            position  : glm::zero(),
+            velocity  : glm::zero(),
+        };
+        controller.position = controller.desired_position();
+        controller
+    }
+
+    // Keep the camera out of the terrain, and the terrain from hiding the target
+    pub fn with_terrain(mut self, terrain: &Rc<TerrainIndex>) -> Self {
+        self.terrain = Some(Rc::clone(terrain));
+        self
+    }
+
+    fn desired_position(&self) -> glm::Vec3 {
+        world_position(self.target, &self.offset)
+    }
+
+    // Move in front of any ground between the camera and what it looks at, and up out of the
+    // ground under it
+    fn avoid_terrain(&mut self) {
+        let terrain = match &self.terrain {
+            Some(terrain) => terrain,
+            None => return,
+        };
+        let target = world_position(self.target, &self.look_at);
+        let to_camera = self.position - target;
+        let distance = glm::length(&to_camera);
+        if distance > 1e-3 {
+            let direction = to_camera / distance;
+            if let Some(hit) = terrain.raycast(&target, &direction, distance + self.clearance) {
+                self.position = target + direction * (hit.distance - self.clearance).max(0.0);
+            }
+        }
+        if let Some(ground) = terrain.height_at(self.position.x, self.position.z) {
+            if self.position.y < ground + self.clearance {
+                self.position.y = ground + self.clearance;
+                self.velocity.y = self.velocity.y.max(0.0);
+            }
+        }
+    }
+}
+
+impl CameraController for ChaseController {
+    fn name(&self) -> &'static str {
+        "chase"
+    }
+
+    fn update(&mut self, _input: &CameraInput, delta_time: f32) {
+        let desired = self.desired_position();
+        // Small steps keep the spring stable through long frames
+        let steps = (delta_time / (1.0 / 240.0)).ceil().max(1.0);
+        let dt = delta_time / steps;
+        for _ in 0..steps as u32 {
+            let acceleration = (desired - self.position) * self.stiffness - self.velocity * self.damping;
+            self.velocity += acceleration * dt;
+            self.position += self.velocity * dt;
+        }
+        self.avoid_terrain();
+    }
+
+    fn view(&self) -> glm::Mat4 {
+        let target = world_position(self.target, &self.look_at);
+        glm::look_at(&self.position, &target, &glm::vec3(0.0, 1.0, 0.0))
+    }
+
+    // Fly over from wherever the camera was instead of jumping
+    fn activate(&mut self, previous_view: &glm::Mat4) {
+        self.position = view_position(previous_view);
+        self.velocity = glm::zero();
+    }
+}
+
+// A perspective camera, with the controllers it can switch between
+pub struct Camera {
+    pub field_of_view : f32, // Vertical, in radians
+    pub near          : f32,
+    pub far           : f32,
+    pub controllers   : Vec<Box<dyn CameraController>>,
+    pub mouse_look    : MouseLook,
+
+    active: usize,
+}
+
+impl Camera {
+    // A free fly camera at the origin
+    pub fn new() -> Self {
+        Camera {
+            field_of_view : FIELD_OF_VIEW,
+            near          : NEAR_PLANE,
+            far           : FAR_PLANE,
+            controllers   : vec![Box::new(FreeFlyController::new())],
+            mouse_look    : MouseLook::default(),
+            active        : 0,
+        }
+    }
+
+    pub fn with_controller<C: CameraController + 'static>(mut self, controller: C) -> Self {
+        self.controllers.push(Box::new(controller));
+        self
+    }
+
+    // Switch to the controller with the given index, if there is one
+    pub fn select(&mut self, index: usize) {
+        if index < self.controllers.len() && index != self.active {
+            let previous_view = self.view();
+            self.active = index;
+            self.controllers[index].activate(&previous_view);
+            println!("Camera: {}", self.controllers[index].name());
+        }
+    }
+
+    pub fn update(&mut self, input: &CameraInput, delta_time: f32) {
+        self.controllers[self.active].update(input, delta_time);
+    }
+
+    pub fn view(&self) -> glm::Mat4 {
+        self.controllers[self.active].view()
+    }
+
+    pub fn projection(&self, aspect: f32) -> glm::Mat4 {
+        glm::perspective(aspect, self.field_of_view, self.near, self.far)
+    }
+
+    pub fn position(&self) -> glm::Vec3 {
+        view_position(&self.view())
+    }
+}
@@ -0,0 +1,105 @@
+use std::path::{Path, PathBuf};
+
+use crate::backend::RenderBackend;
+
+// Read back the color of the given framebuffer, 0 being the window. OpenGL stores the rows
+// bottom to top, so the image is flipped to get the usual top to bottom order.
+pub fn read_framebuffer(backend: &mut dyn RenderBackend, framebuffer_id: u32, width: u32, height: u32) -> image::RgbaImage {
+    let pixels = backend.read_pixels(framebuffer_id, width as i32, height as i32);
+    let image = image::RgbaImage::from_raw(width, height, pixels)
+        .expect("Pixel buffer has the wrong size");
+    image::imageops::flip_vertical(&image)
+}
+
+// Write the current contents of the given framebuffer to a PNG file
+pub fn save_png(
+    backend: &mut dyn RenderBackend,
+    framebuffer_id: u32,
+    width: u32,
+    height: u32,
+    path: &Path,
+) -> image::ImageResult<()> {
+    if let Some(directory) = path.parent() {
+        std::fs::create_dir_all(directory)?;
+    }
+    let image = read_framebuffer(backend, framebuffer_id, width, height);
+    image.save_with_format(path, image::ImageFormat::Png)
+}
+
+// A screenshot path in the given directory that won't overwrite earlier screenshots
+pub fn screenshot_path(directory: &str) -> PathBuf {
+    let milliseconds = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or(0);
+    Path::new(directory).join(format!("screenshot_{}.png", milliseconds))
+}
+
+// Dumps every frame to numbered PNG files, while the simulation advances by a fixed timestep per
+// frame. The frames can then be turned into a video, e.g. with
+// `ffmpeg -framerate 60 -i frames/frame_%05d.png out.mp4`.
+pub struct FrameSequence {
+    pub directory: PathBuf,
+    pub timestep: f32,
+    pub frame: u32,
+}
+
+impl FrameSequence {
+    pub fn new(directory: &str, frames_per_second: f32) -> Self {
+        FrameSequence {
+            directory: PathBuf::from(directory),
+            timestep: 1.0 / frames_per_second,
+            frame: 0,
+        }
+    }
+
+    pub fn frame_path(&self) -> PathBuf {
+        self.directory.join(format!("frame_{:05}.png", self.frame))
+    }
+
+    // Save the current frame and move on to the next number
+    pub fn capture(
+        &mut self,
+        backend: &mut dyn RenderBackend,
+        framebuffer_id: u32,
+        width: u32,
+        height: u32,
+    ) -> image::ImageResult<()> {
+        save_png(backend, framebuffer_id, width, height, &self.frame_path())?;
+        self.frame += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_backend::{Call, MockBackend};
+
+    #[test]
+    fn frames_are_read_through_the_backend_top_row_first() {
+        let mut backend = MockBackend::new();
+        let image = read_framebuffer(&mut backend, 7, 5, 3);
+        assert_eq!(backend.calls, vec![Call::ReadPixels { framebuffer_id: 7, width: 5, height: 3 }]);
+        assert_eq!(image.dimensions(), (5, 3));
+        // The mock numbers the rows from the bottom up
+        assert_eq!(image.get_pixel(0, 0).0, [2, 0, 0, 255]);
+        assert_eq!(image.get_pixel(4, 0).0, [2, 4, 0, 255]);
+        assert_eq!(image.get_pixel(1, 2).0, [0, 1, 0, 255]);
+    }
+
+    #[test]
+    fn frame_sequences_write_numbered_pngs() {
+        let directory = std::env::temp_dir().join(format!("gloom-rs-frames-{}", std::process::id()));
+        let mut sequence = FrameSequence::new(directory.to_str().unwrap(), 30.0);
+        let mut backend = MockBackend::new();
+        for _ in 0..2 {
+            sequence.capture(&mut backend, 0, 4, 2).unwrap();
+        }
+        assert_eq!(sequence.frame, 2);
+        let second = image::open(directory.join("frame_00001.png")).unwrap().to_rgba8();
+        assert_eq!(second, read_framebuffer(&mut backend, 0, 4, 2));
+        assert!(directory.join("frame_00000.png").is_file());
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
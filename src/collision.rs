@@ -0,0 +1,275 @@
+extern crate nalgebra_glm as glm;
+
+use crate::animation;
+use crate::bvh::Aabb;
+use crate::scene_graph::SceneNode;
+
+// A box around a node, turned and moved along with it
+#[derive(Clone, Copy, Debug)]
+pub struct OrientedBox {
+    pub center    : glm::Vec3,
+    pub axes      : [glm::Vec3; 3], // The x, y and z axes of the node, in world space
+    pub half_size : glm::Vec3,      // Along each of the axes
+}
+
+impl OrientedBox {
+    // The bounds of the model a node draws, where the node is now. Only the node's own
+    // transformation is used, so its parent has to be untransformed, like the scene root.
+    pub fn around(bounds: &Aabb, node: &SceneNode) -> Self {
+        let rotation = animation::euler_to_quat(&node.rotation);
+        let axis = |axis: glm::Vec3| glm::quat_rotate_vec3(&rotation, &axis);
+        let center = (bounds.center() - node.reference_point).component_mul(&node.scale);
+        OrientedBox {
+            center    : node.position + node.reference_point + axis(center),
+            axes      : [axis(glm::vec3(1.0, 0.0, 0.0)), axis(glm::vec3(0.0, 1.0, 0.0)), axis(glm::vec3(0.0, 0.0, 1.0))],
+            half_size : glm::abs(&(bounds.size() * 0.5).component_mul(&node.scale)),
+        }
+    }
+
+    // The radius of a sphere around the box
+    pub fn radius(&self) -> f32 {
+        glm::length(&self.half_size)
+    }
+
+    // How far the box reaches along the axis, either way from its center
+    fn reach(&self, axis: &glm::Vec3) -> f32 {
+        (0..3).map(|i| glm::dot(&self.axes[i], axis).abs() * self.half_size[i]).sum()
+    }
+
+    // Whether the boxes overlap, and if so the shortest way to push them apart: the direction
+    // from this box towards the other and how far. Tries every axis the boxes could be separated
+    // along, the faces of either and the crossings of their edges.
+    pub fn contact(&self, other: &OrientedBox) -> Option<(glm::Vec3, f32)> {
+        let between = other.center - self.center;
+        let mut nearest: Option<(glm::Vec3, f32)> = None;
+        let crossings = self.axes.iter().flat_map(|a| other.axes.iter().map(move |b| glm::cross(a, b)));
+        for axis in self.axes.iter().chain(other.axes.iter()).copied().chain(crossings) {
+            let length = glm::length(&axis);
+            // Parallel edges, already covered by the face axes
+            if length < 1e-4 {
+                continue;
+            }
+            let axis = axis / length;
+            let distance = glm::dot(&between, &axis);
+            let overlap = self.reach(&axis) + other.reach(&axis) - distance.abs();
+            if overlap < 0.0 {
+                return None;
+            }
+            if nearest.map_or(true, |(_, depth)| overlap < depth) {
+                nearest = Some((if distance < 0.0 { -axis } else { axis }, overlap));
+            }
+        }
+        nearest
+    }
+}
+
+// Two colliders overlapping, `first` always the lower of the two
+#[derive(Clone, Copy, Debug)]
+pub struct Contact {
+    pub first  : usize,
+    pub second : usize,
+    pub normal : glm::Vec3, // From the first towards the second
+    pub depth  : f32,       // How far the second has to move along the normal to be clear
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum ContactEvent {
+    Began(Contact),
+    Ended(usize, usize),
+}
+
+struct Collider {
+    node   : *const SceneNode, // The node has to outlive the world, like for tweens
+    bounds : Aabb,             // In the space of the node's model
+}
+
+// Finds which of a set of nodes overlap, by boxes around what they draw. The boxes are swept
+// along x to only test those that come near each other, which keeps it fast for many nodes.
+pub struct CollisionWorld {
+    colliders: Vec<Collider>,
+    contacts: Vec<Contact>,
+}
+
+impl CollisionWorld {
+    pub fn new() -> Self {
+        CollisionWorld { colliders: vec![], contacts: vec![] }
+    }
+
+    // Start checking the node for collisions, with the bounds of its model. Returns the index
+    // contacts refer to it by.
+    pub fn add(&mut self, node: &SceneNode, bounds: Aabb) -> usize {
+        self.colliders.push(Collider { node: node as *const SceneNode, bounds });
+        self.colliders.len() - 1
+    }
+
+    // The colliders that overlapped at the last update
+    pub fn contacts(&self) -> &[Contact] {
+        &self.contacts
+    }
+
+    // Check where the nodes are now. Returns the contacts that began since the last update, and
+    // the pairs that came apart.
+    pub fn update(&mut self) -> Vec<ContactEvent> {
+        let boxes: Vec<OrientedBox> = self
+            .colliders
+            .iter()
+            .map(|collider| OrientedBox::around(&collider.bounds, unsafe { &*collider.node }))
+            .collect();
+        let mut order: Vec<usize> = (0..boxes.len()).collect();
+        order.sort_by(|&a, &b| {
+            let start = |i: usize| boxes[i].center.x - boxes[i].radius();
+            start(a).total_cmp(&start(b))
+        });
+
+        let mut contacts = vec![];
+        for (n, &a) in order.iter().enumerate() {
+            let end = boxes[a].center.x + boxes[a].radius();
+            for &b in &order[n + 1..] {
+                if boxes[b].center.x - boxes[b].radius() > end {
+                    break;
+                }
+                let reach = boxes[a].radius() + boxes[b].radius();
+                if glm::distance2(&boxes[a].center, &boxes[b].center) > reach * reach {
+                    continue;
+                }
+                let (first, second) = (a.min(b), a.max(b));
+                if let Some((normal, depth)) = boxes[first].contact(&boxes[second]) {
+                    contacts.push(Contact { first, second, normal, depth });
+                }
+            }
+        }
+        contacts.sort_by_key(|contact| (contact.first, contact.second));
+
+        let pair = |contact: &Contact| (contact.first, contact.second);
+        let mut events: Vec<ContactEvent> = contacts
+            .iter()
+            .filter(|contact| self.contacts.binary_search_by_key(&pair(contact), pair).is_err())
+            .map(|contact| ContactEvent::Began(*contact))
+            .collect();
+        events.extend(
+            self.contacts
+                .iter()
+                .filter(|old| contacts.binary_search_by_key(&pair(old), pair).is_err())
+                .map(|old| ContactEvent::Ended(old.first, old.second)),
+        );
+        self.contacts = contacts;
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::FRAC_PI_4;
+
+    // A box of the given half size, turned by the euler angles of a node
+    fn oriented(center: glm::Vec3, half_size: glm::Vec3, rotation: glm::Vec3) -> OrientedBox {
+        let mut node = SceneNode::new();
+        node.position = center;
+        node.rotation = rotation;
+        OrientedBox::around(&Aabb { min: -half_size, max: half_size }, &node)
+    }
+
+    fn cube(center: glm::Vec3) -> OrientedBox {
+        oriented(center, glm::vec3(1.0, 1.0, 1.0), glm::zero())
+    }
+
+    #[test]
+    fn overlapping_boxes_are_pushed_apart_the_short_way() {
+        let (normal, depth) = cube(glm::zero()).contact(&cube(glm::vec3(1.5, 0.2, -0.1))).unwrap();
+        assert!(glm::distance(&normal, &glm::vec3(1.0, 0.0, 0.0)) < 1e-6);
+        assert!((depth - 0.5).abs() < 1e-6);
+
+        // The other way round, the normal still points from the first box towards the second
+        let (normal, depth) = cube(glm::vec3(1.5, 0.2, -0.1)).contact(&cube(glm::zero())).unwrap();
+        assert!(glm::distance(&normal, &glm::vec3(-1.0, 0.0, 0.0)) < 1e-6);
+        assert!((depth - 0.5).abs() < 1e-6);
+
+        // Turned half way round, a corner reaches further
+        let turned = oriented(glm::vec3(2.2, 0.0, 0.0), glm::vec3(1.0, 1.0, 1.0), glm::vec3(0.0, 0.0, FRAC_PI_4));
+        let (normal, depth) = cube(glm::zero()).contact(&turned).unwrap();
+        assert!(glm::distance(&normal, &glm::vec3(1.0, 0.0, 0.0)) < 1e-5);
+        assert!((depth - (2.0_f32.sqrt() - 1.2)).abs() < 1e-5);
+    }
+
+    #[test]
+    fn separated_boxes_have_no_contact() {
+        assert!(cube(glm::zero()).contact(&cube(glm::vec3(2.5, 0.0, 0.0))).is_none());
+        assert!(cube(glm::zero()).contact(&cube(glm::vec3(0.0, -2.01, 1.0))).is_none());
+        assert!(cube(glm::zero()).contact(&cube(glm::vec3(1.9, 1.9, 1.9))).is_some());
+        assert!(cube(glm::zero()).contact(&cube(glm::vec3(2.1, 1.9, 1.9))).is_none());
+    }
+
+    #[test]
+    fn touching_boxes_are_in_contact_without_depth() {
+        let (normal, depth) = cube(glm::zero()).contact(&cube(glm::vec3(0.0, 0.0, 2.0))).unwrap();
+        assert_eq!(normal, glm::vec3(0.0, 0.0, 1.0));
+        assert_eq!(depth, 0.0);
+    }
+
+    #[test]
+    fn boxes_separated_only_across_their_edges_have_no_contact() {
+        // One box with an edge along z on top, and another with an edge along x underneath. Seen
+        // along either box's faces they overlap, only the crossing of the two edges is clear.
+        let below = oriented(glm::zero(), glm::vec3(1.0, 1.0, 1.0), glm::vec3(0.0, 0.0, FRAC_PI_4));
+        let edges_meet = 2.0 * 2.0_f32.sqrt();
+        let above = |height: f32| oriented(glm::vec3(0.0, height, 0.0), glm::vec3(1.0, 1.0, 1.0), glm::vec3(FRAC_PI_4, 0.0, 0.0));
+
+        let clear = above(edges_meet + 0.05);
+        for axis in below.axes.iter().chain(clear.axes.iter()) {
+            let distance = glm::dot(&(clear.center - below.center), axis).abs();
+            assert!(below.reach(axis) + clear.reach(axis) > distance, "separated along {}", axis);
+        }
+        assert!(below.contact(&clear).is_none());
+
+        let (normal, depth) = below.contact(&above(edges_meet - 0.05)).unwrap();
+        assert!(glm::distance(&normal, &glm::vec3(0.0, 1.0, 0.0)) < 1e-5, "{}", normal);
+        assert!((depth - 0.05).abs() < 1e-4);
+    }
+
+    #[test]
+    fn contacts_begin_and_end_once() {
+        let bounds = Aabb { min: glm::vec3(-1.0, -1.0, -1.0), max: glm::vec3(1.0, 1.0, 1.0) };
+        let (mut a, mut b, mut c) = (SceneNode::new(), SceneNode::new(), SceneNode::new());
+        b.position = glm::vec3(10.0, 0.0, 0.0);
+        c.position = glm::vec3(0.0, 0.0, 50.0);
+        let mut world = CollisionWorld::new();
+        for node in [&a, &b, &c] {
+            world.add(node, bounds);
+        }
+        assert!(world.update().is_empty());
+
+        let mut began = 0;
+        let mut ended = 0;
+        // b flies through a and out the other side, while c stays clear
+        for step in 0..=40 {
+            b.position.x = 10.0 - step as f32 * 0.5;
+            for event in world.update() {
+                match event {
+                    ContactEvent::Began(contact) => {
+                        assert_eq!((contact.first, contact.second), (0, 1));
+                        assert!(contact.depth >= 0.0);
+                        began += 1;
+                    }
+                    ContactEvent::Ended(first, second) => {
+                        assert_eq!((first, second), (0, 1));
+                        assert_eq!(began, 1);
+                        ended += 1;
+                    }
+                }
+            }
+            // Touching at either end counts as a contact
+            let overlapping = b.position.x.abs() <= 2.0;
+            assert_eq!(world.contacts().len(), overlapping as usize, "at step {}", step);
+        }
+        assert_eq!((began, ended), (1, 1));
+        assert!(world.update().is_empty());
+
+        // Contacts that carry on from one update to the next don't begin again
+        a.position = c.position;
+        assert!(matches!(world.update()[..], [ContactEvent::Began(Contact { first: 0, second: 2, .. })]));
+        a.position.y += 0.5;
+        assert!(world.update().is_empty());
+        assert_eq!(world.contacts().len(), 1);
+    }
+}
@@ -0,0 +1,285 @@
+extern crate nalgebra_glm as glm;
+
+use crate::animation;
+use crate::gamepad::FlightControls;
+use crate::scene_graph::SceneNode;
+use crate::terrain::TerrainHit;
+
+const GRAVITY: f32 = 9.81;
+
+// What the pilot does with the controls of a helicopter
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PilotInputs {
+    pub collective   : f32, // Blade pitch of the main rotor, from 0 to 1. More lifts harder.
+    pub cyclic_pitch : f32, // Tilts the rotor to pitch the nose down, from -1 to 1
+    pub cyclic_roll  : f32, // Tilts the rotor to roll the right side down, from -1 to 1
+    pub pedal        : f32, // Tail rotor thrust to turn the nose left, from -1 to 1
+    pub throttle     : f32, // Engine power, which sets how fast the rotor spins, from 0 to 1
+}
+
+impl PilotInputs {
+    // The flight controls with the throttle stick as the collective, centered to hover
+    pub fn from_controls(controls: &FlightControls, engine_on: bool) -> Self {
+        PilotInputs {
+            collective   : (0.5 + 0.5 * controls.throttle).clamp(0.0, 1.0),
+            cyclic_pitch : controls.pitch,
+            cyclic_roll  : controls.roll,
+            pedal        : controls.yaw,
+            throttle     : if engine_on { 1.0 } else { 0.0 },
+        }
+    }
+}
+
+// How a helicopter handles. Forces are given per unit of mass, as accelerations.
+#[derive(Clone, Copy, Debug)]
+pub struct FlightModel {
+    pub rated_rotor_speed : f32, // Radians per second of the main rotor at full throttle
+    pub spool_time        : f32, // Seconds for the rotor to get most of the way to a new speed
+    pub max_lift          : f32, // Rotor thrust at full collective and rated speed, hovering at half of it
+    pub linear_drag       : f32,
+    pub quadratic_drag    : f32,
+    pub rotor_damping     : f32, // Drag along the rotor axis, which stops climbs and sinks when the collective is let go
+    pub cyclic_authority  : f32, // Angular acceleration at full cyclic, radians per second squared
+    pub pedal_authority   : f32,
+    pub angular_damping   : f32, // How quickly spinning slows down by itself, per second
+    pub self_leveling     : f32, // How hard the stability augmentation pulls the helicopter level
+    pub skid_height       : f32, // How far below the origin of the model the skids are
+    pub ground_friction   : f32, // How quickly the skids stop sliding, per second
+}
+
+impl FlightModel {
+    pub fn new() -> Self {
+        FlightModel {
+            rated_rotor_speed : 40.0,
+            spool_time        : 1.5,
+            max_lift          : 2.0 * GRAVITY,
+            linear_drag       : 0.15,
+            quadratic_drag    : 0.01,
+            rotor_damping     : 1.5,
+            cyclic_authority  : 4.0,
+            pedal_authority   : 3.0,
+            angular_damping   : 3.0,
+            self_leveling     : 8.0,
+            skid_height       : 0.0,
+            ground_friction   : 4.0,
+        }
+    }
+}
+
+// A helicopter flown as a rigid body, stepped on the fixed timestep of the simulation
+pub struct Helicopter {
+    pub model            : FlightModel,
+    pub position         : glm::Vec3,
+    pub velocity         : glm::Vec3,
+    pub orientation      : glm::Quat,
+    pub angular_velocity : glm::Vec3, // Around its own axes, radians per second
+    pub rotor_speed      : f32,       // Radians per second
+    pub rotor_angle      : f32,
+    pub on_ground        : bool,
+}
+
+impl Helicopter {
+    // A helicopter hovering at the given position, its rotor already up to speed
+    pub fn new(position: glm::Vec3, model: FlightModel) -> Self {
+        Helicopter {
+            model,
+            position,
+            velocity         : glm::zero(),
+            orientation      : glm::quat_identity(),
+            angular_velocity : glm::zero(),
+            rotor_speed      : model.rated_rotor_speed,
+            rotor_angle      : 0.0,
+            on_ground        : false,
+        }
+    }
+
+    pub fn up(&self) -> glm::Vec3 {
+        glm::quat_rotate_vec3(&self.orientation, &glm::vec3(0.0, 1.0, 0.0))
+    }
+
+    // Move the helicopter forward by one step of the given length
+    pub fn step(&mut self, inputs: &PilotInputs, timestep: f32) {
+        let model = &self.model;
+
+        // The engine drives the rotor, which spins down by itself when the throttle is cut
+        let target_speed = inputs.throttle.clamp(0.0, 1.0) * model.rated_rotor_speed;
+        self.rotor_speed += (target_speed - self.rotor_speed) * (1.0 - (-timestep / model.spool_time).exp());
+        self.rotor_angle = (self.rotor_angle + self.rotor_speed * timestep).rem_euclid(std::f32::consts::TAU);
+
+        // Lift grows with the square of the rotor speed, and pushes along the rotor axis
+        let rotor = self.rotor_speed / model.rated_rotor_speed;
+        let lift = model.max_lift * inputs.collective.clamp(0.0, 1.0) * rotor * rotor;
+        let up = self.up();
+        let speed = glm::length(&self.velocity);
+        let drag = self.velocity * -(model.linear_drag + model.quadratic_drag * speed)
+            - up * (glm::dot(&self.velocity, &up) * model.rotor_damping * rotor);
+        let acceleration = up * lift + drag - glm::vec3(0.0, GRAVITY, 0.0);
+
+        // The cyclic tilts the rotor disc and the tail rotor turns the body. Both need the rotor
+        // to be turning to have any effect.
+        let control = glm::vec3(
+            -inputs.cyclic_pitch * model.cyclic_authority,
+            inputs.pedal * model.pedal_authority,
+            -inputs.cyclic_roll * model.cyclic_authority,
+        ) * rotor;
+        // Stability augmentation turns the rotor axis back towards straight up
+        let tilt = glm::cross(&up, &glm::vec3(0.0, 1.0, 0.0));
+        let mut leveling = glm::quat_rotate_vec3(&glm::quat_inverse(&self.orientation), &tilt) * model.self_leveling;
+        leveling.y = 0.0;
+        let angular_acceleration = control + leveling * rotor - self.angular_velocity * model.angular_damping;
+
+        // Semi-implicit Euler, which keeps the damped motion stable
+        self.velocity += acceleration * timestep;
+        self.position += self.velocity * timestep;
+        self.angular_velocity += angular_acceleration * timestep;
+        let angle = glm::length(&self.angular_velocity) * timestep;
+        if angle > 0.0 {
+            let turn = glm::quat_angle_axis(angle, &glm::normalize(&self.angular_velocity));
+            self.orientation = glm::quat_normalize(&(self.orientation * turn));
+        }
+    }
+
+    // Keep the helicopter from sinking into the ground under it. Once down it sits level on its
+    // skids, facing the way it was, until the rotor lifts it off again. Returns how fast it was
+    // coming down when it touched down this step.
+    pub fn collide_with_ground(&mut self, ground: &TerrainHit, timestep: f32) -> Option<f32> {
+        let skids = self.position.y - self.model.skid_height;
+        if skids > ground.point.y {
+            self.on_ground = false;
+            return None;
+        }
+        self.position.y = ground.point.y + self.model.skid_height;
+        let into_ground = glm::dot(&self.velocity, &ground.normal).min(0.0);
+        self.velocity -= ground.normal * into_ground;
+        self.velocity *= (-self.model.ground_friction * timestep).exp();
+
+        let forward = glm::quat_rotate_vec3(&self.orientation, &glm::vec3(0.0, 0.0, -1.0));
+        let heading = (-forward.x).atan2(-forward.z);
+        self.orientation = glm::quat_angle_axis(heading, &glm::vec3(0.0, 1.0, 0.0));
+        self.angular_velocity.x = 0.0;
+        self.angular_velocity.z = 0.0;
+
+        let touched_down = !self.on_ground;
+        self.on_ground = true;
+        if touched_down {
+            Some(-into_ground)
+        } else {
+            None
+        }
+    }
+
+    // Move the helicopter out of something it ran into, by the given depth along the normal
+    // pointing away from it, and stop it flying any further in
+    pub fn push_out(&mut self, normal: &glm::Vec3, depth: f32) {
+        self.position += normal * depth;
+        let into = glm::dot(&self.velocity, normal).min(0.0);
+        self.velocity -= normal * into;
+    }
+
+    // Pose the helicopter node, with its main rotor as the first child and the tail rotor as the
+    // second, like `build_helicopter` makes them
+    pub fn apply_to(&self, node: &mut SceneNode) {
+        node.position = self.position;
+        node.rotation = animation::quat_to_euler(&self.orientation);
+        node[0].rotation.y = self.rotor_angle;
+        node[1].rotation.x = 2.0 * self.rotor_angle;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::TIMESTEP;
+
+    fn centered(engine_on: bool) -> PilotInputs {
+        PilotInputs::from_controls(&FlightControls::default(), engine_on)
+    }
+
+    #[test]
+    fn half_collective_hovers_in_place() {
+        let start = glm::vec3(3.0, 50.0, -7.0);
+        let mut helicopter = Helicopter::new(start, FlightModel::new());
+        let inputs = centered(true);
+        assert_eq!(inputs.collective, 0.5);
+
+        for _ in 0..(60.0 / TIMESTEP) as usize {
+            helicopter.step(&inputs, TIMESTEP);
+        }
+        assert!(glm::distance(&helicopter.position, &start) < 1e-3, "drifted to {}", helicopter.position);
+        assert!(glm::length(&helicopter.velocity) < 1e-4);
+        assert!((helicopter.up().y - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn without_a_rotor_or_drag_it_falls_like_a_stone() {
+        let model = FlightModel { linear_drag: 0.0, quadratic_drag: 0.0, ..FlightModel::new() };
+        let mut helicopter = Helicopter::new(glm::vec3(0.0, 100.0, 0.0), model);
+        helicopter.rotor_speed = 0.0;
+        let inputs = centered(false);
+
+        let steps = 240;
+        for _ in 0..steps {
+            helicopter.step(&inputs, TIMESTEP);
+        }
+        let time = steps as f32 * TIMESTEP;
+        assert!((helicopter.velocity.y + GRAVITY * time).abs() < 1e-3, "{}", helicopter.velocity);
+        // Semi-implicit Euler falls a little further than the exact 1/2 g t^2, by half a step
+        let fallen = 100.0 - helicopter.position.y;
+        assert!((fallen - 0.5 * GRAVITY * time * (time + TIMESTEP)).abs() < 1e-2, "fell {}", fallen);
+        assert_eq!(helicopter.velocity.x, 0.0);
+        assert_eq!(helicopter.velocity.z, 0.0);
+        assert_eq!(helicopter.orientation, glm::quat_identity());
+    }
+
+    #[test]
+    fn spinning_slows_down_by_itself() {
+        let model = FlightModel::new();
+        let mut helicopter = Helicopter::new(glm::vec3(0.0, 50.0, 0.0), model);
+        helicopter.angular_velocity = glm::vec3(0.0, 2.0, 0.0);
+        let inputs = centered(true);
+
+        let mut previous = helicopter.angular_velocity.y;
+        for _ in 0..(1.0 / TIMESTEP) as usize {
+            helicopter.step(&inputs, TIMESTEP);
+            assert!(helicopter.angular_velocity.y < previous);
+            previous = helicopter.angular_velocity.y;
+        }
+        let expected = 2.0 * (-model.angular_damping).exp();
+        assert!((previous - expected).abs() < 0.05 * expected, "{} after a second, expected {}", previous, expected);
+
+        // Rolling and pitching also die down, and the helicopter comes back level
+        helicopter.angular_velocity = glm::vec3(1.5, 0.0, -2.0);
+        for _ in 0..(10.0 / TIMESTEP) as usize {
+            helicopter.step(&inputs, TIMESTEP);
+        }
+        assert!(glm::length(&helicopter.angular_velocity) < 1e-3, "{}", helicopter.angular_velocity);
+        assert!(helicopter.up().y > 0.9999);
+    }
+
+    #[test]
+    fn stays_stable_over_long_flights() {
+        let mut helicopter = Helicopter::new(glm::vec3(0.0, 500.0, 0.0), FlightModel::new());
+        let steps = (10.0 * 60.0 / TIMESTEP) as usize;
+        for step in 0..steps {
+            // Throw the sticks around, every few seconds to another corner
+            let time = step as f32 * TIMESTEP;
+            let corner = (time / 3.0) as i32;
+            let side = |bit: i32| if corner & bit == 0 { 1.0 } else { -1.0 };
+            let controls = FlightControls { pitch: side(1), roll: side(2), yaw: side(4), throttle: 0.5 * side(8) };
+            helicopter.step(&PilotInputs::from_controls(&controls, true), TIMESTEP);
+
+            let finite = |v: &glm::Vec3| v.iter().all(|x| x.is_finite());
+            assert!(finite(&helicopter.position) && finite(&helicopter.velocity) && finite(&helicopter.angular_velocity));
+            assert!((glm::quat_magnitude(&helicopter.orientation) - 1.0).abs() < 1e-4);
+            assert!(glm::length(&helicopter.velocity) < 100.0, "{} at {}s", helicopter.velocity, time);
+            assert!(glm::length(&helicopter.angular_velocity) < 10.0);
+        }
+
+        // Let go of the sticks, and it levels off and slows down again
+        for _ in 0..(60.0 / TIMESTEP) as usize {
+            helicopter.step(&centered(true), TIMESTEP);
+        }
+        assert!(helicopter.up().y > 0.999);
+        assert!(glm::length(&helicopter.velocity) < 0.1, "{}", helicopter.velocity);
+    }
+}
@@ -0,0 +1,390 @@
+extern crate nalgebra_glm as glm;
+
+use std::path::PathBuf;
+
+use crate::animation;
+use crate::formation::{Formation, GroupMode, GroupSettings};
+use crate::toolbox;
+
+// How the points of a path file shape the curve
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PathKind {
+    CatmullRom, // Passes through every point
+    Bezier,     // Passes through every third point, the two between pull the curve towards them
+}
+
+// Where a helicopter flying the path is and which way it faces
+#[derive(Clone, Copy, Debug)]
+pub struct PathPose {
+    pub position : glm::Vec3,
+    pub velocity : glm::Vec3, // Units per second
+    pub yaw      : f32,       // Heading, 0 flying along -z
+    pub pitch    : f32,       // Nose up
+    pub roll     : f32,       // Right side down when positive, like when banking into a right turn
+}
+
+impl PathPose {
+    // Facing along the velocity, see toolbox::heading_from_motion
+    pub fn from_motion(
+        position: glm::Vec3,
+        velocity: glm::Vec3,
+        acceleration: glm::Vec3,
+        max_bank: f32,
+        nose_down: f32,
+    ) -> Self {
+        let heading = toolbox::heading_from_motion(&position, &velocity, &acceleration, max_bank, nose_down);
+        PathPose { position, velocity, yaw: heading.yaw, pitch: heading.pitch, roll: heading.roll }
+    }
+
+    // The rotation of a SceneNode facing this way. Nodes rotate around the world z axis last,
+    // but a helicopter banks around its own nose, so the angles can't be used as they are.
+    pub fn node_rotation(&self) -> glm::Vec3 {
+        let yaw = glm::quat_angle_axis(self.yaw, &glm::vec3(0.0, 1.0, 0.0));
+        let pitch = glm::quat_angle_axis(self.pitch, &glm::vec3(1.0, 0.0, 0.0));
+        let roll = glm::quat_angle_axis(-self.roll, &glm::vec3(0.0, 0.0, 1.0));
+        animation::quat_to_euler(&(yaw * pitch * roll))
+    }
+}
+
+// Length along the path at regular steps of the curve parameter, to find the point a given
+// distance along it
+#[derive(Clone, Copy, Debug)]
+struct ArcSample {
+    distance : f32,
+    segment  : usize,
+    t        : f32,
+}
+
+const SAMPLES_PER_SEGMENT: usize = 32;
+
+// A route through the air, flown at a constant speed. Either kind of path is turned into cubic
+// Bézier segments, and measured so that distance along it can be turned into a point on it.
+#[derive(Clone, Debug)]
+pub struct FlightPath {
+    pub speed       : f32, // Units per second
+    pub start       : f32, // How far along the path the helicopter is at time 0
+    pub max_bank    : f32, // Radians
+    pub nose_down   : f32, // Radians of forward tilt per unit of speed, as a helicopter leans into its flight
+    pub closed      : bool,
+    pub group       : Option<GroupSettings>, // Helicopters following the one flying the path
+
+    segments: Vec<[glm::Vec3; 4]>,
+    arc_samples: Vec<ArcSample>,
+}
+
+impl FlightPath {
+    pub fn new(kind: PathKind, points: &[glm::Vec3], closed: bool) -> Result<Self, String> {
+        let segments = match kind {
+            PathKind::CatmullRom => catmull_rom_segments(points, closed)?,
+            PathKind::Bezier => bezier_segments(points, closed)?,
+        };
+        let mut path = FlightPath {
+            speed     : 20.0,
+            start     : 0.0,
+            max_bank  : 0.8,
+            nose_down : 0.00875,
+            closed,
+            group     : None,
+            segments,
+            arc_samples: vec![],
+        };
+        path.measure();
+        Ok(path)
+    }
+
+    // A constant speed version of toolbox::simple_heading_animation, the figure eight the
+    // choppers used to fly
+    pub fn figure_eight() -> Self {
+        let circuit_speed = 0.8;
+        let period = std::f32::consts::TAU / circuit_speed;
+        let points: Vec<glm::Vec3> = (0..16)
+            .map(|i| {
+                let heading = toolbox::simple_heading_animation(period * i as f32 / 16.0);
+                glm::vec3(heading.x, 10.0, heading.z)
+            })
+            .collect();
+        let mut path = FlightPath::new(PathKind::CatmullRom, &points, true).expect("Invalid figure eight");
+        path.speed = path.length() / period;
+        path
+    }
+
+    // Read a path from a file like
+    //
+    //     # A loop around the crater
+    //     type catmull_rom   # or bezier
+    //     closed true
+    //     speed 20
+    //     start 0            # Distance along the path at time 0
+    //     point 0 10 -40
+    //     point 30 12 0
+    //     ...
+    //
+    // and optionally a group following the helicopter flying it, either in formation or as a
+    // flock, like
+    //
+    //     formation v 8      # or line, or echelon
+    //     flock 40
+    //     spacing 20         # Distance between neighbours
+    //     cohesion 1         # How hard a flock holds together
+    //     alignment 1        # How hard a flock flies the same way
+    //     separation 1.5     # How hard they keep their distance
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut kind = PathKind::CatmullRom;
+        let mut closed = true;
+        let mut speed = None;
+        let mut start = 0.0;
+        let mut points = vec![];
+        let mut group: Option<GroupSettings> = None;
+        let mut group_settings = vec![];
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = |message: String| format!("line {}: {}", number + 1, message);
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let number = |word: &str| word.parse::<f32>().map_err(|_| error(format!("Invalid number {}", word)));
+            let whole_number = |word: &str| word.parse::<usize>().map_err(|_| error(format!("Invalid count {}", word)));
+            match words.as_slice() {
+                ["type", "catmull_rom"] => kind = PathKind::CatmullRom,
+                ["type", "bezier"] => kind = PathKind::Bezier,
+                ["closed", value] => {
+                    closed = value.parse().map_err(|_| error(format!("Expected true or false, not {}", value)))?
+                }
+                ["speed", value] => speed = Some(number(value)?),
+                ["start", value] => start = number(value)?,
+                ["point", x, y, z] => points.push(glm::vec3(number(x)?, number(y)?, number(z)?)),
+                ["formation", name, count] => {
+                    let formation = Formation::from_name(name)
+                        .ok_or_else(|| error(format!("Unknown formation {}, expected v, line or echelon", name)))?;
+                    group = Some(GroupSettings::new(GroupMode::Formation(formation), whole_number(count)?));
+                }
+                ["flock", count] => group = Some(GroupSettings::new(GroupMode::Flock, whole_number(count)?)),
+                [setting @ ("spacing" | "cohesion" | "alignment" | "separation"), value] => {
+                    let value = number(value)?;
+                    if value < 0.0 || (*setting == "spacing" && value == 0.0) {
+                        return Err(error(format!("Invalid {} {}", setting, value)));
+                    }
+                    group_settings.push((*setting, value));
+                }
+                _ => return Err(error(format!("Unexpected {}", line))),
+            }
+        }
+        let mut path = FlightPath::new(kind, &points, closed)?;
+        if let Some(speed) = speed {
+            if speed <= 0.0 {
+                return Err("The speed has to be above 0".to_string());
+            }
+            path.speed = speed;
+        }
+        path.start = start;
+        if let Some(mut group) = group {
+            for (setting, value) in group_settings {
+                match setting {
+                    "spacing" => group.spacing = value,
+                    "cohesion" => group.cohesion = value,
+                    "alignment" => group.alignment = value,
+                    _ => group.separation = value,
+                }
+            }
+            path.group = Some(group);
+        } else if !group_settings.is_empty() {
+            return Err("Group settings without a formation or flock".to_string());
+        }
+        Ok(path)
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+        FlightPath::parse(&text).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    // Every *.path file in the directory, in the order of their names
+    pub fn load_directory(directory: &str) -> Result<Vec<Self>, String> {
+        let entries = std::fs::read_dir(directory).map_err(|e| format!("Failed to read {}: {}", directory, e))?;
+        let mut files: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|file| file.is_file() && file.extension() == Some("path".as_ref()))
+            .collect();
+        files.sort();
+        files.iter().map(|file| FlightPath::load(&file.to_string_lossy())).collect()
+    }
+
+    // Walk along every segment in small steps and add up the distance
+    fn measure(&mut self) {
+        let mut distance = 0.0;
+        let mut previous = self.segments[0][0];
+        self.arc_samples = vec![ArcSample { distance, segment: 0, t: 0.0 }];
+        for (segment, points) in self.segments.iter().enumerate() {
+            for step in 1..=SAMPLES_PER_SEGMENT {
+                let t = step as f32 / SAMPLES_PER_SEGMENT as f32;
+                let point = bezier(points, t);
+                distance += glm::distance(&previous, &point);
+                previous = point;
+                self.arc_samples.push(ArcSample { distance, segment, t });
+            }
+        }
+    }
+
+    pub fn length(&self) -> f32 {
+        self.arc_samples.last().map_or(0.0, |sample| sample.distance)
+    }
+
+    // The segment and curve parameter at the given distance along the path. Closed paths go
+    // around again, open ones stop at their ends.
+    fn locate(&self, distance: f32) -> (usize, f32) {
+        let length = self.length();
+        let distance = if self.closed && length > 0.0 {
+            distance.rem_euclid(length)
+        } else {
+            distance.clamp(0.0, length)
+        };
+        let after = self.arc_samples.partition_point(|sample| sample.distance < distance).clamp(1, self.arc_samples.len() - 1);
+        let (from, to) = (&self.arc_samples[after - 1], &self.arc_samples[after]);
+        let fraction = if to.distance > from.distance {
+            (distance - from.distance) / (to.distance - from.distance)
+        } else {
+            0.0
+        };
+        // The samples on either side may be the end of one segment and the start of the next
+        let from_t = if from.segment == to.segment { from.t } else { 0.0 };
+        (to.segment, from_t + (to.t - from_t) * fraction)
+    }
+
+    // Where a helicopter flying the path is at the given distance along it, facing along the
+    // path, banking into the turns and leaning forward with its speed
+    pub fn pose_at(&self, distance: f32) -> PathPose {
+        let (segment, t) = self.locate(distance);
+        let points = &self.segments[segment];
+        let position = bezier(points, t);
+        let velocity = bezier_derivative(points, t);
+        let acceleration = bezier_second_derivative(points, t);
+
+        let speed_squared = glm::length2(&velocity).max(1e-6);
+        let forward = velocity / speed_squared.sqrt();
+        // How quickly the direction turns, per unit of distance
+        let curvature = (acceleration - velocity * (glm::dot(&velocity, &acceleration) / speed_squared)) / speed_squared;
+
+        PathPose::from_motion(
+            position,
+            forward * self.speed,
+            curvature * self.speed * self.speed,
+            self.max_bank,
+            self.nose_down,
+        )
+    }
+
+    // The pose at the given time since the start, flying at the path speed
+    pub fn pose_at_time(&self, time: f32) -> PathPose {
+        self.pose_at(self.start + self.speed * time)
+    }
+}
+
+fn bezier(p: &[glm::Vec3; 4], t: f32) -> glm::Vec3 {
+    let s = 1.0 - t;
+    p[0] * (s * s * s) + p[1] * (3.0 * s * s * t) + p[2] * (3.0 * s * t * t) + p[3] * (t * t * t)
+}
+
+fn bezier_derivative(p: &[glm::Vec3; 4], t: f32) -> glm::Vec3 {
+    let s = 1.0 - t;
+    (p[1] - p[0]) * (3.0 * s * s) + (p[2] - p[1]) * (6.0 * s * t) + (p[3] - p[2]) * (3.0 * t * t)
+}
+
+fn bezier_second_derivative(p: &[glm::Vec3; 4], t: f32) -> glm::Vec3 {
+    (p[2] - p[1] * 2.0 + p[0]) * (6.0 * (1.0 - t)) + (p[3] - p[2] * 2.0 + p[1]) * (6.0 * t)
+}
+
+// The Bézier segments of a uniform Catmull-Rom spline through the points. The ends of an open
+// path repeat the end points, so the curve starts and stops on them.
+fn catmull_rom_segments(points: &[glm::Vec3], closed: bool) -> Result<Vec<[glm::Vec3; 4]>, String> {
+    if points.len() < 2 {
+        return Err("A Catmull-Rom path needs at least 2 points".to_string());
+    }
+    let n = points.len() as isize;
+    let point = |i: isize| {
+        if closed {
+            points[i.rem_euclid(n) as usize]
+        } else {
+            points[i.clamp(0, n - 1) as usize]
+        }
+    };
+    let segment_count = if closed { n } else { n - 1 };
+    Ok((0..segment_count)
+        .map(|i| {
+            let (p0, p1, p2, p3) = (point(i - 1), point(i), point(i + 1), point(i + 2));
+            [p1, p1 + (p2 - p0) / 6.0, p2 - (p3 - p1) / 6.0, p2]
+        })
+        .collect())
+}
+
+// Every three points after the first make a segment. A closed path goes from the last point back
+// to the first, so it has a multiple of three points instead.
+fn bezier_segments(points: &[glm::Vec3], closed: bool) -> Result<Vec<[glm::Vec3; 4]>, String> {
+    let given = points.len();
+    let mut points = points.to_vec();
+    if closed && !points.is_empty() {
+        points.push(points[0]);
+    }
+    if points.len() < 4 || (points.len() - 1) % 3 != 0 {
+        return Err(format!("A Bézier path needs 3n + 1 points, or 3n if it is closed, not {}", given));
+    }
+    Ok(points.windows(4).step_by(3).map(|p| [p[0], p[1], p[2], p[3]]).collect())
+}
+
+// A hash of everything about the paths that decides how they are flown, to tell whether two sets
+// of paths are the same. FNV-1a, like the fingerprint of a mesh in a saved BVH.
+pub fn fingerprint(paths: &[FlightPath]) -> u64 {
+    let mut numbers = vec![paths.len() as f32];
+    for path in paths {
+        numbers.extend_from_slice(&[path.speed, path.start, path.max_bank, path.nose_down, path.closed as u8 as f32]);
+        numbers.push(path.segments.len() as f32);
+        numbers.extend(path.segments.iter().flatten().flat_map(|point| point.iter().copied()));
+        match path.group {
+            Some(group) => {
+                let mode = match group.mode {
+                    GroupMode::Formation(Formation::V) => 1.0,
+                    GroupMode::Formation(Formation::Line) => 2.0,
+                    GroupMode::Formation(Formation::Echelon) => 3.0,
+                    GroupMode::Flock => 4.0,
+                };
+                numbers.extend_from_slice(&[mode, group.count as f32, group.spacing, group.cohesion]);
+                numbers.extend_from_slice(&[group.alignment, group.separation, group.max_speed, group.max_acceleration]);
+            }
+            None => numbers.push(0.0),
+        }
+    }
+    let mut hash = 0xcbf29ce484222325u64;
+    for byte in numbers.iter().flat_map(|number| number.to_le_bytes()) {
+        hash = (hash ^ byte as u64).wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn figure_eight_file_flies_the_built_in_figure_eight() {
+        let file = FlightPath::load(concat!(env!("CARGO_MANIFEST_DIR"), "/paths/1_figure_eight.path")).unwrap();
+        let built_in = FlightPath::figure_eight();
+        assert!((file.length() - built_in.length()).abs() < 0.01);
+        assert!((file.speed - built_in.speed).abs() < 0.001);
+        for i in 0..100 {
+            let time = i as f32 * 0.37;
+            let (expected, actual) = (built_in.pose_at_time(time), file.pose_at_time(time));
+            assert!(glm::distance(&expected.position, &actual.position) < 0.01, "{} at {}", actual.position, time);
+            assert!((expected.yaw - actual.yaw).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn bezier_paths_with_the_wrong_number_of_points_are_rejected() {
+        let error = |text: &str| FlightPath::parse(text).unwrap_err();
+        let message = |count: usize| format!("A Bézier path needs 3n + 1 points, or 3n if it is closed, not {}", count);
+        assert_eq!(error("type bezier"), message(0));
+        assert_eq!(error("type bezier\nclosed false"), message(0));
+        assert_eq!(error("type bezier\npoint 0 0 0\npoint 1 0 0\npoint 2 0 0\npoint 3 0 0"), message(4));
+        assert!(FlightPath::parse("type bezier\npoint 0 0 0\npoint 1 0 0\npoint 2 0 0").is_ok());
+    }
+}
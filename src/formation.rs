@@ -0,0 +1,380 @@
+extern crate nalgebra_glm as glm;
+
+use crate::flight_path::{FlightPath, PathPose};
+use crate::steering;
+
+// Where the followers fly, relative to the leader
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Formation {
+    V,       // Back and out to either side, every other follower on the same side
+    Line,    // Side by side with the leader, out to either side
+    Echelon, // Back and out to the right, in a diagonal
+}
+
+impl Formation {
+    pub fn from_name(name: &str) -> Option<Formation> {
+        match name {
+            "v" => Some(Formation::V),
+            "line" => Some(Formation::Line),
+            "echelon" => Some(Formation::Echelon),
+            _ => None,
+        }
+    }
+
+    // Where the follower with the given index flies, in spacings to the right of the leader and
+    // behind it
+    fn slot(self, index: usize) -> (f32, f32) {
+        let rank = (index / 2 + 1) as f32;
+        let side = if index % 2 == 0 { 1.0 } else { -1.0 };
+        match self {
+            Formation::V => (side * rank, rank),
+            Formation::Line => (side * rank, 0.0),
+            Formation::Echelon => ((index + 1) as f32, (index + 1) as f32),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GroupMode {
+    Formation(Formation), // Each follower holds its place in the formation
+    Flock,                // The followers stay together around the leader as boids do
+}
+
+// How a group of helicopters following the one flying a path behaves
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GroupSettings {
+    pub mode             : GroupMode,
+    pub count            : usize, // Followers, not counting the leader
+    pub spacing          : f32,   // Distance between neighbours
+    pub cohesion         : f32,   // How hard a flock pulls towards the middle of their neighbours
+    pub alignment        : f32,   // How hard a flock turns to fly the way their neighbours do
+    pub separation       : f32,   // How hard they push away from anyone closer than the spacing
+    pub max_speed        : f32,
+    pub max_acceleration : f32,
+}
+
+impl GroupSettings {
+    pub fn new(mode: GroupMode, count: usize) -> Self {
+        GroupSettings {
+            mode,
+            count,
+            spacing          : 20.0,
+            cohesion         : 1.0,
+            alignment        : 1.0,
+            separation       : 1.5,
+            max_speed        : 50.0,
+            max_acceleration : 25.0,
+        }
+    }
+}
+
+// How quickly a follower matches the speed it should fly at, per second
+const RESPONSE: f32 = 2.0;
+// How quickly it closes the distance to where it should be, per second. A flock only loosely
+// follows the leader, or it would bunch up behind it.
+const POSITION_GAIN: f32 = 0.8;
+const FLOCK_FOLLOWING: f32 = 0.05;
+const COHESION_GAIN: f32 = 0.4;
+// How far around them the flock looks for neighbours, in spacings
+const NEIGHBOURHOOD: f32 = 1.5;
+// Seconds for the bank to follow the turns, which keeps small corrections from rocking it
+const BANK_SMOOTHING: f32 = 0.3;
+
+#[derive(Clone, Copy, Debug)]
+struct Boid {
+    position     : glm::Vec3,
+    velocity     : glm::Vec3,
+    acceleration : glm::Vec3, // Smoothed, for the bank
+    heading      : glm::Vec3, // Which way it faced when it last moved
+}
+
+// Helicopters following a leader, either holding their places in a formation or flocking around
+// it. Each is a boid steered by its own acceleration, and they only look at neighbours in nearby
+// cells of a grid, so a group can be hundreds strong.
+pub struct Group {
+    pub settings : GroupSettings,
+    pub max_bank : f32,
+    pub nose_down: f32,
+
+    boids: Vec<Boid>,
+    slots: Vec<glm::Vec3>, // Where the slots of the formation were at the last step
+}
+
+impl Group {
+    // The followers of the leader flying the path, in their places around it at the given time
+    pub fn new(settings: GroupSettings, path: &FlightPath, time: f32) -> Self {
+        let leader = path.pose_at_time(time);
+        let (forward, _) = leader_axes(&leader);
+        // A flock starts out in a square behind the leader, and spreads from there
+        let side = (settings.count as f32).sqrt().ceil().max(1.0) as usize;
+        let start = |i: usize| match settings.mode {
+            GroupMode::Formation(formation) => formation.slot(i),
+            GroupMode::Flock => ((i % side) as f32 - (side - 1) as f32 / 2.0, (i / side + 1) as f32),
+        };
+        let slots: Vec<glm::Vec3> = (0..settings.count).map(|i| slot_position(&leader, start(i), settings.spacing)).collect();
+        let boids = slots
+            .iter()
+            .map(|&position| Boid {
+                position,
+                velocity     : leader.velocity,
+                acceleration : glm::zero(),
+                heading      : forward,
+            })
+            .collect();
+        Group { settings, max_bank: path.max_bank, nose_down: path.nose_down, boids, slots }
+    }
+
+    pub fn len(&self) -> usize {
+        self.boids.len()
+    }
+
+    // Where the follower is and which way it faces
+    pub fn pose(&self, index: usize) -> PathPose {
+        let boid = &self.boids[index];
+        let speed = glm::length(&boid.velocity);
+        let velocity = if speed > 0.5 { boid.velocity } else { boid.heading * speed.max(1e-3) };
+        PathPose::from_motion(boid.position, velocity, boid.acceleration, self.max_bank, self.nose_down)
+    }
+
+    // Move the followers one step further, after the leader, keeping them away from each other,
+    // the leader and the obstacles
+    pub fn step(&mut self, leader: &PathPose, obstacles: &[glm::Vec3], timestep: f32) {
+        let settings = self.settings;
+        let (forward, _) = leader_axes(leader);
+        // The slots swing around with the leader as it turns, so the followers fly along with
+        // them rather than with the leader
+        let slots: Vec<glm::Vec3> = match settings.mode {
+            GroupMode::Formation(formation) => {
+                (0..self.boids.len()).map(|i| slot_position(leader, formation.slot(i), settings.spacing)).collect()
+            }
+            GroupMode::Flock => vec![],
+        };
+        let radius = match settings.mode {
+            GroupMode::Formation(_) => settings.spacing,
+            GroupMode::Flock => settings.spacing * NEIGHBOURHOOD,
+        };
+        let grid = Grid::new(&self.boids, radius);
+
+        let accelerations: Vec<glm::Vec3> = (0..self.boids.len())
+            .map(|i| {
+                let boid = &self.boids[i];
+                let mut acceleration = match settings.mode {
+                    GroupMode::Formation(_) => {
+                        let slot_velocity = match self.slots.get(i) {
+                            Some(previous) if timestep > 0.0 => (slots[i] - previous) / timestep,
+                            _ => leader.velocity,
+                        };
+                        self.arrive(boid, &slots[i], &slot_velocity, POSITION_GAIN)
+                    }
+                    GroupMode::Flock => {
+                        let behind = leader.position - forward * settings.spacing * 2.0;
+                        self.arrive(boid, &behind, &leader.velocity, FLOCK_FOLLOWING)
+                    }
+                };
+                acceleration += self.follow_neighbours(i, &grid, radius);
+
+                // Keep clear of the leader and anything else in the way
+                for other in std::iter::once(&leader.position).chain(obstacles) {
+                    acceleration += self.push(&boid.position, other, i) * settings.separation;
+                }
+
+                if glm::length(&acceleration) > settings.max_acceleration {
+                    acceleration = glm::normalize(&acceleration) * settings.max_acceleration;
+                }
+                acceleration
+            })
+            .collect();
+
+        self.slots = slots;
+        let smoothing = 1.0 - (-timestep / BANK_SMOOTHING).exp();
+        for (boid, acceleration) in self.boids.iter_mut().zip(accelerations) {
+            boid.velocity += acceleration * timestep;
+            if glm::length(&boid.velocity) > settings.max_speed {
+                boid.velocity = glm::normalize(&boid.velocity) * settings.max_speed;
+            }
+            boid.position += boid.velocity * timestep;
+            boid.acceleration += (acceleration - boid.acceleration) * smoothing;
+            if glm::length(&boid.velocity) > 0.5 {
+                boid.heading = glm::normalize(&boid.velocity);
+            }
+        }
+    }
+
+    // Keep the followers above the given height of the ground under them, as far as there is one
+    pub fn keep_above(&mut self, height_at: impl Fn(f32, f32) -> Option<f32>) {
+        for boid in &mut self.boids {
+            if let Some(height) = height_at(boid.position.x, boid.position.z) {
+                if boid.position.y < height {
+                    boid.position.y = height;
+                    boid.velocity.y = boid.velocity.y.max(0.0);
+                }
+            }
+        }
+    }
+
+    // Steer to fly along with the given velocity, closing in on the target on the way. The gain
+    // is how much faster to fly for every unit away from it.
+    fn arrive(&self, boid: &Boid, target: &glm::Vec3, velocity: &glm::Vec3, gain: f32) -> glm::Vec3 {
+        let mut desired = velocity + (target - boid.position) * gain;
+        if glm::length(&desired) > self.settings.max_speed {
+            desired = glm::normalize(&desired) * self.settings.max_speed;
+        }
+        (desired - boid.velocity) * RESPONSE
+    }
+
+    // Keep clear of the neighbours, and in a flock also hold together with and fly along with
+    // those within the radius
+    fn follow_neighbours(&self, index: usize, grid: &Grid, radius: f32) -> glm::Vec3 {
+        let boid = &self.boids[index];
+        let flocking = self.settings.mode == GroupMode::Flock;
+        let (mut push, mut center, mut velocity, mut count) = (glm::Vec3::zeros(), glm::Vec3::zeros(), glm::Vec3::zeros(), 0);
+        for other in grid.near(&boid.position).filter(|&j| j != index).map(|j| &self.boids[j]) {
+            if glm::distance2(&other.position, &boid.position) >= radius * radius {
+                continue;
+            }
+            push += self.push(&boid.position, &other.position, index);
+            if flocking {
+                center += other.position;
+                velocity += other.velocity;
+                count += 1;
+            }
+        }
+        let mut acceleration = push * self.settings.separation;
+        if count > 0 {
+            let count = count as f32;
+            acceleration += (center / count - boid.position) * (COHESION_GAIN * self.settings.cohesion)
+                + (velocity / count - boid.velocity) * (RESPONSE * self.settings.alignment);
+        }
+        acceleration
+    }
+
+    // The acceleration away from something at `other`, from nothing at the spacing up to the
+    // most the follower can manage right on top of it
+    fn push(&self, position: &glm::Vec3, other: &glm::Vec3, index: usize) -> glm::Vec3 {
+        steering::push_apart(position, other, index, self.settings.spacing, self.settings.max_acceleration)
+    }
+}
+
+// Which way the leader flies and its right, level with the ground
+fn leader_axes(leader: &PathPose) -> (glm::Vec3, glm::Vec3) {
+    let forward = glm::vec3(-leader.yaw.sin(), 0.0, -leader.yaw.cos());
+    (forward, glm::vec3(-forward.z, 0.0, forward.x))
+}
+
+// The point the given number of spacings right of the leader and behind it
+fn slot_position(leader: &PathPose, (right, back): (f32, f32), spacing: f32) -> glm::Vec3 {
+    let (forward, right_axis) = leader_axes(leader);
+    leader.position + (right_axis * right - forward * back) * spacing
+}
+
+// The boids sorted by the cube of space they're in, with cubes as big as the distance they look
+// around them, so their neighbours are in the cube they're in or the ones next to it
+struct Grid {
+    cell_size: f32,
+    cells: Vec<((i32, i32, i32), usize)>, // The cube of each boid and its index, in order of the cubes
+}
+
+impl Grid {
+    fn new(boids: &[Boid], cell_size: f32) -> Self {
+        let mut grid = Grid { cell_size, cells: Vec::with_capacity(boids.len()) };
+        for (i, boid) in boids.iter().enumerate() {
+            grid.cells.push((grid.cell(&boid.position), i));
+        }
+        grid.cells.sort_unstable();
+        grid
+    }
+
+    fn cell(&self, position: &glm::Vec3) -> (i32, i32, i32) {
+        let cell = position / self.cell_size;
+        (cell.x.floor() as i32, cell.y.floor() as i32, cell.z.floor() as i32)
+    }
+
+    // The boids in the cube around the position and the ones next to it
+    fn near<'a>(&'a self, position: &glm::Vec3) -> impl Iterator<Item = usize> + 'a {
+        let (x, y, z) = self.cell(position);
+        (-1..=1)
+            .flat_map(move |dx| (-1..=1).flat_map(move |dy| (-1..=1).map(move |dz| (x + dx, y + dy, z + dz))))
+            .flat_map(move |cell| {
+                let start = self.cells.partition_point(|&(other, _)| other < cell);
+                self.cells[start..].iter().take_while(move |&&(other, _)| other == cell).map(|&(_, i)| i)
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORMATIONS: [Formation; 3] = [Formation::V, Formation::Line, Formation::Echelon];
+
+    fn leader(yaw: f32) -> PathPose {
+        PathPose { position: glm::vec3(10.0, 40.0, -5.0), velocity: glm::zero(), yaw, pitch: 0.2, roll: -0.4 }
+    }
+
+    #[test]
+    fn followers_take_turns_on_the_right_and_left() {
+        for &formation in &[Formation::V, Formation::Line] {
+            for index in 0..8 {
+                let (right, _) = formation.slot(index);
+                assert_eq!(right.signum(), if index % 2 == 0 { 1.0 } else { -1.0 }, "{:?} {}", formation, index);
+                assert_eq!(right.abs(), (index / 2 + 1) as f32);
+            }
+        }
+        // An echelon is all on the right
+        assert!((0..8).all(|index| Formation::Echelon.slot(index).0 > 0.0));
+    }
+
+    #[test]
+    fn slots_are_a_spacing_apart() {
+        let spacing = 20.0;
+        let leader = leader(0.0);
+        let position = |formation: Formation, index: usize| slot_position(&leader, formation.slot(index), spacing);
+        let close = |a: f32, b: f32| (a - b).abs() < 1e-4;
+        let diagonal = spacing * 2.0_f32.sqrt();
+
+        // Side by side in a line, one spacing further out each
+        assert!(close(glm::distance(&position(Formation::Line, 0), &leader.position), spacing));
+        assert!(close(glm::distance(&position(Formation::Line, 1), &leader.position), spacing));
+        assert!(close(glm::distance(&position(Formation::Line, 0), &position(Formation::Line, 2)), spacing));
+        assert!(close(glm::distance(&position(Formation::Line, 0), &position(Formation::Line, 1)), 2.0 * spacing));
+        // Diagonally back along the arms of a V or the echelon
+        for &formation in &[Formation::V, Formation::Echelon] {
+            assert!(close(glm::distance(&position(formation, 0), &leader.position), diagonal));
+        }
+        assert!(close(glm::distance(&position(Formation::V, 1), &position(Formation::V, 3)), diagonal));
+        assert!(close(glm::distance(&position(Formation::Echelon, 1), &position(Formation::Echelon, 2)), diagonal));
+    }
+
+    #[test]
+    fn slots_turn_with_the_leader_and_stay_level() {
+        // Flying along -x, the right of the leader is -z
+        let turned = leader(std::f32::consts::FRAC_PI_2);
+        let offset = slot_position(&turned, Formation::V.slot(0), 20.0) - turned.position;
+        assert!(glm::distance(&offset, &glm::vec3(20.0, 0.0, -20.0)) < 1e-4, "{}", offset);
+
+        for &formation in &FORMATIONS {
+            for index in 0..5 {
+                let ahead = slot_position(&leader(0.0), formation.slot(index), 20.0) - leader(0.0).position;
+                for &yaw in &[0.3, -1.2, 2.5, 4.0] {
+                    let expected = glm::rotate_y_vec3(&ahead, yaw);
+                    let offset = slot_position(&leader(yaw), formation.slot(index), 20.0) - leader(yaw).position;
+                    assert!(glm::distance(&offset, &expected) < 1e-4, "{:?} {} at {}", formation, index, yaw);
+                    assert_eq!(offset.y, 0.0);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn groups_without_followers_do_nothing() {
+        let path = FlightPath::figure_eight();
+        for &mode in &[GroupMode::Formation(Formation::V), GroupMode::Formation(Formation::Echelon), GroupMode::Flock] {
+            let mut group = Group::new(GroupSettings::new(mode, 0), &path, 0.0);
+            for step in 0..10 {
+                group.step(&path.pose_at_time(step as f32 / 60.0), &[glm::vec3(0.0, 40.0, 0.0)], 1.0 / 60.0);
+            }
+            group.keep_above(|_, _| Some(0.0));
+            assert_eq!(group.len(), 0);
+        }
+    }
+}
@@ -0,0 +1,42 @@
+use crate::backend::{Filter, RenderBackend};
+
+// An offscreen render target with a color and a depth attachment. Single sampled targets keep
+// their color in a texture, so later passes can sample it. Multisampled targets use
+// renderbuffers instead, and have to be resolved into a single sampled target before use.
+// Targets are made and freed by the backend, so they live as long as it does unless deleted
+// through it.
+pub struct Framebuffer {
+    pub id: u32,
+    pub color_texture: u32,      // 0 if multisampled
+    pub color_renderbuffer: u32, // 0 if single sampled
+    pub depth_renderbuffer: u32,
+    pub width: i32,
+    pub height: i32,
+    pub samples: i32,
+}
+
+impl Framebuffer {
+    pub fn new(backend: &mut dyn RenderBackend, width: i32, height: i32, samples: i32) -> Self {
+        backend.create_framebuffer(width, height, samples)
+    }
+
+    // Recreate the attachments with a new size. Does nothing if the size is unchanged.
+    pub fn resize(&mut self, backend: &mut dyn RenderBackend, width: i32, height: i32) {
+        if width == self.width && height == self.height {
+            return;
+        }
+        backend.delete_framebuffer(self);
+        *self = backend.create_framebuffer(width, height, self.samples);
+    }
+
+    // Render into this target from now on
+    pub fn bind(&self, backend: &mut dyn RenderBackend) {
+        backend.bind_framebuffer(self.id, self.width, self.height);
+    }
+
+    // Copy the color attachment into another target, averaging the samples if this target is
+    // multisampled. The sizes have to match when resolving multisampled targets.
+    pub fn resolve_into(&self, backend: &mut dyn RenderBackend, target: &Framebuffer) {
+        backend.blit(self, target.id, target.width, target.height, Filter::Nearest);
+    }
+}
@@ -0,0 +1,481 @@
+// Flying the controllable helicopter with a gamepad. The sticks are read from a GamepadSource,
+// which is either a real controller through gilrs (behind the `gamepad` feature, since gilrs
+// needs libudev on Linux) or a script of timed events for repeatable runs.
+
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GamepadAxis {
+    LeftStickX,
+    LeftStickY,
+    RightStickX,
+    RightStickY,
+    LeftTrigger,
+    RightTrigger,
+}
+
+impl GamepadAxis {
+    const ALL: [GamepadAxis; 6] = [
+        GamepadAxis::LeftStickX,
+        GamepadAxis::LeftStickY,
+        GamepadAxis::RightStickX,
+        GamepadAxis::RightStickY,
+        GamepadAxis::LeftTrigger,
+        GamepadAxis::RightTrigger,
+    ];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            GamepadAxis::LeftStickX => "left_stick_x",
+            GamepadAxis::LeftStickY => "left_stick_y",
+            GamepadAxis::RightStickX => "right_stick_x",
+            GamepadAxis::RightStickY => "right_stick_y",
+            GamepadAxis::LeftTrigger => "left_trigger",
+            GamepadAxis::RightTrigger => "right_trigger",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<GamepadAxis> {
+        GamepadAxis::ALL.iter().copied().find(|axis| axis.name() == name)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GamepadEvent {
+    Connected,
+    Disconnected,
+    AxisChanged(GamepadAxis, f32), // Sticks go from -1 to 1 with y up, triggers from 0 to 1
+}
+
+// Where gamepad events come from
+pub trait GamepadSource {
+    // The events since the last poll. `time` is the simulated time in seconds.
+    fn poll(&mut self, time: f32) -> Vec<GamepadEvent>;
+}
+
+// Plays back events at fixed times, e.g. to fly the same path in every headless run
+pub struct ScriptedSource {
+    events: Vec<(f32, GamepadEvent)>, // Sorted by time
+    next: usize,
+}
+
+impl ScriptedSource {
+    pub fn new(mut events: Vec<(f32, GamepadEvent)>) -> Self {
+        events.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+        ScriptedSource { events, next: 0 }
+    }
+
+    // Read a script with one event per line, like
+    //
+    //     # time  axis           value
+    //     0.0     connect
+    //     0.5     right_stick_y  0.4
+    //     3.0     right_stick_y  0
+    //     8.0     disconnect
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut events = vec![];
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let words: Vec<&str> = line.split_whitespace().collect();
+            events.push(parse_event(&words).map_err(|e| format!("line {}: {}", number + 1, e))?);
+        }
+        Ok(ScriptedSource::new(events))
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+        ScriptedSource::parse(&text).map_err(|e| format!("{}: {}", path, e))
+    }
+}
+
+// Read one timed event from the words of a script line
+pub fn parse_event(words: &[&str]) -> Result<(f32, GamepadEvent), String> {
+    let time = words.first().unwrap_or(&"");
+    let time: f32 = time.parse().map_err(|_| format!("Invalid time {}", time))?;
+    let event = match &words[1..] {
+        ["connect"] => GamepadEvent::Connected,
+        ["disconnect"] => GamepadEvent::Disconnected,
+        [axis, value] => {
+            let axis = GamepadAxis::from_name(axis).ok_or(format!("Unknown axis {}", axis))?;
+            let value: f32 = value.parse().map_err(|_| format!("Invalid value {}", value))?;
+            GamepadEvent::AxisChanged(axis, value.clamp(-1.0, 1.0))
+        }
+        _ => return Err("Expected `time axis value`, `time connect` or `time disconnect`".to_string()),
+    };
+    Ok((time, event))
+}
+
+// Write a timed event the way `parse_event` reads it
+pub fn format_event(time: f32, event: &GamepadEvent) -> String {
+    match event {
+        GamepadEvent::Connected => format!("{} connect", time),
+        GamepadEvent::Disconnected => format!("{} disconnect", time),
+        GamepadEvent::AxisChanged(axis, value) => format!("{} {} {}", time, axis.name(), value),
+    }
+}
+
+impl GamepadSource for ScriptedSource {
+    fn poll(&mut self, time: f32) -> Vec<GamepadEvent> {
+        let start = self.next;
+        while self.next < self.events.len() && self.events[self.next].0 <= time {
+            self.next += 1;
+        }
+        self.events[start..self.next].iter().map(|&(_, event)| event).collect()
+    }
+}
+
+// Every controller plugged in, through gilrs
+#[cfg(feature = "gamepad")]
+pub struct GilrsSource {
+    gilrs: gilrs::Gilrs,
+}
+
+#[cfg(feature = "gamepad")]
+impl GilrsSource {
+    pub fn new() -> Result<Self, String> {
+        let gilrs = gilrs::Gilrs::new().map_err(|e| format!("Failed to open gamepads: {}", e))?;
+        for (_, gamepad) in gilrs.gamepads() {
+            println!("Gamepad: {}", gamepad.name());
+        }
+        Ok(GilrsSource { gilrs })
+    }
+}
+
+#[cfg(feature = "gamepad")]
+impl GamepadSource for GilrsSource {
+    fn poll(&mut self, _time: f32) -> Vec<GamepadEvent> {
+        use gilrs::{Axis, Button, EventType};
+
+        let mut events = vec![];
+        while let Some(gilrs::Event { event, .. }) = self.gilrs.next_event() {
+            let axis = |axis| match axis {
+                Axis::LeftStickX => Some(GamepadAxis::LeftStickX),
+                Axis::LeftStickY => Some(GamepadAxis::LeftStickY),
+                Axis::RightStickX => Some(GamepadAxis::RightStickX),
+                Axis::RightStickY => Some(GamepadAxis::RightStickY),
+                _ => None,
+            };
+            match event {
+                EventType::Connected => events.push(GamepadEvent::Connected),
+                EventType::Disconnected => events.push(GamepadEvent::Disconnected),
+                EventType::AxisChanged(changed, value, _) => {
+                    if let Some(axis) = axis(changed) {
+                        events.push(GamepadEvent::AxisChanged(axis, value));
+                    }
+                }
+                // The analog triggers are buttons with a value in gilrs
+                EventType::ButtonChanged(Button::LeftTrigger2, value, _) => {
+                    events.push(GamepadEvent::AxisChanged(GamepadAxis::LeftTrigger, value));
+                }
+                EventType::ButtonChanged(Button::RightTrigger2, value, _) => {
+                    events.push(GamepadEvent::AxisChanged(GamepadAxis::RightTrigger, value));
+                }
+                _ => {}
+            }
+        }
+        events
+    }
+}
+
+// How stick deflection turns into control input
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ResponseCurve {
+    Linear,
+    Power(f32), // |x|^p with the sign kept, above 1 for finer control around the center
+    Expo(f32),  // Blend between linear (0) and cubic (1), like on RC transmitters
+}
+
+impl Default for ResponseCurve {
+    fn default() -> Self {
+        ResponseCurve::Expo(0.4)
+    }
+}
+
+impl ResponseCurve {
+    pub fn apply(self, x: f32) -> f32 {
+        match self {
+            ResponseCurve::Linear => x,
+            ResponseCurve::Power(power) => x.signum() * x.abs().powf(power),
+            ResponseCurve::Expo(expo) => (1.0 - expo) * x + expo * x * x * x,
+        }
+    }
+
+    // Read a curve written like `linear`, `power:2` or `expo:0.4`. Only curves that keep full
+    // deflection at 1 and grow with the deflection are accepted.
+    pub fn from_name(name: &str) -> Option<ResponseCurve> {
+        let (kind, amount) = name.split_once(':').unwrap_or((name, ""));
+        let value = || amount.parse::<f32>().ok().filter(|value| value.is_finite());
+        match kind {
+            "linear" if !name.contains(':') => Some(ResponseCurve::Linear),
+            "power" => value().filter(|&power| power > 0.0).map(ResponseCurve::Power),
+            "expo" => value().filter(|expo| (0.0..=1.0).contains(expo)).map(ResponseCurve::Expo),
+            _ => None,
+        }
+    }
+}
+
+// Written the way `from_name` reads it
+impl fmt::Display for ResponseCurve {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ResponseCurve::Linear => write!(f, "linear"),
+            ResponseCurve::Power(power) => write!(f, "power:{}", power),
+            ResponseCurve::Expo(expo) => write!(f, "expo:{}", expo),
+        }
+    }
+}
+
+// Ignores small deflections around the center, where worn sticks never quite return to zero,
+// and rescales the rest so the output still reaches 1. Works on both axes of a stick at once,
+// so diagonals are not cut off.
+pub fn apply_deadzone(x: f32, y: f32, deadzone: f32) -> (f32, f32) {
+    let length = (x * x + y * y).sqrt();
+    if length <= deadzone || length == 0.0 {
+        return (0.0, 0.0);
+    }
+    let scale = ((length - deadzone) / (1.0 - deadzone)).min(1.0) / length;
+    (x * scale, y * scale)
+}
+
+// What the pilot asks of the helicopter, every value from -1 to 1
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FlightControls {
+    pub pitch    : f32, // Nose down
+    pub roll     : f32, // Right side down
+    pub yaw      : f32, // Nose left
+    pub throttle : f32, // Climb
+}
+
+impl FlightControls {
+    // Add another set of controls, e.g. the keyboard on top of the gamepad
+    pub fn combine(&self, other: &FlightControls) -> FlightControls {
+        FlightControls {
+            pitch    : (self.pitch + other.pitch).clamp(-1.0, 1.0),
+            roll     : (self.roll + other.roll).clamp(-1.0, 1.0),
+            yaw      : (self.yaw + other.yaw).clamp(-1.0, 1.0),
+            throttle : (self.throttle + other.throttle).clamp(-1.0, 1.0),
+        }
+    }
+}
+
+// The state of the gamepad, with the sticks laid out like an RC transmitter in mode 2: the left
+// stick is throttle and yaw, the right stick is pitch and roll
+pub struct Gamepad {
+    pub deadzone         : f32,
+    pub curve            : ResponseCurve,
+    pub trigger_deadzone : f32,
+    pub connected        : bool,
+    pub record           : bool, // Keep the events, for `take_recorded`
+
+    source: Box<dyn GamepadSource>,
+    axes: [f32; 6],
+    recorded: Vec<(f32, GamepadEvent)>,
+}
+
+impl Gamepad {
+    pub fn new(source: Box<dyn GamepadSource>) -> Self {
+        Gamepad {
+            deadzone         : 0.15,
+            curve            : ResponseCurve::default(),
+            trigger_deadzone : 0.05,
+            connected        : false,
+            record           : false,
+            source,
+            axes: [0.0; 6],
+            recorded: vec![],
+        }
+    }
+
+    // A gamepad reading real controllers, if gilrs is compiled in and works
+    pub fn connect() -> Option<Self> {
+        #[cfg(feature = "gamepad")]
+        {
+            match GilrsSource::new() {
+                Ok(source) => return Some(Gamepad::new(Box::new(source))),
+                Err(e) => println!("{}", e),
+            }
+        }
+        None
+    }
+
+    pub fn update(&mut self, time: f32) {
+        for event in self.source.poll(time) {
+            if self.record {
+                self.recorded.push((time, event));
+            }
+            match event {
+                GamepadEvent::Connected => self.connected = true,
+                GamepadEvent::Disconnected => {
+                    // Don't keep flying on the last stick positions
+                    self.connected = false;
+                    self.axes = [0.0; 6];
+                }
+                GamepadEvent::AxisChanged(axis, value) => {
+                    self.connected = true;
+                    self.axes[axis.index()] = value;
+                }
+            }
+        }
+    }
+
+    // The events since the last call, with the times they were polled at. Only kept while
+    // `record` is on.
+    pub fn take_recorded(&mut self) -> Vec<(f32, GamepadEvent)> {
+        std::mem::take(&mut self.recorded)
+    }
+
+    // The raw value of an axis, as last reported
+    pub fn axis(&self, axis: GamepadAxis) -> f32 {
+        self.axes[axis.index()]
+    }
+
+    // A stick with the deadzone and response curve applied
+    fn stick(&self, x: GamepadAxis, y: GamepadAxis) -> (f32, f32) {
+        let (x, y) = apply_deadzone(self.axis(x), self.axis(y), self.deadzone);
+        (self.curve.apply(x), self.curve.apply(y))
+    }
+
+    fn trigger(&self, axis: GamepadAxis) -> f32 {
+        let value = self.axis(axis);
+        if value <= self.trigger_deadzone {
+            0.0
+        } else {
+            (value - self.trigger_deadzone) / (1.0 - self.trigger_deadzone)
+        }
+    }
+
+    pub fn flight_controls(&self) -> FlightControls {
+        let (left_x, left_y) = self.stick(GamepadAxis::LeftStickX, GamepadAxis::LeftStickY);
+        let (right_x, right_y) = self.stick(GamepadAxis::RightStickX, GamepadAxis::RightStickY);
+        // The triggers yaw as well, for pads where the left stick is awkward to twist
+        let triggers = self.trigger(GamepadAxis::LeftTrigger) - self.trigger(GamepadAxis::RightTrigger);
+        FlightControls {
+            pitch    : right_y,
+            roll     : right_x,
+            yaw      : (-left_x + triggers).clamp(-1.0, 1.0),
+            throttle : left_y,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn axis(axis: GamepadAxis, value: f32) -> GamepadEvent {
+        GamepadEvent::AxisChanged(axis, value)
+    }
+
+    #[test]
+    fn scripts_hand_out_each_event_once_its_time_has_come() {
+        // Out of order, to be sorted by time
+        let mut source = ScriptedSource::new(vec![
+            (3.0, GamepadEvent::Disconnected),
+            (0.0, GamepadEvent::Connected),
+            (0.5, axis(GamepadAxis::RightStickY, 0.4)),
+            (0.5, axis(GamepadAxis::LeftStickX, -1.0)),
+        ]);
+        assert_eq!(source.poll(0.0), vec![GamepadEvent::Connected]);
+        assert_eq!(source.poll(0.49), vec![]);
+        assert_eq!(source.poll(0.5), vec![axis(GamepadAxis::RightStickY, 0.4), axis(GamepadAxis::LeftStickX, -1.0)]);
+        assert_eq!(source.poll(0.5), vec![]);
+        assert_eq!(source.poll(10.0), vec![GamepadEvent::Disconnected]);
+        assert_eq!(source.poll(20.0), vec![]);
+    }
+
+    #[test]
+    fn scripts_read_back_what_is_written() {
+        let script = "# time  axis  value\n0 connect\n\n0.5 right_stick_y 0.4  # Climb\n1.25 left_trigger 3\n8 disconnect\n";
+        let mut source = ScriptedSource::parse(script).unwrap();
+        let events = source.poll(100.0);
+        // Values are clamped to what a gamepad can report
+        let expected = [
+            (0.0, GamepadEvent::Connected),
+            (0.5, axis(GamepadAxis::RightStickY, 0.4)),
+            (1.25, axis(GamepadAxis::LeftTrigger, 1.0)),
+            (8.0, GamepadEvent::Disconnected),
+        ];
+        assert_eq!(events, expected.iter().map(|&(_, event)| event).collect::<Vec<_>>());
+        for (time, event) in &expected {
+            let line = format_event(*time, event);
+            let words: Vec<&str> = line.split_whitespace().collect();
+            assert_eq!(parse_event(&words).unwrap(), (*time, *event), "{}", line);
+        }
+
+        assert_eq!(ScriptedSource::parse("0 connect\n1 left_stick_z 0").err().unwrap(), "line 2: Unknown axis left_stick_z");
+        assert_eq!(ScriptedSource::parse("soon connect").err().unwrap(), "line 1: Invalid time soon");
+        assert_eq!(
+            ScriptedSource::parse("1 left_stick_x").err().unwrap(),
+            "line 1: Expected `time axis value`, `time connect` or `time disconnect`"
+        );
+    }
+
+    #[test]
+    fn deadzones_start_from_zero_and_still_reach_full_deflection() {
+        let deadzone = 0.15;
+        assert_eq!(apply_deadzone(0.1, -0.1, deadzone), (0.0, 0.0));
+        assert_eq!(apply_deadzone(deadzone, 0.0, deadzone), (0.0, 0.0));
+        // Just past the deadzone is just past zero, not a jump
+        let (x, _) = apply_deadzone(deadzone + 1e-4, 0.0, deadzone);
+        assert!(x > 0.0 && x < 1e-3, "{}", x);
+        assert_eq!(apply_deadzone(1.0, 0.0, deadzone), (1.0, 0.0));
+        assert_eq!(apply_deadzone(0.0, -1.0, deadzone), (0.0, -1.0));
+        // Diagonals keep their direction, and full deflection stays full
+        let (x, y) = apply_deadzone(0.6, 0.8, deadzone);
+        assert!((x - 0.6).abs() < 1e-6 && (y - 0.8).abs() < 1e-6, "{}, {}", x, y);
+        // Halfway out of the deadzone is half deflection
+        let (x, y) = apply_deadzone(0.0, deadzone + (1.0 - deadzone) / 2.0, deadzone);
+        assert_eq!(x, 0.0);
+        assert!((y - 0.5).abs() < 1e-6, "{}", y);
+    }
+
+    #[test]
+    fn curves_keep_the_center_and_the_ends_and_only_grow() {
+        let curves = [ResponseCurve::Linear, ResponseCurve::Power(2.0), ResponseCurve::Power(0.5), ResponseCurve::Expo(0.4), ResponseCurve::Expo(1.0)];
+        for &curve in &curves {
+            assert_eq!(curve.apply(0.0), 0.0, "{}", curve);
+            assert!((curve.apply(1.0) - 1.0).abs() < 1e-6, "{}", curve);
+            assert!((curve.apply(-1.0) + 1.0).abs() < 1e-6, "{}", curve);
+            for i in -100..100 {
+                let (x, next) = (i as f32 / 100.0, (i + 1) as f32 / 100.0);
+                assert!(curve.apply(next) > curve.apply(x), "{} at {}", curve, x);
+                assert_eq!(curve.apply(-x), -curve.apply(x), "{} at {}", curve, x);
+            }
+            assert_eq!(ResponseCurve::from_name(&curve.to_string()), Some(curve));
+        }
+        assert_eq!(ResponseCurve::Linear.apply(0.3), 0.3);
+        assert_eq!(ResponseCurve::Power(2.0).apply(-0.5), -0.25);
+        assert!((ResponseCurve::Expo(0.4).apply(0.5) - 0.35).abs() < 1e-6);
+
+        for name in &["power", "power:0", "power:-1", "expo:1.5", "expo:-0.1", "expo:x", "linear:2", "cubic"] {
+            assert_eq!(ResponseCurve::from_name(name), None, "{}", name);
+        }
+    }
+
+    #[test]
+    fn gamepads_fly_with_the_curve_and_stop_when_unplugged() {
+        let source = ScriptedSource::new(vec![
+            (0.0, axis(GamepadAxis::RightStickY, 0.5)),
+            (0.0, axis(GamepadAxis::LeftStickY, 1.0)),
+            (1.0, GamepadEvent::Disconnected),
+        ]);
+        let mut gamepad = Gamepad::new(Box::new(source));
+        gamepad.deadzone = 0.0;
+        gamepad.curve = ResponseCurve::Power(2.0);
+        gamepad.update(0.0);
+        assert!(gamepad.connected);
+        let controls = gamepad.flight_controls();
+        assert_eq!((controls.pitch, controls.throttle), (0.25, 1.0));
+
+        gamepad.update(1.0);
+        assert!(!gamepad.connected);
+        assert_eq!(gamepad.flight_controls(), FlightControls::default());
+    }
+}
@@ -0,0 +1,418 @@
+extern crate nalgebra_glm as glm;
+
+use crate::backend::{GlBackend, RenderBackend};
+use crate::camera::Camera;
+use crate::capture::FrameSequence;
+use crate::framebuffer::Framebuffer;
+use crate::gamepad::{Gamepad, ResponseCurve, ScriptedSource};
+use crate::input::InputMap;
+use crate::options::Options;
+use crate::raster::SoftwareRenderer;
+use crate::recording::{FrameInput, InputRecording};
+use crate::renderer::{self, Renderer};
+use crate::simulation::{self, FixedTimestep, SceneSnapshot};
+use crate::util::DebugAction;
+
+use context::create_context;
+
+// Which kind of offscreen context to create
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HeadlessBackend {
+    Auto,     // Surfaceless EGL if there is a display server to talk to, OSMesa otherwise
+    Egl,      // Surfaceless EGL, needs an X11 or Wayland display for the event loop
+    OsMesa,   // Software rendering through OSMesa (llvmpipe), needs no display at all
+    Software, // The rasterizer in raster.rs, needs no OpenGL at all
+}
+
+impl HeadlessBackend {
+    pub fn from_name(name: &str) -> Option<HeadlessBackend> {
+        match name {
+            "auto" => Some(HeadlessBackend::Auto),
+            "egl" => Some(HeadlessBackend::Egl),
+            "osmesa" => Some(HeadlessBackend::OsMesa),
+            "software" => Some(HeadlessBackend::Software),
+            _ => None,
+        }
+    }
+}
+
+// What to render when running headless. The size, sample count and resources are shared with
+// the window and come from `Options`.
+pub struct HeadlessOptions {
+    pub frames: Option<u32>,     // 1 if not given, or every frame of a replay
+    pub start_time: f32,         // Simulated time of the first frame in seconds
+    pub frames_per_second: f32,  // Simulated time between frames is one over this
+    pub output_directory: String,
+    pub backend: HeadlessBackend,
+    pub gamepad_script: Option<String>, // Flies the controllable helicopter, see ScriptedSource
+}
+
+impl Default for HeadlessOptions {
+    fn default() -> Self {
+        HeadlessOptions {
+            frames: None,
+            start_time: 0.0,
+            frames_per_second: 60.0,
+            output_directory: "./headless".to_string(),
+            backend: HeadlessBackend::Auto,
+            gamepad_script: None,
+        }
+    }
+}
+
+// glutin only makes surfaceless EGL and OSMesa contexts on the Unix platforms
+#[cfg(any(target_os = "linux", target_os = "dragonfly", target_os = "freebsd", target_os = "netbsd", target_os = "openbsd"))]
+mod context {
+    use glutin::dpi::PhysicalSize;
+    use glutin::platform::unix::HeadlessContextExt;
+    use glutin::{Api, ContextBuilder, GlProfile, GlRequest, NotCurrent};
+
+    use super::{HeadlessBackend, HeadlessOptions, Options};
+
+    // The event loop has to outlive an EGL context, since the context uses its display connection
+    pub struct OffscreenContext {
+        pub context: glutin::Context<NotCurrent>,
+        _event_loop: Option<glutin::event_loop::EventLoop<()>>,
+    }
+
+    pub fn create_context(options: &Options, headless: &HeadlessOptions) -> Result<OffscreenContext, String> {
+        let builder = ContextBuilder::new()
+            .with_gl(GlRequest::Specific(Api::OpenGl, (4, 5)))
+            .with_gl_profile(GlProfile::Core);
+
+        // Auto only tries EGL with a display server around, and falls back to OSMesa if it turns
+        // out it can't be reached
+        let has_display = std::env::var_os("DISPLAY").is_some() || std::env::var_os("WAYLAND_DISPLAY").is_some();
+        let try_egl = match headless.backend {
+            HeadlessBackend::Auto => has_display,
+            HeadlessBackend::Egl => true,
+            _ => false,
+        };
+
+        if try_egl {
+            let event_loop = if has_display { connect_event_loop() } else { None };
+            match event_loop {
+                Some(event_loop) => {
+                    let context = builder
+                        .build_surfaceless(&event_loop)
+                        .map_err(|e| format!("Failed to create a surfaceless EGL context: {}", e))?;
+                    return Ok(OffscreenContext { context, _event_loop: Some(event_loop) });
+                }
+                None if headless.backend == HeadlessBackend::Auto => {
+                    println!("Failed to connect to the display server, falling back to OSMesa");
+                }
+                None => {
+                    return Err("Failed to create a surfaceless EGL context: there is no X11 or Wayland display to connect to, \
+                                try --backend osmesa"
+                        .to_string())
+                }
+            }
+        }
+
+        let context = builder
+            .build_osmesa(PhysicalSize::new(options.width, options.height))
+            .map_err(|e| format!("Failed to create an OSMesa context: {}", e))?;
+        Ok(OffscreenContext { context, _event_loop: None })
+    }
+
+    // The event loop EGL gets its display connection from, or None if the display server can't
+    // be reached. winit panics rather than return an error then, so the panic is caught, and kept
+    // off the terminal.
+    fn connect_event_loop() -> Option<glutin::event_loop::EventLoop<()>> {
+        let hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(|_| {}));
+        let event_loop = std::panic::catch_unwind(glutin::event_loop::EventLoop::new).ok();
+        std::panic::set_hook(hook);
+        event_loop
+    }
+}
+
+// Elsewhere only the software backend renders without a window
+#[cfg(not(any(target_os = "linux", target_os = "dragonfly", target_os = "freebsd", target_os = "netbsd", target_os = "openbsd")))]
+mod context {
+    use super::{HeadlessOptions, Options};
+
+    pub struct OffscreenContext {
+        pub context: glutin::Context<glutin::NotCurrent>,
+    }
+
+    pub fn create_context(_options: &Options, _headless: &HeadlessOptions) -> Result<OffscreenContext, String> {
+        Err("Headless OpenGL contexts aren't supported on this platform, use --backend software".to_string())
+    }
+}
+
+// The gamepad flying the controllable helicopter: the one in the recording when replaying, a
+// script if one was given, or none at all. Replays respond the way the recording did.
+fn scripted_gamepad(
+    headless: &HeadlessOptions,
+    recording: Option<&InputRecording>,
+    curve: ResponseCurve,
+) -> Result<Option<Gamepad>, String> {
+    let (source, curve) = match (recording, &headless.gamepad_script) {
+        (Some(recording), _) => (ScriptedSource::new(recording.gamepad_events()), recording.header.gamepad_curve),
+        (None, Some(path)) => (ScriptedSource::load(path)?, curve),
+        (None, None) => return Ok(None),
+    };
+    let mut gamepad = Gamepad::new(Box::new(source));
+    gamepad.curve = curve;
+    Ok(Some(gamepad))
+}
+
+// Steps the scene from the start time in fixed steps, whatever the frame rate, so a scripted
+// flight takes the same path at every --fps. A replay takes the input and length of every frame
+// from the recording instead, and plays out just like it did in the window.
+struct HeadlessSimulation {
+    timestep: FixedTimestep,
+    start_time: f32,
+    previous_step: SceneSnapshot,
+    gamepad: Option<Gamepad>,
+    player: crate::Player,
+    camera: Camera,
+    frames: Vec<FrameInput>, // The input of every frame to render
+}
+
+impl HeadlessSimulation {
+    fn new(
+        scene: &mut crate::Scene,
+        headless: &HeadlessOptions,
+        recording: Option<InputRecording>,
+        curve: ResponseCurve,
+    ) -> Result<Self, String> {
+        crate::animate_scene(scene, headless.start_time);
+        let gamepad = scripted_gamepad(headless, recording.as_ref(), curve)?;
+        let (timestep, player, frames) = match recording {
+            Some(recording) => {
+                let frame_count = headless.frames.map_or(recording.frames.len(), |frames| frames as usize);
+                let frames = recording.frames.into_iter().take(frame_count).collect();
+                (FixedTimestep::new(recording.header.timestep), crate::Player::new(recording.header.input_map), frames)
+            }
+            None => {
+                let mut timestep = FixedTimestep::new(simulation::TIMESTEP);
+                // Frames are simulated in full, however far apart they are
+                timestep.max_frame_time = f32::INFINITY;
+                // The first frame is at the start time, and every one after that a frame later
+                let frames = (0..headless.frames.unwrap_or(1))
+                    .map(|frame| FrameInput {
+                        delta_time: if frame == 0 { 0.0 } else { 1.0 / headless.frames_per_second },
+                        ..FrameInput::default()
+                    })
+                    .collect();
+                (timestep, crate::Player::new(InputMap::new()), frames)
+            }
+        };
+        Ok(HeadlessSimulation {
+            timestep,
+            start_time: headless.start_time,
+            previous_step: SceneSnapshot::capture(&scene.root_node),
+            gamepad,
+            player,
+            // Same starting point as the cameras in the window
+            camera: crate::create_camera(scene),
+            frames,
+        })
+    }
+
+    // Take in the input of the given frame, simulate the time it covers, and draw the scene as it
+    // is between the last two steps
+    fn run_frame<T>(
+        &mut self,
+        scene: &mut crate::Scene,
+        frame: usize,
+        draw: impl FnOnce(&mut crate::Scene, &Camera, &crate::Player) -> T,
+    ) -> T {
+        let frame = &self.frames[frame];
+        let (camera_input, controls) = self.player.update(frame, &mut self.camera);
+        self.timestep.accumulate(frame.delta_time);
+        while self.timestep.step() {
+            self.previous_step = SceneSnapshot::capture(&scene.root_node);
+            let time = self.start_time + self.timestep.time();
+            crate::update(scene, &mut self.gamepad, &controls, time, self.timestep.timestep);
+        }
+
+        let current_step = simulation::interpolate_scene(&mut scene.root_node, &self.previous_step, self.timestep.alpha());
+        // Place the nodes once per frame, for both the orbit and chase cameras and the renderer
+        unsafe { renderer::update_node_transformations(&mut scene.root_node, &glm::identity()) };
+        self.camera.update(&camera_input, frame.delta_time);
+        let result = draw(scene, &self.camera, &self.player);
+        current_step.apply(&mut scene.root_node);
+        result
+    }
+}
+
+// Render the requested frames offscreen and write them to numbered PNG files, without ever
+// opening a window. With a recording, the frames are the ones recorded.
+pub fn run(options: &Options, headless: &HeadlessOptions, recording: Option<InputRecording>) -> Result<(), String> {
+    if headless.backend == HeadlessBackend::Software {
+        return run_software(options, headless, recording);
+    }
+
+    let offscreen = create_context(options, headless)?;
+    let _context = unsafe {
+        let c = offscreen
+            .context
+            .make_current()
+            .map_err(|(_, e)| format!("Failed to make the context current: {}", e))?;
+        gl::load_with(|symbol| c.get_proc_address(symbol) as *const _);
+        c
+    };
+
+    let debug_sink = unsafe { renderer::init_gl(options.debug_sink()) };
+    let (width, height) = (options.width as i32, options.height as i32);
+    let mut renderer = Renderer::new(unsafe { GlBackend::new() }, width, height, options.samples, &options.shader_directory);
+    renderer.post_chain.settings = options.post_settings;
+    let mut scene = crate::load_scene(options, &mut |mesh| renderer.backend.create_vao(mesh));
+    let mut simulation = HeadlessSimulation::new(&mut scene, headless, recording, options.gamepad_curve)?;
+    let output = Framebuffer::new(&mut renderer.backend, width, height, 0);
+
+    let mut sequence = FrameSequence::new(&headless.output_directory, headless.frames_per_second);
+    for frame in 0..simulation.frames.len() {
+        simulation.run_frame(&mut scene, frame, |scene, camera, player| unsafe {
+            crate::toggle_post_effects(player, &mut renderer.post_chain);
+            renderer.render(&scene.root_node, &mut scene.lighting, camera, Some(&output))
+        });
+        let path = sequence.frame_path();
+        sequence
+            .capture(&mut renderer.backend, output.id, options.width, options.height)
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+        println!("Wrote {}", path.display());
+        debug_sink.check();
+    }
+    if options.gl_debug == DebugAction::Count {
+        println!("OpenGL reported {} debug messages, {} of them errors", debug_sink.received(), debug_sink.errors());
+    }
+    Ok(())
+}
+
+// Same as `run`, but drawn by the software rasterizer. Slow, but gives the same image on every
+// machine.
+fn run_software(options: &Options, headless: &HeadlessOptions, recording: Option<InputRecording>) -> Result<(), String> {
+    let mut software = SoftwareRenderer::new(options.width, options.height);
+    let mut scene = crate::load_scene(options, &mut |mesh| software.register_mesh(mesh));
+    let mut simulation = HeadlessSimulation::new(&mut scene, headless, recording, options.gamepad_curve)?;
+
+    let mut sequence = FrameSequence::new(&headless.output_directory, headless.frames_per_second);
+    std::fs::create_dir_all(&sequence.directory)
+        .map_err(|e| format!("Failed to create {}: {}", sequence.directory.display(), e))?;
+    for frame in 0..simulation.frames.len() {
+        let image = simulation.run_frame(&mut scene, frame, |scene, camera, _| {
+            software.render(&scene.root_node, &scene.lighting, camera)
+        });
+        let path = sequence.frame_path();
+        image
+            .save(&path)
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+        println!("Wrote {}", path.display());
+        sequence.frame += 1;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use glutin::event::{ElementState, VirtualKeyCode};
+
+    use super::*;
+    use crate::bvh::Bvh;
+    use crate::flight_path::{self, FlightPath};
+    use crate::formation::{Formation, GroupMode, GroupSettings};
+    use crate::mesh::{Helicopter, Mesh};
+    use crate::recording::RecordingHeader;
+
+    // Flat ground, 200 units across, in squares of 10
+    fn ground() -> Mesh {
+        let (mut vertices, mut indices) = (vec![], vec![]);
+        for z in 0..=20 {
+            for x in 0..=20 {
+                vertices.extend_from_slice(&[x as f32 * 10.0 - 100.0, 0.0, z as f32 * 10.0 - 100.0]);
+            }
+        }
+        for z in 0..20 {
+            for x in 0..20 {
+                let corner = z * 21 + x;
+                indices.extend_from_slice(&[corner, corner + 21, corner + 1, corner + 1, corner + 21, corner + 22]);
+            }
+        }
+        let count = vertices.len();
+        let index_count = indices.len() as i32;
+        Mesh { normals: vec![0.0; count], colors: vec![1.0; count / 3 * 4], vertices, indices, index_count }
+    }
+
+    // A box for every part of the helicopter, which is all the collisions need
+    fn helicopter() -> Helicopter {
+        let part = |min: [f32; 3], max: [f32; 3]| {
+            let vertices: Vec<f32> = (0..8)
+                .flat_map(|corner| (0..3).map(move |axis| if corner >> axis & 1 == 0 { min[axis] } else { max[axis] }))
+                .collect();
+            Mesh { normals: vec![0.0; 24], colors: vec![1.0; 32], vertices, indices: vec![0, 1, 2], index_count: 3 }
+        };
+        Helicopter {
+            body       : part([-1.0, 0.0, -4.0], [1.0, 2.0, 4.0]),
+            door       : part([1.0, 0.2, -0.5], [1.2, 1.6, 1.0]),
+            main_rotor : part([-5.0, 2.1, -5.0], [5.0, 2.3, 5.0]),
+            tail_rotor : part([0.2, 1.0, 4.5], [0.3, 2.6, 6.1]),
+        }
+    }
+
+    // Uneven frames, with the controllable helicopter taking off, flying around and opening its
+    // door, and the choppers turning on their avoidance
+    fn frames() -> Vec<FrameInput> {
+        use VirtualKeyCode::*;
+        let presses = [(0, H), (40, I), (70, O), (90, V), (100, J)];
+        (0..150)
+            .map(|frame| {
+                let mut keys = vec![];
+                for &(at, key) in &presses {
+                    if frame == at {
+                        keys.push((key, ElementState::Pressed));
+                    } else if frame == at + 25 {
+                        keys.push((key, ElementState::Released));
+                    }
+                }
+                let delta_time = [0.016, 0.021, 0.009, 0.033][frame % 4];
+                FrameInput { delta_time, keys, mouse_delta: (1.0, -0.5), ..FrameInput::default() }
+            })
+            .collect()
+    }
+
+    // The scene as it is drawn every frame of a run through the frames above, with choppers
+    // flying the given paths
+    fn run(paths: &[FlightPath]) -> Vec<String> {
+        let mut next_vao = 0;
+        let terrain = ground();
+        let mut scene = crate::build_scene(&terrain, Bvh::build(&terrain), &helicopter(), paths, &mut |_| {
+            next_vao += 1;
+            next_vao
+        });
+        let recording = InputRecording {
+            header: RecordingHeader {
+                timestep      : simulation::TIMESTEP,
+                terrain       : "ground.obj".to_string(),
+                helicopter    : "helicopter.obj".to_string(),
+                paths         : flight_path::fingerprint(paths),
+                input_map     : InputMap::new(),
+                gamepad_curve : ResponseCurve::default(),
+            },
+            frames: frames(),
+        };
+        let headless = HeadlessOptions::default();
+        let mut simulation = HeadlessSimulation::new(&mut scene, &headless, Some(recording), ResponseCurve::default()).unwrap();
+        // Debug writes out every float exactly, so the same text means the same bits
+        let draw = |scene: &mut crate::Scene, _: &Camera, _: &crate::Player| format!("{:?}", SceneSnapshot::capture(&scene.root_node));
+        (0..simulation.frames.len()).map(|frame| simulation.run_frame(&mut scene, frame, draw)).collect()
+    }
+
+    #[test]
+    fn the_same_input_flies_the_same_way() {
+        let mut flock = FlightPath::figure_eight();
+        flock.group = Some(GroupSettings::new(GroupMode::Flock, 6));
+        let mut formation = FlightPath::figure_eight();
+        formation.start = formation.length() / 2.0;
+        formation.group = Some(GroupSettings::new(GroupMode::Formation(Formation::V), 4));
+        let paths = [flock, formation];
+
+        let first = run(&paths);
+        assert_eq!(first, run(&paths));
+        // Everything moves from frame to frame, so there is something to compare
+        assert!(first.windows(2).all(|frames| frames[0] != frames[1]));
+    }
+}
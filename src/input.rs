@@ -0,0 +1,559 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use glutin::event::{ElementState, VirtualKeyCode};
+
+// Everything the player can do with the keyboard. Axes go from -1 to 1, the other actions are
+// either on or off.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Action {
+    MoveForward,
+    MoveRight,
+    MoveUp,
+    LookUp,
+    TurnRight,
+    Zoom,
+    CameraFreeFly,
+    CameraOrbit,
+    CameraChase,
+    GrabCursor,
+    MouseSensitivityDown,
+    MouseSensitivityUp,
+    InvertMouse,
+    HelicopterPitch,
+    HelicopterRoll,
+    HelicopterYaw,
+    HelicopterThrottle,
+    HelicopterEngine,
+    ChopperAvoidance,
+    DoorSlide,
+    DoorSwing,
+    ToggleTonemap,
+    ToggleGamma,
+    ToggleFxaa,
+    ToggleVignette,
+    RecordFrames,
+    Screenshot,
+}
+
+impl Action {
+    pub const ALL: [Action; 27] = [
+        Action::MoveForward,
+        Action::MoveRight,
+        Action::MoveUp,
+        Action::LookUp,
+        Action::TurnRight,
+        Action::Zoom,
+        Action::CameraFreeFly,
+        Action::CameraOrbit,
+        Action::CameraChase,
+        Action::GrabCursor,
+        Action::MouseSensitivityDown,
+        Action::MouseSensitivityUp,
+        Action::InvertMouse,
+        Action::HelicopterPitch,
+        Action::HelicopterRoll,
+        Action::HelicopterYaw,
+        Action::HelicopterThrottle,
+        Action::HelicopterEngine,
+        Action::ChopperAvoidance,
+        Action::DoorSlide,
+        Action::DoorSwing,
+        Action::ToggleTonemap,
+        Action::ToggleGamma,
+        Action::ToggleFxaa,
+        Action::ToggleVignette,
+        Action::RecordFrames,
+        Action::Screenshot,
+    ];
+
+    // The name used in the config file
+    pub fn name(self) -> &'static str {
+        match self {
+            Action::MoveForward => "move_forward",
+            Action::MoveRight => "move_right",
+            Action::MoveUp => "move_up",
+            Action::LookUp => "look_up",
+            Action::TurnRight => "turn_right",
+            Action::Zoom => "zoom",
+            Action::CameraFreeFly => "camera_free_fly",
+            Action::CameraOrbit => "camera_orbit",
+            Action::CameraChase => "camera_chase",
+            Action::GrabCursor => "grab_cursor",
+            Action::MouseSensitivityDown => "mouse_sensitivity_down",
+            Action::MouseSensitivityUp => "mouse_sensitivity_up",
+            Action::InvertMouse => "invert_mouse",
+            Action::HelicopterPitch => "helicopter_pitch",
+            Action::HelicopterRoll => "helicopter_roll",
+            Action::HelicopterYaw => "helicopter_yaw",
+            Action::HelicopterThrottle => "helicopter_throttle",
+            Action::HelicopterEngine => "helicopter_engine",
+            Action::ChopperAvoidance => "chopper_avoidance",
+            Action::DoorSlide => "door_slide",
+            Action::DoorSwing => "door_swing",
+            Action::ToggleTonemap => "toggle_tonemap",
+            Action::ToggleGamma => "toggle_gamma",
+            Action::ToggleFxaa => "toggle_fxaa",
+            Action::ToggleVignette => "toggle_vignette",
+            Action::RecordFrames => "record_frames",
+            Action::Screenshot => "screenshot",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Action> {
+        Action::ALL.iter().copied().find(|action| action.name() == name)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Modifiers {
+    pub shift : bool,
+    pub ctrl  : bool,
+    pub alt   : bool,
+    pub logo  : bool,
+}
+
+impl Modifiers {
+    // The modifiers currently down
+    pub fn held(input: &InputState) -> Self {
+        let any = |left, right| input.down(left) || input.down(right);
+        Modifiers {
+            shift : any(VirtualKeyCode::LShift, VirtualKeyCode::RShift),
+            ctrl  : any(VirtualKeyCode::LControl, VirtualKeyCode::RControl),
+            alt   : any(VirtualKeyCode::LAlt, VirtualKeyCode::RAlt),
+            logo  : any(VirtualKeyCode::LWin, VirtualKeyCode::RWin),
+        }
+    }
+
+    // Whether every modifier in `other` is also in these
+    pub fn contains(&self, other: &Modifiers) -> bool {
+        (self.shift || !other.shift)
+            && (self.ctrl || !other.ctrl)
+            && (self.alt || !other.alt)
+            && (self.logo || !other.logo)
+    }
+
+    pub fn count(&self) -> usize {
+        [self.shift, self.ctrl, self.alt, self.logo].iter().filter(|&&held| held).count()
+    }
+}
+
+// One key, with the modifiers that have to be held along with it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Binding {
+    pub key       : VirtualKeyCode,
+    pub modifiers : Modifiers,
+    pub scale     : f32, // The value of the action while the binding is held
+}
+
+impl Binding {
+    // Parse a binding like `W`, `-S`, `Ctrl+Shift+F12` or `-Down*0.5`
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let text = text.trim();
+        let (sign, rest) = match text.strip_prefix('-') {
+            Some(rest) => (-1.0, rest),
+            None => (1.0, text.strip_prefix('+').unwrap_or(text)),
+        };
+        let (keys, scale) = match rest.split_once('*') {
+            Some((keys, scale)) => {
+                let scale: f32 = scale.trim().parse().map_err(|_| format!("Invalid scale in {}", text))?;
+                (keys, scale)
+            }
+            None => (rest, 1.0),
+        };
+
+        let mut parts: Vec<&str> = keys.split('+').map(str::trim).collect();
+        let key_name = parts.pop().unwrap_or("");
+        let key = key_from_name(key_name).ok_or(format!("Unknown key {}", key_name))?;
+        let mut modifiers = Modifiers::default();
+        for part in parts {
+            match part.to_ascii_lowercase().as_str() {
+                "shift" => modifiers.shift = true,
+                "ctrl" | "control" => modifiers.ctrl = true,
+                "alt" => modifiers.alt = true,
+                "logo" | "super" | "win" => modifiers.logo = true,
+                _ => return Err(format!("Unknown modifier {} in {}", part, text)),
+            }
+        }
+        Ok(Binding { key, modifiers, scale: sign * scale })
+    }
+}
+
+// Writes the binding the way `Binding::parse` reads it
+impl fmt::Display for Binding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.scale < 0.0 {
+            write!(f, "-")?;
+        }
+        let modifiers = [
+            (self.modifiers.shift, "Shift"),
+            (self.modifiers.ctrl, "Ctrl"),
+            (self.modifiers.alt, "Alt"),
+            (self.modifiers.logo, "Logo"),
+        ];
+        for &(held, name) in modifiers.iter() {
+            if held {
+                write!(f, "{}+", name)?;
+            }
+        }
+        write!(f, "{}", key_name(self.key).unwrap_or("Unknown"))?;
+        if self.scale.abs() != 1.0 {
+            write!(f, "*{}", self.scale.abs())?;
+        }
+        Ok(())
+    }
+}
+
+// Which keys drive which actions. Starts out with the bindings in controls.cfg at the root of
+// the repository, and a config file on disk can replace the bindings of any action.
+#[derive(Clone)]
+pub struct InputMap {
+    bindings: HashMap<Action, Vec<Binding>>,
+}
+
+const DEFAULT_BINDINGS: &str = include_str!("../controls.cfg");
+
+impl InputMap {
+    pub fn new() -> Self {
+        let mut map = InputMap { bindings: HashMap::new() };
+        map.parse(DEFAULT_BINDINGS).expect("Invalid default key bindings");
+        map
+    }
+
+    // The default bindings, with the ones in the given file on top. A missing file is not an
+    // error, but a malformed one is.
+    pub fn load(path: &str) -> Result<Self, String> {
+        let mut map = InputMap::new();
+        match std::fs::read_to_string(path) {
+            Ok(text) => map.parse(&text).map_err(|e| format!("{}: {}", path, e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(format!("Failed to read {}: {}", path, e)),
+        }
+        Ok(map)
+    }
+
+    // Read `action = binding, binding` lines, replacing the bindings of every action mentioned
+    pub fn parse(&mut self, text: &str) -> Result<(), String> {
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (name, bindings) = line
+                .split_once('=')
+                .ok_or(format!("line {}: Expected `action = keys`", number + 1))?;
+            let action = Action::from_name(name.trim())
+                .ok_or(format!("line {}: Unknown action {}", number + 1, name.trim()))?;
+            let bindings = bindings
+                .split(',')
+                .filter(|binding| !binding.trim().is_empty())
+                .map(Binding::parse)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| format!("line {}: {}", number + 1, e))?;
+            self.bind(action, bindings);
+        }
+        Ok(())
+    }
+
+    pub fn bind(&mut self, action: Action, bindings: Vec<Binding>) {
+        self.bindings.insert(action, bindings);
+    }
+
+    pub fn bindings(&self, action: Action) -> &[Binding] {
+        self.bindings.get(&action).map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    // Every binding as `action = binding, binding` lines, which `parse` reads back
+    pub fn to_config(&self) -> String {
+        let mut config = String::new();
+        for &action in Action::ALL.iter() {
+            let bindings: Vec<String> = self.bindings(action).iter().map(Binding::to_string).collect();
+            config += format!("{} = {}", action.name(), bindings.join(", ")).trim_end();
+            config += "\n";
+        }
+        config
+    }
+
+    // Whether the binding applies with the given modifiers held: all of its own are, and no
+    // binding of the same key, for any action, asks for more of them. Shift+W then takes over
+    // from W rather than adding to it, and Ctrl+S doesn't also fire S.
+    fn matches(&self, binding: &Binding, held: &Modifiers) -> bool {
+        held.contains(&binding.modifiers)
+            && !self.bindings.values().flatten().any(|other| {
+                other.key == binding.key
+                    && held.contains(&other.modifiers)
+                    && other.modifiers.count() > binding.modifiers.count()
+            })
+    }
+
+    // Sum up the bindings of the action that apply with the modifiers held, weighing each by its
+    // key
+    fn sum(&self, action: Action, input: &InputState, key_value: impl Fn(VirtualKeyCode) -> f32) -> f32 {
+        let modifiers = Modifiers::held(input);
+        self.bindings(action)
+            .iter()
+            .filter(|binding| self.matches(binding, &modifiers))
+            .map(|binding| binding.scale * key_value(binding.key))
+            .sum()
+    }
+
+    // The value of the action with the keys currently down, summed over its bindings
+    pub fn value(&self, action: Action, input: &InputState) -> f32 {
+        let sum = self.sum(action, input, |key| if input.down(key) { 1.0 } else { 0.0 });
+        sum.clamp(-1.0, 1.0)
+    }
+
+    #[allow(dead_code)]
+    pub fn active(&self, action: Action, input: &InputState) -> bool {
+        self.value(action, input) != 0.0
+    }
+
+    // Whether a key bound to the action was pressed this frame
+    pub fn triggered(&self, action: Action, input: &InputState) -> bool {
+        self.sum(action, input, |key| if input.just_pressed(key) { 1.0 } else { 0.0 }) != 0.0
+    }
+
+    // Whether a key bound to the action was released this frame
+    #[allow(dead_code)]
+    pub fn released(&self, action: Action, input: &InputState) -> bool {
+        self.sum(action, input, |key| if input.just_released(key) { 1.0 } else { 0.0 }) != 0.0
+    }
+
+    // How far to step the action this frame: once when a key is pressed, and then at the key
+    // repeat rate while it is held. Unlike `value`, this does not depend on the frame rate.
+    pub fn steps(&self, action: Action, input: &InputState) -> f32 {
+        self.sum(action, input, |key| input.repeats(key) as f32)
+    }
+}
+
+// The keyboard as seen by the render thread. The event loop queues up key events, and the render
+// thread applies them once per frame, so presses and releases are seen exactly once even if both
+// happen between two frames.
+pub struct InputState {
+    pub repeat_delay    : f32, // Seconds before a held key starts repeating
+    pub repeat_interval : f32, // Seconds between repeats after that
+
+    held          : Vec<(VirtualKeyCode, f32)>, // With how long they have been held
+    just_pressed  : Vec<VirtualKeyCode>,
+    just_released : Vec<VirtualKeyCode>,
+    frame_time    : f32,
+}
+
+impl InputState {
+    pub fn new() -> Self {
+        InputState {
+            repeat_delay    : 0.4,
+            repeat_interval : 0.08,
+            held            : vec![],
+            just_pressed    : vec![],
+            just_released   : vec![],
+            frame_time      : 0.0,
+        }
+    }
+
+    // Start a new frame with the key events queued up since the last one
+    pub fn update<I>(&mut self, events: I, delta_time: f32)
+    where
+        I: IntoIterator<Item = (VirtualKeyCode, ElementState)>,
+    {
+        self.just_pressed.clear();
+        self.just_released.clear();
+        for (_, time) in self.held.iter_mut() {
+            *time += delta_time;
+        }
+        self.frame_time = delta_time;
+
+        for (key, state) in events {
+            match state {
+                // The window system repeats presses of held keys, those are not new presses
+                ElementState::Pressed if !self.held(key) => {
+                    self.held.push((key, 0.0));
+                    self.just_pressed.push(key);
+                }
+                ElementState::Released => {
+                    self.held.retain(|&(held_key, _)| held_key != key);
+                    self.just_released.push(key);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    pub fn held(&self, key: VirtualKeyCode) -> bool {
+        self.held.iter().any(|&(held_key, _)| held_key == key)
+    }
+
+    // Held, or pressed and released again this frame
+    pub fn down(&self, key: VirtualKeyCode) -> bool {
+        self.held(key) || self.just_pressed(key)
+    }
+
+    pub fn just_pressed(&self, key: VirtualKeyCode) -> bool {
+        self.just_pressed.contains(&key)
+    }
+
+    pub fn just_released(&self, key: VirtualKeyCode) -> bool {
+        self.just_released.contains(&key)
+    }
+
+    // Seconds the key has been held, or None if it is up
+    pub fn held_time(&self, key: VirtualKeyCode) -> Option<f32> {
+        self.held.iter().find(|&&(held_key, _)| held_key == key).map(|&(_, time)| time)
+    }
+
+    pub fn held_keys(&self) -> Vec<VirtualKeyCode> {
+        self.held.iter().map(|&(key, _)| key).collect()
+    }
+
+    // How many times the key fires this frame: once when pressed, then once per repeat interval
+    // after the repeat delay
+    pub fn repeats(&self, key: VirtualKeyCode) -> u32 {
+        let fired_by = |time: f32| {
+            if time < self.repeat_delay {
+                0
+            } else {
+                ((time - self.repeat_delay) / self.repeat_interval) as u32 + 1
+            }
+        };
+        if self.just_pressed(key) {
+            return 1;
+        }
+        match self.held_time(key) {
+            Some(time) => fired_by(time) - fired_by(time - self.frame_time),
+            None => 0,
+        }
+    }
+}
+
+// Builds the table of key names, so each name only has to be written once
+macro_rules! key_names {
+    ($($key:ident),* $(,)?) => {
+        const KEY_NAMES: &[(&str, VirtualKeyCode)] = &[$((stringify!($key), VirtualKeyCode::$key)),*];
+    };
+}
+
+key_names!(
+    Key1, Key2, Key3, Key4, Key5, Key6, Key7, Key8, Key9, Key0,
+    A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P, Q, R, S, T, U, V, W, X, Y, Z,
+    Escape, F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12,
+    Insert, Home, Delete, End, PageDown, PageUp, Left, Up, Right, Down,
+    Back, Return, Space, Tab,
+    Numpad0, Numpad1, Numpad2, Numpad3, Numpad4, Numpad5, Numpad6, Numpad7, Numpad8, Numpad9,
+    NumpadAdd, NumpadSubtract, NumpadMultiply, NumpadDivide, NumpadDecimal, NumpadEnter,
+    Apostrophe, Backslash, Comma, Equals, Grave, LBracket, Minus, Period, RBracket, Semicolon, Slash,
+    LAlt, LControl, LShift, LWin, RAlt, RControl, RShift, RWin,
+);
+
+// Look up a key by its VirtualKeyCode name, ignoring case. Digits can be written without the
+// Key prefix.
+pub fn key_from_name(name: &str) -> Option<VirtualKeyCode> {
+    let name = if name.len() == 1 && name.as_bytes()[0].is_ascii_digit() {
+        format!("Key{}", name)
+    } else {
+        name.to_string()
+    };
+    KEY_NAMES
+        .iter()
+        .find(|(key_name, _)| key_name.eq_ignore_ascii_case(&name))
+        .map(|&(_, key)| key)
+}
+
+// The name `key_from_name` reads for the key, if it has one
+pub fn key_name(key: VirtualKeyCode) -> Option<&'static str> {
+    KEY_NAMES.iter().find(|&&(_, named_key)| named_key == key).map(|&(name, _)| name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn holding(keys: &[VirtualKeyCode]) -> InputState {
+        let mut input = InputState::new();
+        input.update(keys.iter().map(|&key| (key, ElementState::Pressed)), 0.016);
+        input
+    }
+
+    fn map(config: &str) -> InputMap {
+        let mut map = InputMap::new();
+        map.parse(config).unwrap();
+        map
+    }
+
+    #[test]
+    fn parse_reads_keys_modifiers_signs_and_scales() {
+        let binding = Binding::parse(" -Down*0.5 ").unwrap();
+        assert_eq!(binding.key, VirtualKeyCode::Down);
+        assert_eq!(binding.modifiers, Modifiers::default());
+        assert_eq!(binding.scale, -0.5);
+
+        let binding = Binding::parse("ctrl + Shift+f12").unwrap();
+        assert_eq!(binding.key, VirtualKeyCode::F12);
+        assert_eq!(binding.modifiers, Modifiers { shift: true, ctrl: true, ..Modifiers::default() });
+        assert_eq!(binding.scale, 1.0);
+
+        assert_eq!(Binding::parse("+1").unwrap().key, VirtualKeyCode::Key1);
+        assert!(Binding::parse("Hyper+W").is_err());
+        assert!(Binding::parse("Shift+").is_err());
+        assert!(Binding::parse("W*fast").is_err());
+    }
+
+    #[test]
+    fn parse_reports_the_line_of_an_error() {
+        let mut map = InputMap::new();
+        assert_eq!(map.parse("\n# comment\nmove_forward = W\nfly = Space").unwrap_err(), "line 4: Unknown action fly");
+        assert_eq!(map.parse("zoom Space").unwrap_err(), "line 1: Expected `action = keys`");
+    }
+
+    #[test]
+    fn bindings_read_back_what_they_display() {
+        for text in ["W", "-S", "Shift+W*0.25", "-Ctrl+Alt+Logo+Numpad0*2", "Key1"] {
+            let binding = Binding::parse(text).unwrap();
+            assert_eq!(binding.to_string(), text);
+            assert_eq!(Binding::parse(&binding.to_string()).unwrap(), binding);
+        }
+
+        let defaults = InputMap::new();
+        let mut read_back = InputMap { bindings: HashMap::new() };
+        read_back.parse(&defaults.to_config()).unwrap();
+        for &action in Action::ALL.iter() {
+            assert_eq!(read_back.bindings(action), defaults.bindings(action), "{}", action.name());
+        }
+    }
+
+    #[test]
+    fn value_sums_the_bindings_held_and_clamps_them() {
+        let map = map("move_forward = W, -S, Up*0.5");
+        assert_eq!(map.value(Action::MoveForward, &holding(&[])), 0.0);
+        assert_eq!(map.value(Action::MoveForward, &holding(&[VirtualKeyCode::W])), 1.0);
+        assert_eq!(map.value(Action::MoveForward, &holding(&[VirtualKeyCode::Up])), 0.5);
+        assert_eq!(map.value(Action::MoveForward, &holding(&[VirtualKeyCode::S, VirtualKeyCode::Up])), -0.5);
+        assert_eq!(map.value(Action::MoveForward, &holding(&[VirtualKeyCode::W, VirtualKeyCode::Up])), 1.0);
+    }
+
+    #[test]
+    fn value_uses_the_binding_with_the_most_modifiers_held() {
+        let map = map("move_forward = W, -S, Shift+W*0.25");
+        let shift_w = holding(&[VirtualKeyCode::LShift, VirtualKeyCode::W]);
+        assert_eq!(map.value(Action::MoveForward, &shift_w), 0.25);
+        // Nothing asks for Shift+S, so S still works with Shift held
+        let shift_s = holding(&[VirtualKeyCode::RShift, VirtualKeyCode::S]);
+        assert_eq!(map.value(Action::MoveForward, &shift_s), -1.0);
+        // Nor for Ctrl+W
+        let ctrl_w = holding(&[VirtualKeyCode::LControl, VirtualKeyCode::W]);
+        assert_eq!(map.value(Action::MoveForward, &ctrl_w), 1.0);
+    }
+
+    #[test]
+    fn modifier_bindings_take_over_from_other_actions() {
+        let map = map("move_forward = W, -S\nscreenshot = F12, Ctrl+S\nrecord_frames = Shift+F12");
+        let ctrl_s = holding(&[VirtualKeyCode::LControl, VirtualKeyCode::S]);
+        assert!(map.triggered(Action::Screenshot, &ctrl_s));
+        assert_eq!(map.value(Action::MoveForward, &ctrl_s), 0.0);
+
+        let shift_f12 = holding(&[VirtualKeyCode::LShift, VirtualKeyCode::F12]);
+        assert!(map.triggered(Action::RecordFrames, &shift_f12));
+        assert!(!map.triggered(Action::Screenshot, &shift_f12));
+        assert!(map.triggered(Action::Screenshot, &holding(&[VirtualKeyCode::F12])));
+        assert!(!map.triggered(Action::RecordFrames, &holding(&[VirtualKeyCode::F12])));
+    }
+}
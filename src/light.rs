@@ -0,0 +1,190 @@
+extern crate nalgebra_glm as glm;
+
+use crate::backend::{RenderBackend, Uniform};
+use crate::scene_graph::SceneNode;
+
+// Has to match MAX_LIGHTS in simple.frag
+pub const MAX_LIGHTS: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LightKind {
+    Directional,
+    Point,
+    Spot {
+        inner_angle: f32, // Full intensity inside this angle from the direction (radians)
+        outer_angle: f32, // No light outside this angle from the direction (radians)
+    },
+}
+
+impl LightKind {
+    // The integer identifying the light type in simple.frag
+    fn shader_id(&self) -> i32 {
+        match self {
+            LightKind::Directional => 0,
+            LightKind::Point => 1,
+            LightKind::Spot { .. } => 2,
+        }
+    }
+}
+
+pub struct Light {
+    pub kind          : LightKind,
+    pub position      : glm::Vec3,     // Relative to the node the light is attached to, if any
+    pub direction     : glm::Vec3,     // Relative to the node the light is attached to, if any
+    pub color         : glm::Vec3,
+    pub intensity     : f32,
+    pub attenuation   : glm::Vec3,     // Constant, linear and quadratic falloff with distance
+    pub enabled       : bool,
+    pub casts_shadows : bool,
+    pub shadow_layer  : Option<usize>, // Assigned by the ShadowRenderer every frame
+
+    node: Option<*const SceneNode>, // Who carries me around
+}
+
+impl Light {
+    fn new(kind: LightKind, position: glm::Vec3, direction: glm::Vec3) -> Self {
+        Light {
+            kind,
+            position,
+            direction     : glm::normalize(&direction),
+            color         : glm::vec3(1.0, 1.0, 1.0),
+            intensity     : 1.0,
+            attenuation   : glm::vec3(1.0, 0.0, 0.0),
+            enabled       : true,
+            casts_shadows : false,
+            shadow_layer  : None,
+            node          : None,
+        }
+    }
+
+    pub fn directional(direction: glm::Vec3) -> Self {
+        Light::new(LightKind::Directional, glm::zero(), direction)
+    }
+
+    pub fn point(position: glm::Vec3) -> Self {
+        let mut light = Light::new(LightKind::Point, position, glm::vec3(0.0, -1.0, 0.0));
+        light.attenuation = glm::vec3(1.0, 0.01, 0.001);
+        light
+    }
+
+    pub fn spot(position: glm::Vec3, direction: glm::Vec3, inner_angle: f32, outer_angle: f32) -> Self {
+        let mut light = Light::new(LightKind::Spot { inner_angle, outer_angle }, position, direction);
+        light.attenuation = glm::vec3(1.0, 0.01, 0.001);
+        light
+    }
+
+    pub fn with_color(mut self, color: glm::Vec3, intensity: f32) -> Self {
+        self.color = color;
+        self.intensity = intensity;
+        self
+    }
+
+    pub fn with_attenuation(mut self, constant: f32, linear: f32, quadratic: f32) -> Self {
+        self.attenuation = glm::vec3(constant, linear, quadratic);
+        self
+    }
+
+    // Only directional and spot lights can cast shadows
+    pub fn with_shadows(mut self) -> Self {
+        self.casts_shadows = true;
+        self
+    }
+
+    // Make the light follow a node around. The position and direction are then relative to it.
+    // The node has to outlive the light, which is always the case for the leaked scene nodes.
+    pub fn attach_to(mut self, node: &SceneNode) -> Self {
+        self.node = Some(node as *const SceneNode);
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn detach(&mut self) {
+        self.node = None;
+    }
+
+    // Position in world space, using the transformations computed for the current frame
+    pub fn world_position(&self) -> glm::Vec3 {
+        match self.node {
+            Some(node) => {
+                let matrix = unsafe { (*node).current_transformation_matrix };
+                (matrix * glm::vec4(self.position.x, self.position.y, self.position.z, 1.0)).xyz()
+            }
+            None => self.position,
+        }
+    }
+
+    // Direction in world space, using the transformations computed for the current frame
+    pub fn world_direction(&self) -> glm::Vec3 {
+        match self.node {
+            Some(node) => {
+                let matrix = unsafe { (*node).current_transformation_matrix };
+                let direction = matrix * glm::vec4(self.direction.x, self.direction.y, self.direction.z, 0.0);
+                glm::normalize(&direction.xyz())
+            }
+            None => self.direction,
+        }
+    }
+}
+
+// All the lights in a scene, along with the ambient term shared by every fragment
+pub struct Lighting {
+    pub ambient          : glm::Vec3,
+    pub shininess        : f32,
+    pub specular_strength: f32,
+    pub lights           : Vec<Light>,
+
+    warned_about_count: bool, // Too many lights are only reported once, not every frame
+}
+
+impl Lighting {
+    pub fn new() -> Self {
+        Lighting {
+            ambient          : glm::vec3(0.1, 0.1, 0.1),
+            shininess        : 32.0,
+            specular_strength: 0.5,
+            lights           : vec![],
+            warned_about_count: false,
+        }
+    }
+
+    // Returns the index of the light, which can be used to modify it later on
+    pub fn add(&mut self, light: Light) -> usize {
+        self.lights.push(light);
+        self.lights.len() - 1
+    }
+
+    // Upload the enabled lights to the program in use. Has to be called after the node
+    // transformations are updated, so attached lights end up where their node is.
+    pub fn upload(&mut self, backend: &mut dyn RenderBackend, camera_position: &glm::Vec3) {
+        let enabled: Vec<&Light> = self.lights.iter().filter(|l| l.enabled).collect();
+        if enabled.len() > MAX_LIGHTS && !self.warned_about_count {
+            println!("Only the first {} of {} lights are uploaded", MAX_LIGHTS, enabled.len());
+            self.warned_about_count = true;
+        }
+        let count = enabled.len().min(MAX_LIGHTS);
+
+        backend.set_uniform("lightCount", Uniform::Int(count as i32));
+        backend.set_uniform("ambientColor", Uniform::Vec3(self.ambient));
+        backend.set_uniform("cameraPosition", Uniform::Vec3(*camera_position));
+        backend.set_uniform("shininess", Uniform::Float(self.shininess));
+        backend.set_uniform("specularStrength", Uniform::Float(self.specular_strength));
+
+        for (i, light) in enabled.iter().take(count).enumerate() {
+            let name = |field: &str| format!("lights[{}].{}", i, field);
+            let (inner, outer) = match light.kind {
+                LightKind::Spot { inner_angle, outer_angle } => (inner_angle.cos(), outer_angle.cos()),
+                _ => (-1.0, -1.0),
+            };
+            let shadow_layer = light.shadow_layer.map(|layer| layer as i32).unwrap_or(-1);
+
+            backend.set_uniform(&name("type"), Uniform::Int(light.kind.shader_id()));
+            backend.set_uniform(&name("position"), Uniform::Vec3(light.world_position()));
+            backend.set_uniform(&name("direction"), Uniform::Vec3(light.world_direction()));
+            backend.set_uniform(&name("color"), Uniform::Vec3(light.color * light.intensity));
+            backend.set_uniform(&name("attenuation"), Uniform::Vec3(light.attenuation));
+            backend.set_uniform(&name("innerCutoff"), Uniform::Float(inner));
+            backend.set_uniform(&name("outerCutoff"), Uniform::Float(outer));
+            backend.set_uniform(&name("shadowLayer"), Uniform::Int(shadow_layer));
+        }
+    }
+}
@@ -1,37 +1,82 @@
 extern crate nalgebra_glm as glm;
+use std::rc::Rc;
 use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
-use std::{mem, os::raw::c_void, ptr};
-
+use std::{mem, os::raw::c_void};
+
+mod animation;
+mod backend;
+mod bvh;
+mod camera;
+mod capture;
+mod collision;
+mod flight_model;
+mod flight_path;
+mod formation;
+mod framebuffer;
+mod gamepad;
+mod headless;
+mod input;
+mod light;
 mod mesh;
+#[cfg(test)]
+mod mock_backend;
+mod options;
+mod postprocess;
+mod raster;
+mod recording;
+mod renderer;
 mod scene_graph;
 mod shader;
+mod shadow;
+mod simulation;
+mod steering;
+mod terrain;
 mod toolbox;
+mod tween;
 mod util;
+use animation::{AnimationClip, AnimationPlayer, Interpolation, LoopMode, Property, Track};
+use backend::{GlBackend, RenderBackend};
+use bvh::{Aabb, Bvh};
+use flight_model::{FlightModel, Helicopter, PilotInputs};
+use flight_path::FlightPath;
+use formation::Group;
+use camera::{Camera, CameraInput, ChaseController, OrbitController};
+use collision::{CollisionWorld, ContactEvent};
+use gamepad::{FlightControls, Gamepad, ScriptedSource};
 use glutin::event::{
     DeviceEvent,
-    ElementState::{Pressed, Released},
-    Event, KeyboardInput,
+    ElementState,
+    Event, KeyboardInput, MouseScrollDelta,
     VirtualKeyCode::{self, *},
     WindowEvent,
 };
+use glutin::dpi::PhysicalSize;
 use glutin::event_loop::ControlFlow;
+use glutin::window::Fullscreen;
+use input::{Action, InputMap, InputState};
+use light::{Light, Lighting};
+use options::Options;
+use postprocess::{PostChain, PostEffect};
+use recording::{FrameInput, InputRecorder, InputRecording, RecordingHeader};
+use renderer::Renderer;
 use scene_graph::SceneNode;
+use simulation::{FixedTimestep, SceneSnapshot};
+use steering::Separation;
+use terrain::TerrainIndex;
+use tween::{Easing, Tween, Tweener};
 
 const SCREEN_W: u32 = 1600;
 const SCREEN_H: u32 = 900;
+const MSAA_SAMPLES: i32 = 4;
+const CAPTURE_FPS: f32 = 60.0;
+const PIXELS_PER_LINE: f32 = 40.0;
+const CONTROLS_PATH: &str = "./controls.cfg";
+const PATHS_DIRECTORY: &str = "./paths";
+const FIGURE_EIGHT_CHOPPERS: usize = 5;
+const CHOPPER_CLEARANCE: f32 = 2.0; // The lowest the animated helicopters fly over the terrain
 
 // == // Helper functions to make interacting with OpenGL a little bit prettier. You *WILL* need these! // == //
-// The names should be pretty self explanatory
-fn byte_size_of_array<T>(val: &[T]) -> isize {
-    std::mem::size_of_val(&val[..]) as isize
-}
-
-// Get the OpenGL-compatible pointer to an arbitrary array of numbers
-fn pointer_to_array<T>(val: &[T]) -> *const c_void {
-    &val[0] as *const T as *const c_void
-}
-
 // Get the size of the given type in bytes
 fn size_of<T>() -> i32 {
     mem::size_of::<T>() as i32
@@ -138,217 +183,570 @@ fn sine_function(min: f32, max: f32, resolution: u32) -> (Vec<f32>, Vec<u32>) {
     return (coordinates, indices);
 }
 
-// == // Modify and complete the function below for the first task
-unsafe fn set_up_vao(
-    coordinates: &Vec<f32>,
-    indices: &Vec<u32>,
-    colors: &Vec<f32>,
-    normals: &Vec<f32>,
-) -> u32 {
-    let mut arrayID: u32 = 0;
-    gl::GenVertexArrays(1, &mut arrayID as *mut u32);
-    gl::BindVertexArray(arrayID);
-
-    let mut bufferIDs: u32 = 0;
-    gl::GenBuffers(1, &mut bufferIDs as *mut u32);
-    gl::BindBuffer(gl::ARRAY_BUFFER, bufferIDs);
-
-    gl::BufferData(
-        gl::ARRAY_BUFFER,
-        byte_size_of_array(coordinates),
-        pointer_to_array(coordinates),
-        gl::STATIC_DRAW,
-    );
-    gl::VertexAttribPointer(0, 3, gl::FLOAT, gl::FALSE, 12, ptr::null());
-    gl::EnableVertexAttribArray(0);
-    // Set up color buffer
-    let mut color_bufferIds: u32 = 0;
-    gl::GenBuffers(1, &mut color_bufferIds as *mut u32);
-    gl::BindBuffer(gl::ARRAY_BUFFER, color_bufferIds);
-
-    gl::BufferData(
-        gl::ARRAY_BUFFER,
-        byte_size_of_array(colors),
-        pointer_to_array(colors),
-        gl::STATIC_DRAW,
-    );
+fn build_helicopter(
+    helicopter: &mesh::Helicopter,
+    make_vao: &mut dyn FnMut(&mesh::Mesh) -> u32,
+) -> scene_graph::Node {
+    let mut body_node = SceneNode::from_vao(make_vao(&helicopter.body), helicopter.body.index_count);
+    let mut door_node = SceneNode::from_vao(make_vao(&helicopter.door), helicopter.door.index_count);
+    let mut main_rotor_node =
+        SceneNode::from_vao(make_vao(&helicopter.main_rotor), helicopter.main_rotor.index_count);
+    let mut tail_rotor_node =
+        SceneNode::from_vao(make_vao(&helicopter.tail_rotor), helicopter.tail_rotor.index_count);
+    body_node.reference_point = glm::vec3(0.0, 0.0, 0.0);
+    door_node.reference_point = glm::vec3(1.13, 0.82, 0.0);
+    main_rotor_node.reference_point = glm::vec3(0.0, 2.2, 0.0);
+    tail_rotor_node.reference_point = glm::vec3(0.35, 2.3, 10.4);
+    body_node.add_child(&main_rotor_node);
+    body_node.add_child(&tail_rotor_node);
+    body_node.add_child(&door_node);
+    body_node.position.y = 10.0; // make helicopters fly a bit higher
 
-    gl::VertexAttribPointer(1, 4, gl::FLOAT, gl::FALSE, 16, ptr::null());
-    gl::EnableVertexAttribArray(1);
-    // Set up normal buffer
-    let mut normal_bufferIds: u32 = 0;
-    gl::GenBuffers(2, &mut normal_bufferIds as *mut u32);
-    gl::BindBuffer(gl::ARRAY_BUFFER, normal_bufferIds);
-
-    gl::BufferData(
-        gl::ARRAY_BUFFER,
-        byte_size_of_array(normals),
-        pointer_to_array(normals),
-        gl::STATIC_DRAW,
-    );
+    return body_node;
+}
 
-    gl::VertexAttribPointer(2, 3, gl::FLOAT, gl::FALSE, 12, ptr::null());
-    gl::EnableVertexAttribArray(2);
-    let mut index_bufferIds: u32 = 0;
-    gl::GenBuffers(1, &mut index_bufferIds as *mut u32);
-    gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, index_bufferIds);
-
-    gl::BufferData(
-        gl::ELEMENT_ARRAY_BUFFER,
-        byte_size_of_array(indices),
-        pointer_to_array(indices),
-        gl::STATIC_DRAW,
-    );
-    return arrayID;
+// A box around a helicopter model, big enough to hold the discs its rotors sweep as they turn
+fn helicopter_bounds(helicopter: &mesh::Helicopter) -> Aabb {
+    let points = |mesh: &mesh::Mesh| -> Vec<glm::Vec3> {
+        mesh.vertices.chunks_exact(3).map(|v| glm::vec3(v[0], v[1], v[2])).collect()
+    };
+    let body = Aabb::around(&points(&helicopter.body)).union(&Aabb::around(&points(&helicopter.door)));
+    // The hubs are in the middle of the blades, which spin around y and x
+    let disc = |mesh: &mesh::Mesh, spin_axis: usize| {
+        let blades = points(mesh);
+        let hub = Aabb::around(&blades).center();
+        let radius = blades.iter().fold(0.0f32, |radius, point| {
+            let mut from_hub = point - hub;
+            from_hub[spin_axis] = 0.0;
+            radius.max(glm::length(&from_hub))
+        });
+        let mut reach = glm::vec3(radius, radius, radius);
+        reach[spin_axis] = Aabb::around(&blades).size()[spin_axis] / 2.0;
+        Aabb { min: hub - reach, max: hub + reach }
+    };
+    body.union(&disc(&helicopter.main_rotor, 1)).union(&disc(&helicopter.tail_rotor, 0))
 }
 
-unsafe fn draw_scene(
-    node: &scene_graph::SceneNode,
-    view_projection_matrix: &glm::Mat4,
-    shader: &shader::Shader,
-) {
-    // Check if node is drawable, set uniforms, draw
-    if node.index_count > 0 {
-        gl::BindVertexArray(node.vao_id);
-        let location = shader.get_uniform_location("mvp");
-        gl::UniformMatrix4fv(
-            location,
-            1,
-            gl::FALSE,
-            (view_projection_matrix * node.current_transformation_matrix).as_ptr(),
-        );
-        let location = shader.get_uniform_location("model");
-        gl::UniformMatrix4fv(
-            location,
-            1,
-            gl::FALSE,
-            (node.current_transformation_matrix).as_ptr(),
-        );
-        gl::DrawElements(
-            gl::TRIANGLES,
-            node.index_count,
-            gl::UNSIGNED_INT,
-            ptr::null(),
+// Choppers following one that flies a path, in formation or as a flock
+struct ChopperGroup {
+    leader : usize, // The chopper flying the path
+    first  : usize, // Where the followers start in `Scene::choppers`, one after the other
+    group  : Group,
+}
+
+// Everything the render loop animates and controls
+struct Scene {
+    root_node: scene_graph::Node,
+    terrain: Rc<TerrainIndex>,
+    choppers: Vec<scene_graph::Node>,         // Those flying the paths first, then their followers
+    chopper_animations: Vec<AnimationPlayer>, // One for each chopper
+    chopper_paths: Vec<FlightPath>,           // One for each chopper flying a path
+    chopper_groups: Vec<ChopperGroup>,
+    controllable_helicopter: scene_graph::Node,
+    helicopter: Helicopter, // How the controllable helicopter flies
+    collisions: CollisionWorld, // The choppers in order, then the controllable helicopter
+    separation: Separation,     // Steers the choppers around each other
+    lighting: Lighting,
+    tweens: Tweener,
+    door_open: bool,  // Where the door of the controllable helicopter is headed
+    door_swung: bool,
+}
+
+// Spins the rotors of a helicopter, bound as "main_rotor" and "tail_rotor". The clip is one turn
+// of the main rotor, during which the tail rotor turns twice.
+fn rotor_clip() -> AnimationClip {
+    let turn = std::f32::consts::TAU;
+    let period = turn / 5.0;
+    AnimationClip::new("rotors")
+        .with_track(
+            Track::new("main_rotor", Property::Rotation, Interpolation::Linear)
+                .key(0.0, glm::vec3(0.0, 0.0, 0.0))
+                .key(period, glm::vec3(0.0, turn, 0.0)),
+        )
+        .with_track(
+            Track::new("tail_rotor", Property::Rotation, Interpolation::Linear)
+                .key(0.0, glm::vec3(0.0, 0.0, 0.0))
+                .key(period, glm::vec3(2.0 * turn, 0.0, 0.0)),
+        )
+}
+
+// Load the terrain and helicopter models given in the options and build the scene graph.
+// `make_vao` uploads a mesh and returns the id its nodes are drawn with, e.g.
+// `RenderBackend::create_vao`.
+fn load_scene(options: &Options, make_vao: &mut dyn FnMut(&mesh::Mesh) -> u32) -> Scene {
+    let terrain = mesh::Terrain::load(&options.terrain);
+    // The hierarchy is saved next to the model, so only the first load of a terrain builds it
+    let before = std::time::Instant::now();
+    let bvh = Bvh::load_or_build(&terrain, &format!("{}.bvh", options.terrain));
+    println!("Indexed the terrain in {:.3}ms.", before.elapsed().as_micros() as f32 / 1e3);
+    let helicopter_mesh = mesh::Helicopter::load(&options.helicopter);
+    build_scene(&terrain, bvh, &helicopter_mesh, &options.flight_paths, make_vao)
+}
+
+// Build the scene graph around the given models, with a chopper flying each of the paths, or a
+// few flying the figure eight if there are none
+fn build_scene(
+    terrain: &mesh::Mesh,
+    bvh: Bvh,
+    helicopter_mesh: &mesh::Helicopter,
+    flight_paths: &[FlightPath],
+    make_vao: &mut dyn FnMut(&mesh::Mesh) -> u32,
+) -> Scene {
+    let terrain_index = Rc::new(TerrainIndex::new(bvh));
+
+    let mut root_node = SceneNode::new();
+    root_node.reference_point = glm::vec3(0.0, 0.0, 0.0);
+
+    let mut surface_node = SceneNode::from_vao(make_vao(terrain), terrain.index_count);
+    surface_node.reference_point = glm::vec3(0.0, 0.0, 0.0);
+
+    root_node.add_child(&surface_node);
+    // One chopper for each flight path, or a few spread out along the figure eight
+    let chopper_paths = if flight_paths.is_empty() {
+        let figure_eight = FlightPath::figure_eight();
+        (0..FIGURE_EIGHT_CHOPPERS)
+            .map(|i| {
+                let mut path = figure_eight.clone();
+                path.start = path.length() * i as f32 / FIGURE_EIGHT_CHOPPERS as f32;
+                path
+            })
+            .collect()
+    } else {
+        flight_paths.to_vec()
+    };
+
+    // Paths can have groups of choppers following the one flying them
+    let mut chopper_groups = Vec::new();
+    let mut first = chopper_paths.len();
+    for (leader, path) in chopper_paths.iter().enumerate() {
+        if let Some(settings) = path.group {
+            let group = Group::new(settings, path, 0.0);
+            chopper_groups.push(ChopperGroup { leader, first, group });
+            first += settings.count;
+        }
+    }
+
+    let mut choppers: Vec<scene_graph::Node> = Vec::new();
+    let rotors = Rc::new(rotor_clip());
+    let mut chopper_animations = Vec::new();
+    for _ in 0..first {
+        let mut chopper = build_helicopter(helicopter_mesh, make_vao);
+        root_node.add_child(&chopper);
+
+        let mut animation = AnimationPlayer::new()
+            .with_node("main_rotor", &mut chopper[0])
+            .with_node("tail_rotor", &mut chopper[1])
+            .with_node("door", &mut chopper[2]);
+        animation.play(&rotors, LoopMode::Loop, 0.0);
+        chopper_animations.push(animation);
+        choppers.push(chopper);
+    }
+
+    let mut controllable_helicopter = build_helicopter(helicopter_mesh, make_vao);
+    let helicopter = Helicopter::new(glm::vec3(0.0, 20.0, 0.0), FlightModel::new());
+    helicopter.apply_to(&mut controllable_helicopter);
+    root_node.add_child(&controllable_helicopter);
+
+    let bounds = helicopter_bounds(helicopter_mesh);
+    let mut collisions = CollisionWorld::new();
+    for chopper in choppers.iter().chain(std::iter::once(&controllable_helicopter)) {
+        collisions.add(chopper, bounds);
+    }
+    let separation = Separation::new(chopper_paths.len());
+
+    // The sun replaces the old hard-coded light direction, and the controllable helicopter
+    // carries a searchlight pointing down in front of it
+    let mut lighting = Lighting::new();
+    lighting.add(Light::directional(glm::vec3(0.8, -0.5, 0.6)).with_shadows());
+    lighting.add(
+        Light::spot(glm::vec3(0.0, -0.3, -3.5), glm::vec3(0.0, -1.0, -0.6), 0.2, 0.35)
+            .with_color(glm::vec3(1.0, 0.95, 0.8), 3.0)
+            .with_attenuation(1.0, 0.005, 0.0005)
+            .with_shadows()
+            .attach_to(&controllable_helicopter),
+    );
+    // Only the choppers flying the paths carry lights, as there are only so many to go around
+    for chopper in &choppers[..chopper_paths.len()] {
+        lighting.add(
+            Light::point(glm::vec3(0.0, 0.1, 4.0))
+                .with_color(glm::vec3(1.0, 0.1, 0.1), 1.5)
+                .attach_to(chopper),
         );
     }
 
-    // Recurse
-    for &child in &node.children {
-        draw_scene(&*child, view_projection_matrix, shader);
+    let mut scene = Scene {
+        root_node,
+        terrain: terrain_index,
+        choppers,
+        chopper_animations,
+        chopper_paths,
+        chopper_groups,
+        controllable_helicopter,
+        helicopter,
+        collisions,
+        separation,
+        lighting,
+        tweens: Tweener::new(),
+        door_open: false,
+        door_swung: false,
+    };
+    place_followers(&mut scene);
+    scene
+}
+
+// Move the animated helicopters to where they should be at the given time
+fn animate_scene(scene: &mut Scene, elapsed: f32) {
+    for (i, (chopper, path)) in scene.choppers.iter_mut().zip(&scene.chopper_paths).enumerate() {
+        let pose = path.pose_at_time(elapsed);
+        chopper.position = pose.position + scene.separation.offset(i);
+        chopper.rotation = pose.node_rotation();
+        if let Some(ground) = scene.terrain.height_at(pose.position.x, pose.position.z) {
+            chopper.position.y = chopper.position.y.max(ground + CHOPPER_CLEARANCE);
+        }
+    }
+    for animation in &scene.chopper_animations {
+        animation.apply(elapsed);
     }
 }
 
-unsafe fn update_node_transformations(
-    node: &mut scene_graph::SceneNode,
-    transformation_so_far: &glm::Mat4,
-) {
-    // Construct the correct transformation matrix
-    let mut trans: glm::Mat4 = glm::identity();
-
-    // trans = glm::inverse(transformation_so_far) * trans;
-    trans = glm::translation(&-node.reference_point) * trans; // move to origin
-    trans = glm::scaling(&node.scale) * trans; // scale
-
-    let mut rotation: glm::Mat4 = glm::identity();
-    rotation = glm::rotation(
-        node.rotation.z,
-        &(&rotation * &glm::vec4(0.0, 0.0, 1.0, 1.0)).xyz(),
-    ) * rotation; // rotate around z
-    rotation = glm::rotation(
-        node.rotation.y,
-        &(&rotation * &glm::vec4(0.0, 1.0, 0.0, 1.0)).xyz(),
-    ) * rotation; // rotate around y
-    rotation = glm::rotation(
-        node.rotation.x,
-        &(&rotation * &glm::vec4(1.0, 0.0, 0.0, 1.0)).xyz(),
-    ) * rotation; // rotate around x
-
-    trans = rotation * trans; // apply rotation
-    trans = glm::translation(&node.reference_point) * trans; // move to back to reference point
-    trans = glm::translation(&node.position) * trans; // move to relative location
-
-    // Update the node's transformation matrix
-    node.current_transformation_matrix = transformation_so_far * trans;
-    // Recurse
-    for &child in &node.children {
-        update_node_transformations(&mut *child, &node.current_transformation_matrix);
+// Fly the controllable helicopter one step further
+fn fly_helicopter(scene: &mut Scene, controls: &FlightControls, engine_on: bool, timestep: f32) {
+    let helicopter = &mut scene.helicopter;
+    helicopter.step(&PilotInputs::from_controls(controls, engine_on), timestep);
+    if let Some(ground) = scene.terrain.ground_at(helicopter.position.x, helicopter.position.z) {
+        if let Some(speed) = helicopter.collide_with_ground(&ground, timestep) {
+            println!("Touched down at {:.1} units per second", speed);
+        }
     }
+    helicopter.apply_to(&mut scene.controllable_helicopter);
 }
 
-fn build_helicopter(helicopter: &mesh::Helicopter) -> scene_graph::Node {
-    let body_vao_id: u32;
-    let door_vao_id: u32;
-    let main_rotor_vao_id: u32;
-    let tail_rotor_vao_id: u32;
-    let mut body_node;
-    let mut door_node;
-    let mut main_rotor_node;
-    let mut tail_rotor_node;
-    unsafe {
-        body_vao_id = set_up_vao(
-            &helicopter.body.vertices,
-            &helicopter.body.indices,
-            &helicopter.body.colors,
-            &helicopter.body.normals,
-        );
-        door_vao_id = set_up_vao(
-            &helicopter.door.vertices,
-            &helicopter.door.indices,
-            &helicopter.door.colors,
-            &helicopter.door.normals,
-        );
-        main_rotor_vao_id = set_up_vao(
-            &helicopter.main_rotor.vertices,
-            &helicopter.main_rotor.indices,
-            &helicopter.main_rotor.colors,
-            &helicopter.main_rotor.normals,
-        );
-        tail_rotor_vao_id = set_up_vao(
-            &helicopter.tail_rotor.vertices,
-            &helicopter.tail_rotor.indices,
-            &helicopter.tail_rotor.colors,
-            &helicopter.tail_rotor.normals,
-        );
+// Steer the choppers flying the paths around each other and the controllable helicopter, for
+// the next step
+fn steer_choppers(scene: &mut Scene, avoidance: bool, timestep: f32) {
+    let leaders = &scene.choppers[..scene.chopper_paths.len()];
+    let positions: Vec<glm::Vec3> = leaders.iter().map(|chopper| chopper.position).collect();
+    scene.separation.step(&positions, &[scene.helicopter.position], avoidance, timestep);
+}
+
+// Fly the groups one step after their leaders, which have to be where they are at the given
+// time. The followers keep clear of the other leaders and the controllable helicopter too.
+fn fly_groups(scene: &mut Scene, time: f32, timestep: f32) {
+    let leaders: Vec<glm::Vec3> =
+        scene.choppers[..scene.chopper_paths.len()].iter().map(|chopper| chopper.position).collect();
+    let terrain = &scene.terrain;
+    for chopper_group in &mut scene.chopper_groups {
+        let mut leader = scene.chopper_paths[chopper_group.leader].pose_at_time(time);
+        leader.position = leaders[chopper_group.leader];
+        let mut obstacles: Vec<glm::Vec3> = leaders
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| i != chopper_group.leader)
+            .map(|(_, position)| *position)
+            .collect();
+        obstacles.push(scene.helicopter.position);
+
+        let group = &mut chopper_group.group;
+        group.step(&leader, &obstacles, timestep);
+        group.keep_above(|x, z| terrain.height_at(x, z).map(|ground| ground + CHOPPER_CLEARANCE));
     }
-    body_node = SceneNode::from_vao(body_vao_id, helicopter.body.index_count);
-    door_node = SceneNode::from_vao(door_vao_id, helicopter.door.index_count);
-    main_rotor_node = SceneNode::from_vao(main_rotor_vao_id, helicopter.main_rotor.index_count);
-    tail_rotor_node = SceneNode::from_vao(tail_rotor_vao_id, helicopter.tail_rotor.index_count);
+    place_followers(scene);
+}
 
-    body_node.reference_point = glm::vec3(0.0, 0.0, 0.0);
-    door_node.reference_point = glm::vec3(1.13, 0.82, 0.0);
-    main_rotor_node.reference_point = glm::vec3(0.0, 2.2, 0.0);
-    tail_rotor_node.reference_point = glm::vec3(0.35, 2.3, 10.4);
-    body_node.add_child(&main_rotor_node);
-    body_node.add_child(&tail_rotor_node);
-    body_node.add_child(&door_node);
-    body_node.position.y = 10.0; // make helicopters fly a bit higher
+// Move the nodes of the followers to where their groups have them
+fn place_followers(scene: &mut Scene) {
+    for chopper_group in &scene.chopper_groups {
+        for i in 0..chopper_group.group.len() {
+            let pose = chopper_group.group.pose(i);
+            let chopper = &mut scene.choppers[chopper_group.first + i];
+            chopper.position = pose.position;
+            chopper.rotation = pose.node_rotation();
+        }
+    }
+}
 
-    return body_node;
+// Report helicopters running into each other, and push the controllable one back out of any
+// it flew into
+fn detect_collisions(scene: &mut Scene) {
+    let player = scene.choppers.len();
+    let name = |index: usize| {
+        if index == player {
+            "the controllable helicopter".to_string()
+        } else {
+            format!("chopper {}", index + 1)
+        }
+    };
+    for event in scene.collisions.update() {
+        match event {
+            ContactEvent::Began(contact) => {
+                println!("Collision between {} and {}", name(contact.first), name(contact.second))
+            }
+            ContactEvent::Ended(first, second) => println!("{} and {} are clear", name(first), name(second)),
+        }
+    }
+    for contact in scene.collisions.contacts() {
+        if contact.second == player {
+            scene.helicopter.push_out(&contact.normal, contact.depth);
+            scene.helicopter.apply_to(&mut scene.controllable_helicopter);
+        }
+    }
+}
+
+// What the player does to the scene, held for a simulation step
+#[derive(Clone, Copy, Debug, Default)]
+struct Controls {
+    flight     : FlightControls,
+    engine_on  : bool,
+    door_open  : bool,
+    door_swung : bool,
+    avoidance  : bool, // Whether the choppers steer around each other
+}
+
+// Advance the scene by one fixed step to the given time. The gamepad, if there is one, flies the
+// helicopter on top of the given controls.
+fn update(scene: &mut Scene, gamepad: &mut Option<Gamepad>, controls: &Controls, time: f32, timestep: f32) {
+    let mut flight = controls.flight;
+    if let Some(gamepad) = gamepad {
+        gamepad.update(time);
+        flight = flight.combine(&gamepad.flight_controls());
+    }
+    animate_scene(scene, time);
+    fly_groups(scene, time, timestep);
+    fly_helicopter(scene, &flight, controls.engine_on, timestep);
+    steer_choppers(scene, controls.avoidance, timestep);
+    detect_collisions(scene);
+    move_door(scene, controls, time);
+    scene.tweens.update(time);
+}
+
+// Slide the door of the controllable helicopter open or shut, and swing it out or back in, when
+// the controls ask for it
+fn move_door(scene: &mut Scene, controls: &Controls, time: f32) {
+    let door = &mut scene.controllable_helicopter[2];
+    if controls.door_open != scene.door_open {
+        scene.door_open = controls.door_open;
+        scene.tweens.stop(door);
+        let tween = if controls.door_open {
+            Tween::new(door, Property::Position, glm::vec3(0.0, 0.0, 2.0))
+                .over(1.2, Easing::CubicInOut)
+                .on_complete(|| println!("Door open"))
+        } else {
+            // Swing the door back in before it slides shut
+            scene.door_swung = false;
+            Tween::new(door, Property::Rotation, glm::zero()).over(0.4, Easing::QuadOut).then(
+                Tween::new(door, Property::Position, glm::zero())
+                    .over(1.0, Easing::BounceOut)
+                    .on_complete(|| println!("Door closed")),
+            )
+        };
+        scene.tweens.start(tween, time);
+    }
+    if controls.door_swung != scene.door_swung {
+        scene.door_swung = controls.door_swung;
+        let tween = if controls.door_swung {
+            Tween::new(door, Property::Rotation, glm::vec3(0.0, 0.0, 2.0)).over(1.5, Easing::ElasticOut)
+        } else {
+            Tween::new(door, Property::Rotation, glm::zero()).over(1.0, Easing::BounceOut)
+        };
+        scene.tweens.start(tween, time);
+    }
+}
+
+// The camera can fly freely, orbit or chase the controllable helicopter
+fn create_camera(scene: &Scene) -> Camera {
+    Camera::new()
+        .with_controller(OrbitController::new(&scene.controllable_helicopter))
+        .with_controller(ChaseController::new(&scene.controllable_helicopter).with_terrain(&scene.terrain))
+}
+
+// The keyboard and mouse as the player uses them, carried from frame to frame. What comes out
+// only depends on the frames put in, so a recorded session plays back the same way.
+struct Player {
+    input: InputState,
+    input_map: InputMap,
+    cursor_grabbed: bool, // Mouse look only turns the camera while the cursor is grabbed
+    engine_on: bool,      // Toggled by a press, not held, like the door
+    avoidance: bool,
+    door_open: bool,
+    door_swung: bool,
+}
+
+impl Player {
+    fn new(input_map: InputMap) -> Self {
+        Player {
+            input: InputState::new(),
+            input_map,
+            cursor_grabbed: false,
+            engine_on: true,
+            avoidance: false,
+            door_open: false,
+            door_swung: false,
+        }
+    }
+
+    // Whether a key bound to the action was pressed this frame
+    fn triggered(&self, action: Action) -> bool {
+        self.input_map.triggered(action, &self.input)
+    }
+
+    // Take in a frame of input and handle the camera keys. Returns how to move the camera this
+    // frame, and the controls to hold through its simulation steps.
+    fn update(&mut self, frame: &FrameInput, camera: &mut Camera) -> (CameraInput, Controls) {
+        self.input.update(frame.keys.iter().copied(), frame.delta_time);
+        let (input_map, input) = (&self.input_map, &self.input);
+        let value = |action| input_map.value(action, input);
+
+        let mut camera_input = CameraInput {
+            movement: glm::vec3(value(Action::MoveRight), value(Action::MoveUp), value(Action::MoveForward)),
+            look: glm::vec2(value(Action::TurnRight), value(Action::LookUp)),
+            turn: glm::zero(),
+            zoom: input_map.steps(Action::Zoom, input) + frame.scroll,
+        };
+        for (i, action) in [Action::CameraFreeFly, Action::CameraOrbit, Action::CameraChase].iter().enumerate() {
+            if self.triggered(*action) {
+                camera.select(i);
+            }
+        }
+
+        if self.triggered(Action::GrabCursor) {
+            self.cursor_grabbed = !self.cursor_grabbed;
+        }
+        if self.triggered(Action::MouseSensitivityDown) || self.triggered(Action::MouseSensitivityUp) {
+            let factor = if self.triggered(Action::MouseSensitivityDown) { 0.8 } else { 1.25 };
+            camera.mouse_look.sensitivity *= factor;
+            println!("Mouse sensitivity: {:.4}", camera.mouse_look.sensitivity);
+        }
+        if self.triggered(Action::InvertMouse) {
+            camera.mouse_look.invert_y = !camera.mouse_look.invert_y;
+            println!("Inverted mouse: {}", if camera.mouse_look.invert_y { "on" } else { "off" });
+        }
+        if self.cursor_grabbed {
+            camera_input.turn = camera.mouse_look.turn(frame.mouse_delta);
+        }
+
+        if self.triggered(Action::HelicopterEngine) {
+            self.engine_on = !self.engine_on;
+            println!("Engine: {}", if self.engine_on { "on" } else { "off" });
+        }
+        if self.triggered(Action::ChopperAvoidance) {
+            self.avoidance = !self.avoidance;
+            println!("Chopper avoidance: {}", if self.avoidance { "on" } else { "off" });
+        }
+        // Closing the door swings it back in as well
+        let slide = input_map.steps(Action::DoorSlide, input);
+        if slide != 0.0 {
+            self.door_open = slide > 0.0;
+            self.door_swung &= self.door_open;
+        }
+        let swing = input_map.steps(Action::DoorSwing, input);
+        if swing != 0.0 {
+            self.door_swung = swing > 0.0;
+        }
+
+        let controls = Controls {
+            flight: FlightControls {
+                pitch: value(Action::HelicopterPitch),
+                roll: value(Action::HelicopterRoll),
+                yaw: value(Action::HelicopterYaw),
+                throttle: value(Action::HelicopterThrottle),
+            },
+            engine_on: self.engine_on,
+            door_open: self.door_open,
+            door_swung: self.door_swung,
+            avoidance: self.avoidance,
+        };
+        (camera_input, controls)
+    }
+}
+
+// Toggle the post processing passes once per key press
+fn toggle_post_effects(player: &Player, post_chain: &mut PostChain) {
+    let toggles = [
+        (Action::ToggleTonemap, PostEffect::Tonemap),
+        (Action::ToggleGamma, PostEffect::Gamma),
+        (Action::ToggleFxaa, PostEffect::Fxaa),
+        (Action::ToggleVignette, PostEffect::Vignette),
+    ];
+    for &(action, effect) in toggles.iter() {
+        if player.triggered(action) {
+            let enabled = post_chain.toggle(effect);
+            println!("Post processing {}: {}", effect.name(), if enabled { "on" } else { "off" });
+        }
+    }
 }
+
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let mut options = match Options::from_args(&args) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            print!("{}", options::USAGE);
+            return;
+        }
+        Err(e) => {
+            eprintln!("{}\nRun with --help to see the available options.", e);
+            std::process::exit(2);
+        }
+    };
+
+    // A replay starts out the way the recording did, whatever the command line says
+    let recording = match &options.replay {
+        Some(path) => match InputRecording::load(path).and_then(|recording| {
+            recording.check_paths(&options.flight_paths).map_err(|e| format!("{}: {}", path, e))?;
+            Ok(recording)
+        }) {
+            Ok(recording) => Some(recording),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    if let Some(recording) = &recording {
+        options.terrain = recording.header.terrain.clone();
+        options.helicopter = recording.header.helicopter.clone();
+        options.gamepad_curve = recording.header.gamepad_curve;
+    }
+
+    // Render a few frames offscreen and exit, without ever opening a window
+    if let Some(headless_options) = &options.headless {
+        if let Err(e) = headless::run(&options, headless_options, recording) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     // Set up the necessary objects to deal with windows and event handling
     let el = glutin::event_loop::EventLoop::new();
     let wb = glutin::window::WindowBuilder::new()
         .with_title("Gloom-rs")
-        .with_resizable(false)
-        .with_inner_size(glutin::dpi::LogicalSize::new(SCREEN_W, SCREEN_H));
-    let cb = glutin::ContextBuilder::new().with_vsync(true);
+        .with_resizable(true)
+        .with_inner_size(glutin::dpi::LogicalSize::new(options.width, options.height))
+        .with_fullscreen(if options.fullscreen { Some(Fullscreen::Borderless(None)) } else { None });
+    let cb = glutin::ContextBuilder::new().with_vsync(options.vsync);
     let windowed_context = cb.build_windowed(wb, &el).unwrap();
-    // Uncomment these if you want to use the mouse for controls, but want it to be confined to the screen and/or invisible.
-    // windowed_context.window().set_cursor_grab(true).expect("failed to grab cursor");
-    // windowed_context.window().set_cursor_visible(false);
 
-    // Set up a shared vector for keeping track of currently pressed keys
-    let arc_pressed_keys = Arc::new(Mutex::new(Vec::<VirtualKeyCode>::with_capacity(10)));
-    // Make a reference of this vector to send to the render thread
-    let pressed_keys = Arc::clone(&arc_pressed_keys);
+    // Set up a shared queue of key presses and releases, emptied by the render thread every frame
+    let arc_key_events = Arc::new(Mutex::new(Vec::<(VirtualKeyCode, ElementState)>::with_capacity(10)));
+    // Make a reference of this queue to send to the render thread
+    let key_events = Arc::clone(&arc_key_events);
 
     // Set up shared tuple for tracking mouse movement between frames
     let arc_mouse_delta = Arc::new(Mutex::new((0f32, 0f32)));
     // Make a reference of this tuple to send to the render thread
     let mouse_delta = Arc::clone(&arc_mouse_delta);
 
+    // Set up a shared value for the latest window size in physical pixels, taken by the render
+    // thread when it changes
+    let arc_window_size = Arc::new(Mutex::new(None::<PhysicalSize<u32>>));
+    let window_size = Arc::clone(&arc_window_size);
+
+    // Set up a shared value for the mouse wheel, in lines scrolled since last frame
+    let arc_mouse_scroll = Arc::new(Mutex::new(0f32));
+    let mouse_scroll = Arc::clone(&arc_mouse_scroll);
+
     // Spawn a separate thread for rendering, so event handling doesn't block rendering
     let render_thread = thread::spawn(move || {
         // Acquire the OpenGL Context and load the function pointers. This has to be done inside of the rendering thread, because
@@ -359,215 +757,189 @@ fn main() {
             c
         };
 
-        // Set up openGL
-        unsafe {
-            gl::Enable(gl::DEPTH_TEST);
-            gl::DepthFunc(gl::LESS);
-            gl::Enable(gl::CULL_FACE);
-            gl::Disable(gl::MULTISAMPLE);
-            gl::Enable(gl::BLEND);
-            gl::BlendFunc(gl::SRC_ALPHA, gl::ONE_MINUS_SRC_ALPHA);
-            gl::Enable(gl::DEBUG_OUTPUT_SYNCHRONOUS);
-            gl::DebugMessageCallback(Some(util::debug_callback), ptr::null());
-
-            // Print some diagnostics
-            println!(
-                "{}: {}",
-                util::get_gl_string(gl::VENDOR),
-                util::get_gl_string(gl::RENDERER)
-            );
-            println!("OpenGL\t: {}", util::get_gl_string(gl::VERSION));
-            println!(
-                "GLSL\t: {}",
-                util::get_gl_string(gl::SHADING_LANGUAGE_VERSION)
-            );
-        }
-
-        // Use ShaderBuilder to load and link shaders
-        let shader_pair;
-        unsafe {
-            shader_pair = shader::ShaderBuilder::new()
-                .attach_file("./shaders/simple.vert")
-                .attach_file("./shaders/simple.frag")
-                .link();
-            shader_pair.activate(); // activate shaders
-        }
-        // == // Set up your VAO here
-        let surface_vao_id: u32;
-
-        let mut surface_node;
-        let terrain = mesh::Terrain::load("./resources/lunarsurface.obj");
-        let helicopter_mesh = mesh::Helicopter::load("./resources/helicopter.obj");
-
-        let mut root_node = SceneNode::new();
-        root_node.reference_point = glm::vec3(0.0, 0.0, 0.0);
-
-        unsafe {
-            // set up the vertex array objects
-            surface_vao_id = set_up_vao(
-                &terrain.vertices,
-                &terrain.indices,
-                &terrain.colors,
-                &terrain.normals,
-            );
+        let debug_sink = unsafe { renderer::init_gl(options.debug_sink()) };
+
+        // The targets are sized in physical pixels, which inner_size() already is, so they stay
+        // sharp on screens with more than one physical pixel per logical one
+        let size = context.window().inner_size();
+        let (width, height) = (size.width as i32, size.height as i32);
+        let mut renderer = Renderer::new(unsafe { GlBackend::new() }, width, height, options.samples, &options.shader_directory);
+        renderer.post_chain.settings = options.post_settings;
+        let mut scene = load_scene(&options, &mut |mesh| renderer.backend.create_vao(mesh));
+
+        let mut last_frame_time = std::time::Instant::now();
+
+        // Take a screenshot, or start or stop dumping every frame
+        let mut take_screenshot = false;
+        let mut frame_sequence: Option<capture::FrameSequence> = None;
+
+        // Key bindings from the controls file, falling back to the defaults. A replay brings its
+        // own.
+        let input_map = match &recording {
+            Some(recording) => recording.header.input_map.clone(),
+            None => InputMap::load(&options.controls).unwrap_or_else(|e| {
+                println!("Using the default key bindings. {}", e);
+                InputMap::new()
+            }),
+        };
+        let mut player = Player::new(input_map);
+        let mut camera = create_camera(&scene);
+
+        // Gamepads are only read when built with the gamepad feature. A replay plays back the
+        // recorded gamepad events instead.
+        let mut gamepad = match &recording {
+            Some(recording) => Some(Gamepad::new(Box::new(ScriptedSource::new(recording.gamepad_events())))),
+            None => Gamepad::connect(),
+        };
+        if let Some(gamepad) = &mut gamepad {
+            gamepad.curve = options.gamepad_curve;
         }
 
-        surface_node = SceneNode::from_vao(surface_vao_id, terrain.index_count);
-        surface_node.reference_point = glm::vec3(0.0, 0.0, 0.0);
-
-        root_node.add_child(&surface_node);
-        let mut choppers: Vec<scene_graph::Node> = Vec::new();
-        for _i in 0..5 {
-            let chopper = build_helicopter(&helicopter_mesh);
-            root_node.add_child(&chopper);
-
-            choppers.push(chopper);
+        // The scene is simulated in fixed steps, and drawn between the last two of them
+        let timestep_length = recording.as_ref().map_or(simulation::TIMESTEP, |recording| recording.header.timestep);
+        let mut timestep = FixedTimestep::new(timestep_length);
+        animate_scene(&mut scene, 0.0);
+        let mut previous_step = SceneSnapshot::capture(&scene.root_node);
+
+        // Write down every frame of input, or take them from a recording until it runs out
+        let mut recorder = None;
+        if let Some(path) = &options.record {
+            let header = RecordingHeader {
+                timestep: timestep.timestep,
+                terrain: options.terrain.clone(),
+                helicopter: options.helicopter.clone(),
+                paths: flight_path::fingerprint(&options.flight_paths),
+                input_map: player.input_map.clone(),
+                gamepad_curve: options.gamepad_curve,
+            };
+            match InputRecorder::create(path, &header) {
+                Ok(created) => {
+                    println!("Recording input to {}", path);
+                    recorder = Some(created);
+                    if let Some(gamepad) = &mut gamepad {
+                        gamepad.record = true;
+                    }
+                }
+                Err(e) => println!("{}", e),
+            }
         }
-
-        let mut controllable_helicopter = build_helicopter(&helicopter_mesh);
-        root_node.add_child(&controllable_helicopter);
-
-        let first_frame_time = std::time::Instant::now();
-        let mut last_frame_time = first_frame_time;
-
-        let mut x: f32 = 0.0;
-        let mut y: f32 = 0.0;
-        let mut z: f32 = 0.0;
-        let mut pitch: f32 = 0.0;
-        let mut yaw: f32 = 0.0;
-
-        let mut speed = 70.0;
+        let mut replay = recording.map(|recording| recording.frames.into_iter());
 
         // The main rendering loop
         loop {
             let now = std::time::Instant::now();
-            let elapsed = now.duration_since(first_frame_time).as_secs_f32();
-            let delta_time = now.duration_since(last_frame_time).as_secs_f32();
+            // While dumping frames, time advances by a fixed step no matter how long a frame takes
+            let delta_time = match &frame_sequence {
+                Some(sequence) => sequence.timestep,
+                None => now.duration_since(last_frame_time).as_secs_f32(),
+            };
             last_frame_time = now;
 
-            // Handle keyboard input
-            if let Ok(keys) = pressed_keys.lock() {
-                for key in keys.iter() {
-                    match key {
-                        VirtualKeyCode::A => {
-                            z += speed * delta_time * yaw.sin();
-                            x += speed * delta_time * yaw.cos();
-                        }
-                        VirtualKeyCode::D => {
-                            z -= speed * delta_time * yaw.sin();
-                            x -= speed * delta_time * yaw.cos();
-                        }
-                        VirtualKeyCode::W => {
-                            z += speed * delta_time * yaw.cos() * pitch.cos();
-                            x += speed * delta_time * -yaw.sin() * pitch.cos();
-                            y += speed * delta_time * pitch.sin();
-                        }
-                        VirtualKeyCode::S => {
-                            z -= speed * delta_time * yaw.cos();
-                            x -= speed * delta_time * -yaw.sin();
-                            y -= speed * delta_time * pitch.sin();
-                        }
-                        VirtualKeyCode::Q => {
-                            y += speed * delta_time;
-                        }
-                        VirtualKeyCode::E => {
-                            y -= speed * delta_time;
-                        }
-                        VirtualKeyCode::Up => {
-                            if pitch > -1.5 {
-                                pitch -= 1.5 * delta_time;
-                            }
-                        }
-                        VirtualKeyCode::Down => {
-                            if pitch < 1.5 {
-                                pitch += 1.5 * delta_time;
-                            }
-                        }
-                        VirtualKeyCode::Left => {
-                            yaw -= 1.5 * delta_time;
-                        }
-                        VirtualKeyCode::Right => {
-                            yaw += 1.5 * delta_time;
-                        }
-                        VirtualKeyCode::I => controllable_helicopter.rotation.x -= 1.5 * delta_time,
-                        VirtualKeyCode::K => controllable_helicopter.rotation.x += 1.5 * delta_time,
-                        VirtualKeyCode::J => controllable_helicopter.rotation.y += 1.5 * delta_time,
-                        VirtualKeyCode::L => controllable_helicopter.rotation.y -= 1.5 * delta_time,
-
-                        VirtualKeyCode::O => {
-                            if controllable_helicopter[2].position.z + delta_time <= 2.0 {
-                                controllable_helicopter[2].position.z += delta_time;
-                            }
-                        }
-                        VirtualKeyCode::P => {
-                            if controllable_helicopter[2].position.z - delta_time >= 0.0 {
-                                controllable_helicopter[2].position.z -= delta_time;
-                            }
-                        }
-
-                        VirtualKeyCode::R => {
-                            if controllable_helicopter[2].rotation.z + delta_time <= 2.0 {
-                                controllable_helicopter[2].rotation.z += delta_time;
-                            }
-                        }
-                        VirtualKeyCode::T => {
-                            if controllable_helicopter[2].rotation.z - delta_time >= 0.0 {
-                                controllable_helicopter[2].rotation.z -= delta_time;
-                            }
-                        }
-                        VirtualKeyCode::Space => {
-                            speed += 20.0;
-                        }
-                        VirtualKeyCode::LControl => {
-                            speed -= if speed > 20.0 { 20.0 } else { 0.0 };
-                        }
-
-                        _ => {}
-                    }
+            // Follow the window size. A minimized window is zero pixels big, so keep the old size
+            // until it comes back.
+            if let Ok(mut size) = window_size.lock() {
+                if let Some(size) = size.take().filter(|size| size.width > 0 && size.height > 0) {
+                    context.resize(size);
+                    renderer.resize(size.width as i32, size.height as i32);
                 }
             }
-            // Handle mouse movement. delta contains the x and y movement of the mouse since last frame in pixels
+
+            // Collect the keys pressed and released and the mouse movement since the last frame
+            let mut live_input = FrameInput { delta_time, ..FrameInput::default() };
+            if let Ok(mut events) = key_events.lock() {
+                live_input.keys = events.drain(..).collect();
+            }
             if let Ok(mut delta) = mouse_delta.lock() {
-                *delta = (0.0, 0.0);
+                live_input.mouse_delta = std::mem::take(&mut *delta);
             }
-
-            unsafe {
-                gl::ClearColor(0.6, 0.71372549, 0.94901961, 0.7);
-                gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
-
-                let translation: glm::Mat4 = glm::translation(&glm::vec3(x, y, z - 5.0));
-                let perspective: glm::Mat4 =
-                    glm::perspective(SCREEN_W as f32 / SCREEN_H as f32, 1.0, 1.0, 1000.0);
-
-                let mut rotation: glm::Mat4 = glm::identity();
-
-                rotation =
-                    glm::rotation(pitch, &(&rotation * &glm::vec4(1.0, 0.0, 0.0, 1.0)).xyz())
-                        * rotation;
-                rotation = glm::rotation(yaw, &(&rotation * &glm::vec4(0.0, 1.0, 0.0, 1.0)).xyz())
-                    * rotation;
-
-                let matrix: glm::Mat4 = perspective * rotation * translation;
-
-                for i in 0..5 {
-                    let path = toolbox::simple_heading_animation(elapsed - 600.0 * i as f32);
-                    choppers[i].position.x = path.x;
-                    choppers[i].position.z = path.z;
-                    choppers[i].rotation.x = path.pitch;
-                    choppers[i].rotation.y = path.yaw;
-                    choppers[i].rotation.z = path.roll;
-                    choppers[i][0].rotation.y = 5.0 * elapsed; // rotate main rotor
-                    choppers[i][1].rotation.x = 10.0 * elapsed; // rotate tail rotor
+            if let Ok(mut scroll) = mouse_scroll.lock() {
+                live_input.scroll = std::mem::take(&mut *scroll);
+            }
+            let mut frame = match replay.as_mut().map(Iterator::next) {
+                Some(Some(frame)) => frame,
+                Some(None) => {
+                    println!("Replay finished, over to you");
+                    replay = None;
+                    // Let go of the keys still held at the end of the recording
+                    let mut keys: Vec<_> = player.input.held_keys().into_iter().map(|key| (key, ElementState::Released)).collect();
+                    keys.append(&mut live_input.keys);
+                    FrameInput { keys, ..live_input }
+                }
+                None => live_input,
+            };
+
+            let cursor_was_grabbed = player.cursor_grabbed;
+            let (camera_input, controls) = player.update(&frame, &mut camera);
+            if player.cursor_grabbed != cursor_was_grabbed {
+                let window = context.window();
+                if let Err(e) = window.set_cursor_grab(player.cursor_grabbed) {
+                    println!("Failed to grab the cursor: {}", e);
                 }
+                window.set_cursor_visible(!player.cursor_grabbed);
+            }
 
-                controllable_helicopter.position.y = 20.0;
+            toggle_post_effects(&player, &mut renderer.post_chain);
+            if player.triggered(Action::RecordFrames) {
+                frame_sequence = match frame_sequence.take() {
+                    Some(sequence) => {
+                        println!("Stopped capturing after {} frames", sequence.frame);
+                        None
+                    }
+                    None => {
+                        println!("Capturing frames to ./frames/");
+                        Some(capture::FrameSequence::new("./frames", CAPTURE_FPS))
+                    }
+                };
+            }
+            if player.triggered(Action::Screenshot) {
+                take_screenshot = true;
+            }
 
-                // Draw elements
-                update_node_transformations(&mut root_node, &glm::identity());
-                draw_scene(&root_node, &matrix, &shader_pair);
+            // Catch the simulation up with the time that has passed
+            timestep.accumulate(frame.delta_time);
+            while timestep.step() {
+                previous_step = SceneSnapshot::capture(&scene.root_node);
+                update(&mut scene, &mut gamepad, &controls, timestep.time(), timestep.timestep);
+            }
+
+            if let Some(active) = &mut recorder {
+                if let Some(gamepad) = &mut gamepad {
+                    frame.gamepad = gamepad.take_recorded();
+                }
+                if let Err(e) = active.record(&frame) {
+                    println!("Stopped recording after {} frames: {}", active.frames, e);
+                    recorder = None;
+                }
+            }
+
+            unsafe {
+                let current_step = simulation::interpolate_scene(&mut scene.root_node, &previous_step, timestep.alpha());
+                // Place the nodes once per frame, for both the orbit and chase cameras and the renderer
+                renderer::update_node_transformations(&mut scene.root_node, &glm::identity());
+                camera.update(&camera_input, frame.delta_time);
+
+                renderer.render(&scene.root_node, &mut scene.lighting, &camera, None);
+                current_step.apply(&mut scene.root_node);
+
+                // Read back the finished frame before it is swapped away
+                if take_screenshot {
+                    take_screenshot = false;
+                    let path = capture::screenshot_path("./screenshots");
+                    match capture::save_png(&mut renderer.backend, 0, renderer.width as u32, renderer.height as u32, &path) {
+                        Ok(()) => println!("Saved screenshot to {}", path.display()),
+                        Err(e) => println!("Failed to save screenshot: {}", e),
+                    }
+                }
+                if let Some(sequence) = &mut frame_sequence {
+                    if let Err(e) = sequence.capture(&mut renderer.backend, 0, renderer.width as u32, renderer.height as u32) {
+                        println!("Failed to save frame {}: {}", sequence.frame, e);
+                        frame_sequence = None;
+                    }
+                }
             }
 
             context.swap_buffers().unwrap();
+            debug_sink.check();
         }
     });
 
@@ -601,7 +973,25 @@ fn main() {
             } => {
                 *control_flow = ControlFlow::Exit;
             }
-            // Keep track of currently pressed keys to send to the rendering thread
+            // Let the rendering thread know about new window sizes, which also change when the
+            // window moves to a screen with another scale factor
+            Event::WindowEvent {
+                event: WindowEvent::Resized(size),
+                ..
+            } => {
+                if let Ok(mut window_size) = arc_window_size.lock() {
+                    *window_size = Some(size);
+                }
+            }
+            Event::WindowEvent {
+                event: WindowEvent::ScaleFactorChanged { new_inner_size, .. },
+                ..
+            } => {
+                if let Ok(mut window_size) = arc_window_size.lock() {
+                    *window_size = Some(*new_inner_size);
+                }
+            }
+            // Queue up key presses and releases for the rendering thread
             Event::WindowEvent {
                 event:
                     WindowEvent::KeyboardInput {
@@ -615,20 +1005,8 @@ fn main() {
                     },
                 ..
             } => {
-                if let Ok(mut keys) = arc_pressed_keys.lock() {
-                    match key_state {
-                        Released => {
-                            if keys.contains(&keycode) {
-                                let i = keys.iter().position(|&k| k == keycode).unwrap();
-                                keys.remove(i);
-                            }
-                        }
-                        Pressed => {
-                            if !keys.contains(&keycode) {
-                                keys.push(keycode);
-                            }
-                        }
-                    }
+                if let Ok(mut events) = arc_key_events.lock() {
+                    events.push((keycode, key_state));
                 }
 
                 // Handle escape separately
@@ -648,6 +1026,19 @@ fn main() {
                     *position = (position.0 + delta.0 as f32, position.1 + delta.1 as f32);
                 }
             }
+            Event::WindowEvent {
+                event: WindowEvent::MouseWheel { delta, .. },
+                ..
+            } => {
+                // Accumulate scrolling, with touchpads scrolling in pixels rather than lines
+                let lines = match delta {
+                    MouseScrollDelta::LineDelta(_, y) => y,
+                    MouseScrollDelta::PixelDelta(position) => position.y as f32 / PIXELS_PER_LINE,
+                };
+                if let Ok(mut scroll) = arc_mouse_scroll.lock() {
+                    *scroll += lines;
+                }
+            }
             _ => {}
         }
     });
@@ -5,6 +5,7 @@ fn generate_color_vec(color: [f32; 4], num: usize) -> Vec<f32> {
     color.iter().cloned().cycle().take(num*4).collect()
 }
 
+#[derive(Clone)]
 pub struct Mesh {
     pub vertices: Vec<f32>,
     pub normals: Vec<f32>,
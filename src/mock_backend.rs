@@ -0,0 +1,228 @@
+extern crate nalgebra_glm as glm;
+
+use std::collections::HashMap;
+
+use crate::backend::{Capability, Filter, RenderBackend, TextureKind, Uniform};
+use crate::framebuffer::Framebuffer;
+use crate::mesh::Mesh;
+use crate::shader::Shader;
+use crate::shadow::ShadowMap;
+
+// One call received by the mock backend
+#[derive(Clone, Debug, PartialEq)]
+pub enum Call {
+    CreateVao { vao_id: u32, index_count: i32 },
+    CreateProgram { program_id: u32, shader_paths: Vec<String> },
+    CreateFramebuffer { framebuffer_id: u32, width: i32, height: i32, samples: i32 },
+    DeleteFramebuffer(u32),
+    CreateShadowMap { framebuffer_id: u32, texture_id: u32, size: i32, layers: usize },
+    BindFramebuffer { framebuffer_id: u32, width: i32, height: i32 },
+    AttachShadowLayer { framebuffer_id: u32, layer: usize },
+    Blit { source_id: u32, target_id: u32, filter: Filter },
+    ReadPixels { framebuffer_id: u32, width: i32, height: i32 },
+    Clear { color: Option<[f32; 4]>, depth: bool },
+    SetCapability(Capability, bool),
+    SetDepthBias { factor: f32, units: f32 },
+    UseProgram(u32),
+    SetUniform { name: String, value: Uniform },
+    BindTexture { unit: u32, kind: TextureKind, texture_id: u32 },
+    BindVao(u32),
+    DrawElements { index_count: i32 },
+    DrawArrays { vertex_count: i32 },
+}
+
+// The state a draw call was made with, put together from the calls before it
+#[derive(Clone, Debug)]
+pub struct DrawCall {
+    pub program_id: u32,
+    pub vao_id: u32,
+    pub framebuffer_id: u32,
+    pub index_count: i32, // The vertex count for draws without an index buffer
+    pub indexed: bool,
+    pub uniforms: HashMap<String, Uniform>,
+    pub textures: HashMap<u32, u32>, // Texture ids by the unit they are bound to
+}
+
+impl DrawCall {
+    // The matrix uniform with the given name, if it was set
+    pub fn matrix(&self, name: &str) -> Option<glm::Mat4> {
+        match self.uniforms.get(name) {
+            Some(Uniform::Mat4(matrix)) => Some(*matrix),
+            _ => None,
+        }
+    }
+}
+
+// A backend without a GPU behind it. It hands out increasing ids and records every call, so the
+// drawing code can be checked without an OpenGL context.
+#[derive(Default)]
+pub struct MockBackend {
+    pub calls: Vec<Call>,
+    next_id: u32,
+}
+
+impl MockBackend {
+    pub fn new() -> Self {
+        MockBackend::default()
+    }
+
+    fn next_id(&mut self) -> u32 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    // Replay the recorded calls and return the draws, each with the program, vertex array,
+    // framebuffer, uniforms and textures that were current at the time. Uniforms are tracked per
+    // program like in OpenGL.
+    pub fn draws(&self) -> Vec<DrawCall> {
+        let mut program_uniforms: HashMap<u32, HashMap<String, Uniform>> = HashMap::new();
+        let mut textures = HashMap::new();
+        let (mut program_id, mut vao_id, mut framebuffer_id) = (0, 0, 0);
+        let mut draws = vec![];
+        for call in &self.calls {
+            let (index_count, indexed) = match call {
+                Call::UseProgram(id) => {
+                    program_id = *id;
+                    continue;
+                }
+                Call::BindVao(id) => {
+                    vao_id = *id;
+                    continue;
+                }
+                Call::BindFramebuffer { framebuffer_id: id, .. } => {
+                    framebuffer_id = *id;
+                    continue;
+                }
+                Call::SetUniform { name, value } => {
+                    program_uniforms.entry(program_id).or_default().insert(name.clone(), *value);
+                    continue;
+                }
+                Call::BindTexture { unit, texture_id, .. } => {
+                    textures.insert(*unit, *texture_id);
+                    continue;
+                }
+                Call::DrawElements { index_count } => (*index_count, true),
+                Call::DrawArrays { vertex_count } => (*vertex_count, false),
+                _ => continue,
+            };
+            draws.push(DrawCall {
+                program_id,
+                vao_id,
+                framebuffer_id,
+                index_count,
+                indexed,
+                uniforms: program_uniforms.get(&program_id).cloned().unwrap_or_default(),
+                textures: textures.clone(),
+            });
+        }
+        draws
+    }
+
+    // The draws made with the given vertex array object
+    pub fn draws_of(&self, vao_id: u32) -> Vec<DrawCall> {
+        self.draws().into_iter().filter(|draw| draw.vao_id == vao_id).collect()
+    }
+
+    // The draws made into the framebuffer with the given id
+    pub fn draws_into(&self, framebuffer_id: u32) -> Vec<DrawCall> {
+        self.draws().into_iter().filter(|draw| draw.framebuffer_id == framebuffer_id).collect()
+    }
+}
+
+impl RenderBackend for MockBackend {
+    fn create_vao(&mut self, mesh: &Mesh) -> u32 {
+        let vao_id = self.next_id();
+        self.calls.push(Call::CreateVao { vao_id, index_count: mesh.index_count });
+        vao_id
+    }
+
+    fn create_empty_vao(&mut self) -> u32 {
+        let vao_id = self.next_id();
+        self.calls.push(Call::CreateVao { vao_id, index_count: 0 });
+        vao_id
+    }
+
+    fn create_program(&mut self, shader_paths: &[&str]) -> Shader {
+        let program_id = self.next_id();
+        self.calls.push(Call::CreateProgram {
+            program_id,
+            shader_paths: shader_paths.iter().map(|path| path.to_string()).collect(),
+        });
+        Shader { program_id }
+    }
+
+    fn create_framebuffer(&mut self, width: i32, height: i32, samples: i32) -> Framebuffer {
+        let id = self.next_id();
+        let color = self.next_id();
+        let depth_renderbuffer = self.next_id();
+        self.calls.push(Call::CreateFramebuffer { framebuffer_id: id, width, height, samples });
+        let (color_texture, color_renderbuffer) = if samples > 0 { (0, color) } else { (color, 0) };
+        Framebuffer { id, color_texture, color_renderbuffer, depth_renderbuffer, width, height, samples }
+    }
+
+    fn delete_framebuffer(&mut self, framebuffer: &Framebuffer) {
+        self.calls.push(Call::DeleteFramebuffer(framebuffer.id));
+    }
+
+    fn create_shadow_map(&mut self, size: i32, layers: usize) -> ShadowMap {
+        let framebuffer_id = self.next_id();
+        let texture_id = self.next_id();
+        self.calls.push(Call::CreateShadowMap { framebuffer_id, texture_id, size, layers });
+        ShadowMap { framebuffer_id, texture_id, size, layers }
+    }
+
+    fn bind_framebuffer(&mut self, framebuffer_id: u32, width: i32, height: i32) {
+        self.calls.push(Call::BindFramebuffer { framebuffer_id, width, height });
+    }
+
+    fn attach_shadow_layer(&mut self, shadow_map: &ShadowMap, layer: usize) {
+        self.calls.push(Call::AttachShadowLayer { framebuffer_id: shadow_map.framebuffer_id, layer });
+    }
+
+    fn blit(&mut self, source: &Framebuffer, target_id: u32, _width: i32, _height: i32, filter: Filter) {
+        self.calls.push(Call::Blit { source_id: source.id, target_id, filter });
+    }
+
+    // Every pixel is the number of its row, counted from the bottom, in red, and of its column
+    // in green
+    fn read_pixels(&mut self, framebuffer_id: u32, width: i32, height: i32) -> Vec<u8> {
+        self.calls.push(Call::ReadPixels { framebuffer_id, width, height });
+        (0..height).flat_map(|row| (0..width).flat_map(move |column| [row as u8, column as u8, 0, 255])).collect()
+    }
+
+    fn clear(&mut self, color: Option<[f32; 4]>, depth: bool) {
+        self.calls.push(Call::Clear { color, depth });
+    }
+
+    fn set_capability(&mut self, capability: Capability, enabled: bool) {
+        self.calls.push(Call::SetCapability(capability, enabled));
+    }
+
+    fn set_depth_bias(&mut self, factor: f32, units: f32) {
+        self.calls.push(Call::SetDepthBias { factor, units });
+    }
+
+    fn use_program(&mut self, shader: &Shader) {
+        self.calls.push(Call::UseProgram(shader.program_id));
+    }
+
+    fn set_uniform(&mut self, name: &str, value: Uniform) {
+        self.calls.push(Call::SetUniform { name: name.to_string(), value });
+    }
+
+    fn bind_texture(&mut self, unit: u32, kind: TextureKind, texture_id: u32) {
+        self.calls.push(Call::BindTexture { unit, kind, texture_id });
+    }
+
+    fn bind_vao(&mut self, vao_id: u32) {
+        self.calls.push(Call::BindVao(vao_id));
+    }
+
+    fn draw_elements(&mut self, index_count: i32) {
+        self.calls.push(Call::DrawElements { index_count });
+    }
+
+    fn draw_arrays(&mut self, vertex_count: i32) {
+        self.calls.push(Call::DrawArrays { vertex_count });
+    }
+}
@@ -0,0 +1,437 @@
+use std::path::Path;
+
+use crate::flight_path::FlightPath;
+use crate::gamepad::ResponseCurve;
+use crate::headless::{HeadlessBackend, HeadlessOptions};
+use crate::postprocess::PostSettings;
+use crate::util::{DebugAction, DebugSeverity, DebugSink};
+
+pub const USAGE: &str = "\
+Usage: gloom-rs [OPTIONS]
+
+Resources:
+    --resources <DIR>         Directory the models are read from [default: ./resources]
+    --terrain <FILE>          Terrain model [default: <resources>/lunarsurface.obj]
+    --helicopter <FILE>       Helicopter model [default: <resources>/helicopter.obj]
+    --shaders <DIR>           Directory the shaders are read from [default: ./shaders]
+    --controls <FILE>         Key bindings [default: ./controls.cfg]
+    --paths <DIR>             Flight paths, one helicopter for each *.path file [default: ./paths]
+
+Window and rendering:
+    --resolution <WxH>        Window or image size, e.g. 1920x1080 [default: 1600x900]
+    --width <PIXELS>          Width only
+    --height <PIXELS>         Height only
+    --fullscreen              Cover the whole screen, the resolution is ignored
+    --vsync, --no-vsync       Wait for the display between frames [default: on]
+    --msaa <SAMPLES>          Samples per pixel, 0 to turn multisampling off [default: 4]
+
+Post processing, for the passes toggled with F1 to F4:
+    --exposure <SCALE>        Brightness of the HDR colors before tonemapping [default: 1]
+    --gamma <GAMMA>           Display gamma the colors are encoded for [default: 2.2]
+    --vignette <STRENGTH>     How dark the corners get, from 0 to 1 [default: 0.35]
+    --vignette-radius <RADIUS>
+                              Where the darkening starts, from 0 at the center to below 1
+                              at the corners [default: 0.75]
+
+OpenGL debug output:
+    --gl-debug <ACTION>       What to do with driver messages: log, count, or panic to stop at the
+                              first one. Headless runs print the counts at the end [default: log]
+    --gl-debug-severity <LEVEL>
+                              Least severe message to handle: notification, low, medium or high
+                              [default: low]
+    --gl-debug-ignore <IDS>   Comma separated message ids to ignore, can be given more than once
+
+Gamepad:
+    --gamepad-curve <CURVE>   How the sticks respond: linear, power:<P> with P above 0, or
+                              expo:<AMOUNT> from 0 (linear) to 1 (cubic) [default: expo:0.4]
+
+Recording:
+    --record <FILE>           Write every key press and mouse movement to a file
+    --replay <FILE>           Play a recorded session back, from the models it was recorded with.
+                              It has to fly the same flight paths as it was recorded with.
+
+Headless rendering:
+    --headless                Render frames to PNG files instead of opening a window
+    --frames <COUNT>          Number of frames to render [default: 1, or all of a replay]
+    --time <SECONDS>          Simulated time of the first frame, not for replays [default: 0]
+    --fps <RATE>              Simulated frames per second [default: 60]
+    --output <DIR>            Where the frames are written [default: ./headless]
+    --backend <NAME>          auto, egl, osmesa or software [default: auto]
+    --gamepad-script <FILE>   Fly the controllable helicopter from a script of gamepad events
+
+    -h, --help                Print this help
+
+Options taking a value accept both `--msaa 8` and `--msaa=8`.
+";
+
+// Everything that can be set from the command line
+pub struct Options {
+    pub terrain           : String,
+    pub helicopter        : String,
+    pub shader_directory  : String,
+    pub controls          : String,
+    pub flight_paths      : Vec<FlightPath>, // Empty to fly the figure eight
+    pub width             : u32,
+    pub height            : u32,
+    pub fullscreen        : bool,
+    pub vsync             : bool,
+    pub samples           : i32,
+    pub post_settings     : PostSettings,
+    pub gamepad_curve     : ResponseCurve,
+    pub gl_debug          : DebugAction,
+    pub gl_debug_severity : DebugSeverity,
+    pub gl_debug_ignore   : Vec<u32>, // Message ids
+    pub record            : Option<String>,
+    pub replay            : Option<String>,
+    pub headless          : Option<HeadlessOptions>, // Set with --headless
+}
+
+impl Options {
+    // Read the options from the command line arguments, with the program name first. Returns
+    // None if the help was asked for, and an error message for anything that doesn't make sense.
+    pub fn from_args(args: &[String]) -> Result<Option<Options>, String> {
+        let mut resources = "./resources".to_string();
+        let mut terrain = None;
+        let mut helicopter = None;
+        let mut controls = None;
+        let mut paths = None;
+        let mut options = Options {
+            terrain           : String::new(),
+            helicopter        : String::new(),
+            shader_directory  : "./shaders".to_string(),
+            controls          : crate::CONTROLS_PATH.to_string(),
+            flight_paths      : vec![],
+            width             : crate::SCREEN_W,
+            height            : crate::SCREEN_H,
+            fullscreen        : false,
+            vsync             : true,
+            samples           : crate::MSAA_SAMPLES,
+            post_settings     : PostSettings::default(),
+            gamepad_curve     : ResponseCurve::default(),
+            gl_debug          : DebugAction::Log,
+            gl_debug_severity : DebugSeverity::Low,
+            gl_debug_ignore   : vec![],
+            record            : None,
+            replay            : None,
+            headless          : None,
+        };
+        let mut headless = HeadlessOptions::default();
+        let mut run_headless = false;
+        // Headless flags given without --headless are most likely a mistake, so remember one
+        let mut headless_flag = None;
+
+        let mut args = args.iter().skip(1);
+        while let Some(arg) = args.next() {
+            // Split `--flag=value`, otherwise the value is the next argument
+            let (flag, mut inline_value) = match arg.split_once('=') {
+                Some((flag, value)) if flag.starts_with("--") => (flag, Some(value.to_string())),
+                _ => (arg.as_str(), None),
+            };
+            let mut value = || match inline_value.take() {
+                Some(value) => Ok(value),
+                None => args.next().cloned().ok_or(format!("Missing value for {}", flag)),
+            };
+
+            match flag {
+                "-h" | "--help" => return Ok(None),
+                "--resources" => resources = value()?,
+                "--terrain" => terrain = Some(value()?),
+                "--helicopter" => helicopter = Some(value()?),
+                "--shaders" => options.shader_directory = value()?,
+                "--controls" => controls = Some(value()?),
+                "--paths" => paths = Some(value()?),
+                "--resolution" => {
+                    let resolution = value()?;
+                    let (width, height) = resolution
+                        .split_once('x')
+                        .ok_or(format!("Invalid resolution {}, expected WIDTHxHEIGHT", resolution))?;
+                    options.width = parse(flag, width)?;
+                    options.height = parse(flag, height)?;
+                }
+                "--width" => options.width = parse(flag, &value()?)?,
+                "--height" => options.height = parse(flag, &value()?)?,
+                "--fullscreen" => options.fullscreen = true,
+                "--vsync" => options.vsync = true,
+                "--no-vsync" => options.vsync = false,
+                "--msaa" => options.samples = parse(flag, &value()?)?,
+                "--exposure" => options.post_settings.exposure = parse(flag, &value()?)?,
+                "--gamma" => options.post_settings.gamma = parse(flag, &value()?)?,
+                "--vignette" => options.post_settings.vignette_strength = parse(flag, &value()?)?,
+                "--vignette-radius" => options.post_settings.vignette_radius = parse(flag, &value()?)?,
+                "--gamepad-curve" => {
+                    let curve = value()?;
+                    options.gamepad_curve = ResponseCurve::from_name(&curve).ok_or(format!(
+                        "Invalid gamepad curve {}, expected linear, power:<P> with P above 0 or expo:<AMOUNT> from 0 to 1",
+                        curve
+                    ))?
+                }
+                "--gl-debug" => {
+                    let action = value()?;
+                    options.gl_debug = DebugAction::from_name(&action)
+                        .ok_or(format!("Unknown OpenGL debug action {}, expected log, count or panic", action))?
+                }
+                "--gl-debug-severity" => {
+                    let severity = value()?;
+                    options.gl_debug_severity = DebugSeverity::from_name(&severity).ok_or(format!(
+                        "Unknown OpenGL debug severity {}, expected notification, low, medium or high",
+                        severity
+                    ))?
+                }
+                "--gl-debug-ignore" => {
+                    for id in value()?.split(',') {
+                        options.gl_debug_ignore.push(parse(flag, id.trim())?);
+                    }
+                }
+                "--record" => options.record = Some(value()?),
+                "--replay" => options.replay = Some(value()?),
+                "--headless" => run_headless = true,
+                "--frames" | "--time" | "--fps" | "--output" | "--backend" | "--gamepad-script" => {
+                    headless_flag = Some(flag.to_string());
+                    let value = value()?;
+                    match flag {
+                        "--frames" => headless.frames = Some(parse(flag, &value)?),
+                        "--time" => headless.start_time = parse(flag, &value)?,
+                        "--fps" => headless.frames_per_second = parse(flag, &value)?,
+                        "--output" => headless.output_directory = value,
+                        "--backend" => {
+                            headless.backend = HeadlessBackend::from_name(&value).ok_or(format!(
+                                "Unknown headless backend {}, expected auto, egl, osmesa or software",
+                                value
+                            ))?
+                        }
+                        _ => headless.gamepad_script = Some(value),
+                    }
+                }
+                other => return Err(format!("Unknown argument {}", other)),
+            }
+            if inline_value.is_some() {
+                return Err(format!("{} doesn't take a value", flag));
+            }
+        }
+
+        options.terrain = terrain.unwrap_or_else(|| resource_path(&resources, "lunarsurface.obj"));
+        options.helicopter = helicopter.unwrap_or_else(|| resource_path(&resources, "helicopter.obj"));
+        if let Some(controls) = controls {
+            check_file("Key bindings", &controls)?;
+            options.controls = controls;
+        }
+        if run_headless {
+            options.headless = Some(headless);
+        } else if let Some(flag) = headless_flag {
+            return Err(format!("{} only works together with --headless", flag));
+        }
+
+        options.validate()?;
+        // Without a paths directory of their own, the helicopters fly the figure eight
+        match paths {
+            Some(paths) => options.flight_paths = FlightPath::load_directory(&paths)?,
+            None if Path::new(crate::PATHS_DIRECTORY).is_dir() => {
+                options.flight_paths = FlightPath::load_directory(crate::PATHS_DIRECTORY)?
+            }
+            None => {}
+        }
+        Ok(Some(options))
+    }
+
+    // The sink for the OpenGL debug messages, filtered as asked for
+    pub fn debug_sink(&self) -> DebugSink {
+        let sink = DebugSink::new(self.gl_debug).with_min_severity(self.gl_debug_severity);
+        for &id in &self.gl_debug_ignore {
+            sink.suppress(id);
+        }
+        sink
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.width == 0 || self.height == 0 || self.width > 16384 || self.height > 16384 {
+            return Err(format!("Invalid resolution {}x{}, both sides have to be 1 to 16384", self.width, self.height));
+        }
+        if !matches!(self.samples, 0 | 2 | 4 | 8 | 16) {
+            return Err(format!("Invalid MSAA sample count {}, expected 0, 2, 4, 8 or 16", self.samples));
+        }
+        let post = &self.post_settings;
+        if !(post.exposure.is_finite() && post.exposure > 0.0) {
+            return Err("--exposure has to be above 0".to_string());
+        }
+        if !(post.gamma.is_finite() && post.gamma > 0.0) {
+            return Err("--gamma has to be above 0".to_string());
+        }
+        if !(0.0..=1.0).contains(&post.vignette_strength) {
+            return Err("--vignette has to be from 0 to 1".to_string());
+        }
+        if !(0.0..1.0).contains(&post.vignette_radius) {
+            return Err("--vignette-radius has to be from 0 to below 1".to_string());
+        }
+        if self.record.is_some() && self.replay.is_some() {
+            return Err("--record and --replay can't be used together".to_string());
+        }
+        if let Some(replay) = &self.replay {
+            check_file("Recording", replay)?;
+        }
+        if let Some(headless) = &self.headless {
+            if self.fullscreen {
+                return Err("--fullscreen doesn't work together with --headless".to_string());
+            }
+            if self.record.is_some() {
+                return Err("--record needs a window to take input from, not --headless".to_string());
+            }
+            if self.replay.is_some() && headless.gamepad_script.is_some() {
+                return Err("--gamepad-script can't be used together with --replay".to_string());
+            }
+            // The paths and the gamepad would be flown from another time than they were recorded at
+            if self.replay.is_some() && headless.start_time != 0.0 {
+                return Err("--time can't be used together with --replay, which starts where the recording did".to_string());
+            }
+            if headless.frames == Some(0) {
+                return Err("--frames has to be at least 1".to_string());
+            }
+            if !headless.frames_per_second.is_finite() || headless.frames_per_second <= 0.0 {
+                return Err("--fps has to be above 0".to_string());
+            }
+            if !headless.start_time.is_finite() || headless.start_time < 0.0 {
+                return Err("--time has to be 0 or above".to_string());
+            }
+            if let Some(script) = &headless.gamepad_script {
+                check_file("Gamepad script", script)?;
+            }
+        }
+
+        check_file("Terrain model", &self.terrain)?;
+        check_file("Helicopter model", &self.helicopter)?;
+        // The software rasterizer has its shading built in
+        let software = matches!(&self.headless, Some(headless) if headless.backend == HeadlessBackend::Software);
+        if !software && !Path::new(&self.shader_directory).is_dir() {
+            return Err(format!("Shader directory {} not found", self.shader_directory));
+        }
+        Ok(())
+    }
+}
+
+fn parse<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("Invalid value {} for {}", value, flag))
+}
+
+fn resource_path(directory: &str, name: &str) -> String {
+    Path::new(directory).join(name).to_string_lossy().into_owned()
+}
+
+fn check_file(what: &str, path: &str) -> Result<(), String> {
+    if Path::new(path).is_file() {
+        Ok(())
+    } else {
+        Err(format!("{} {} not found", what, path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(args: &[&str]) -> String {
+        let args: Vec<String> = std::iter::once("gloom-rs").chain(args.iter().copied()).map(String::from).collect();
+        Options::from_args(&args).err().expect("the arguments should be rejected")
+    }
+
+    // The repository has no models, so any file stands in for them
+    const MODEL: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml");
+    const SHADERS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/shaders");
+
+    fn options(args: &[&str]) -> Options {
+        let resources = ["gloom-rs", "--terrain", MODEL, "--helicopter", MODEL, "--shaders", SHADERS];
+        let args: Vec<String> = resources.iter().chain(args).copied().map(String::from).collect();
+        Options::from_args(&args).expect("the arguments should be accepted").expect("not the help")
+    }
+
+    #[test]
+    fn everything_has_a_default() {
+        // Without any arguments, the models are looked for in ./resources
+        assert_eq!(error(&[]), format!("Terrain model {} not found", resource_path("./resources", "lunarsurface.obj")));
+        assert_eq!(
+            error(&["--resources", "models", "--terrain", MODEL]),
+            format!("Helicopter model {} not found", resource_path("models", "helicopter.obj"))
+        );
+
+        let options = options(&[]);
+        assert_eq!((options.terrain.as_str(), options.helicopter.as_str()), (MODEL, MODEL));
+        assert_eq!(options.shader_directory, SHADERS);
+        assert_eq!(options.controls, crate::CONTROLS_PATH);
+        assert_eq!((options.width, options.height), (crate::SCREEN_W, crate::SCREEN_H));
+        assert!(!options.fullscreen);
+        assert!(options.vsync);
+        assert_eq!(options.samples, crate::MSAA_SAMPLES);
+        assert_eq!(options.gamepad_curve, ResponseCurve::default());
+        assert_eq!(options.gl_debug, DebugAction::Log);
+        assert_eq!(options.gl_debug_severity, DebugSeverity::Low);
+        assert!(options.gl_debug_ignore.is_empty());
+        assert_eq!(options.post_settings, PostSettings::default());
+        assert!(options.record.is_none() && options.replay.is_none() && options.headless.is_none());
+    }
+
+    #[test]
+    fn window_options_are_read_in_either_form() {
+        assert_eq!(options(&["--msaa=8"]).samples, 8);
+        assert_eq!(options(&["--msaa", "0"]).samples, 0);
+
+        let options_1080p = options(&["--resolution", "1920x1080"]);
+        assert_eq!((options_1080p.width, options_1080p.height), (1920, 1080));
+        let narrow = options(&["--resolution=1920x1080", "--width", "800"]);
+        assert_eq!((narrow.width, narrow.height), (800, 1080));
+
+        assert!(!options(&["--no-vsync"]).vsync);
+        assert!(options(&["--no-vsync", "--vsync"]).vsync);
+        assert!(options(&["--fullscreen"]).fullscreen);
+    }
+
+    #[test]
+    fn headless_runs_render_the_frames_asked_for() {
+        let options = options(&["--headless", "--frames", "3"]);
+        let headless = options.headless.expect("--headless should run headless");
+        assert_eq!(headless.frames, Some(3));
+        let defaults = HeadlessOptions::default();
+        assert_eq!(headless.start_time, defaults.start_time);
+        assert_eq!(headless.frames_per_second, defaults.frames_per_second);
+        assert_eq!(headless.output_directory, defaults.output_directory);
+        assert_eq!(headless.backend, HeadlessBackend::Auto);
+        assert!(headless.gamepad_script.is_none());
+
+        assert_eq!(error(&["--frames", "3"]), "--frames only works together with --headless");
+    }
+
+    #[test]
+    fn debug_output_options_are_read() {
+        let options = options(&["--gl-debug=panic", "--gl-debug-severity", "high", "--gl-debug-ignore", "131185, 131218", "--gl-debug-ignore=7"]);
+        assert_eq!(options.gl_debug, DebugAction::Panic);
+        assert_eq!(options.gl_debug_severity, DebugSeverity::High);
+        assert_eq!(options.gl_debug_ignore, vec![131185, 131218, 7]);
+        let sink = options.debug_sink();
+        assert_eq!((sink.action, sink.min_severity), (DebugAction::Panic, DebugSeverity::High));
+
+        assert_eq!(error(&["--gl-debug", "abort"]), "Unknown OpenGL debug action abort, expected log, count or panic");
+    }
+
+    #[test]
+    fn replays_start_where_they_were_recorded() {
+        let recording = concat!(env!("CARGO_MANIFEST_DIR"), "/controls.cfg");
+        assert_eq!(
+            error(&["--headless", "--replay", recording, "--time", "12"]),
+            "--time can't be used together with --replay, which starts where the recording did"
+        );
+    }
+
+    #[test]
+    fn times_have_to_be_0_or_above() {
+        for time in &["-1", "NaN", "inf"] {
+            assert_eq!(error(&["--headless", "--time", time]), "--time has to be 0 or above");
+        }
+        assert_eq!(error(&["--headless", "--fps", "0"]), "--fps has to be above 0");
+    }
+
+    #[test]
+    fn post_settings_have_to_make_sense() {
+        assert_eq!(error(&["--exposure", "0"]), "--exposure has to be above 0");
+        assert_eq!(error(&["--gamma=-2.2"]), "--gamma has to be above 0");
+        assert_eq!(error(&["--vignette", "1.5"]), "--vignette has to be from 0 to 1");
+        assert_eq!(error(&["--vignette-radius", "1"]), "--vignette-radius has to be from 0 to below 1");
+        assert_eq!(error(&["--exposure", "bright"]), "Invalid value bright for --exposure");
+    }
+}
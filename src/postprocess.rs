@@ -0,0 +1,252 @@
+extern crate nalgebra_glm as glm;
+
+use crate::backend::{Capability, Filter, RenderBackend, TextureKind, Uniform};
+use crate::framebuffer::Framebuffer;
+use crate::shader::{shader_path, Shader};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PostEffect {
+    Tonemap,  // Map HDR colors into [0, 1] with the ACES filmic curve
+    Gamma,    // Encode linear colors for the display
+    Fxaa,     // Fast approximate anti-aliasing
+    Vignette, // Darken the corners of the image
+}
+
+impl PostEffect {
+    // Relative to the shader directory
+    fn fragment_shader(self) -> &'static str {
+        match self {
+            PostEffect::Tonemap => "post/tonemap.frag",
+            PostEffect::Gamma => "post/gamma.frag",
+            PostEffect::Fxaa => "post/fxaa.frag",
+            PostEffect::Vignette => "post/vignette.frag",
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            PostEffect::Tonemap => "tonemap",
+            PostEffect::Gamma => "gamma",
+            PostEffect::Fxaa => "fxaa",
+            PostEffect::Vignette => "vignette",
+        }
+    }
+}
+
+pub struct PostPass {
+    pub effect: PostEffect,
+    pub enabled: bool,
+    shader: Shader,
+}
+
+// Parameters shared by the post processing shaders
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PostSettings {
+    pub exposure: f32,
+    pub gamma: f32,
+    pub vignette_strength: f32,
+    pub vignette_radius: f32,
+}
+
+impl Default for PostSettings {
+    fn default() -> Self {
+        PostSettings {
+            exposure: 1.0,
+            gamma: 2.2,
+            vignette_strength: 0.35,
+            vignette_radius: 0.75,
+        }
+    }
+}
+
+// An ordered list of fullscreen passes. Each enabled pass reads the output of the previous one,
+// and the last enabled pass writes into the output.
+pub struct PostChain {
+    pub passes: Vec<PostPass>,
+    pub settings: PostSettings,
+    ping: Framebuffer,
+    pong: Framebuffer,
+    empty_vao: u32,
+    shader_directory: String,
+}
+
+impl PostChain {
+    pub fn new(backend: &mut dyn RenderBackend, width: i32, height: i32, shader_directory: &str) -> Self {
+        let mut chain = PostChain {
+            passes: vec![],
+            settings: PostSettings::default(),
+            ping: Framebuffer::new(backend, width, height, 0),
+            pong: Framebuffer::new(backend, width, height, 0),
+            empty_vao: backend.create_empty_vao(),
+            shader_directory: shader_directory.to_string(),
+        };
+        // The scene colors are authored for the display, so only the anti-aliasing is on by default
+        chain.push(backend, PostEffect::Tonemap, false);
+        chain.push(backend, PostEffect::Gamma, false);
+        chain.push(backend, PostEffect::Fxaa, true);
+        chain.push(backend, PostEffect::Vignette, false);
+        chain
+    }
+
+    pub fn push(&mut self, backend: &mut dyn RenderBackend, effect: PostEffect, enabled: bool) {
+        let shader = backend.create_program(&[
+            &shader_path(&self.shader_directory, "post/fullscreen.vert"),
+            &shader_path(&self.shader_directory, effect.fragment_shader()),
+        ]);
+        self.passes.push(PostPass { effect, enabled, shader });
+    }
+
+    // Flip the given effect on or off, and return whether it is now enabled
+    pub fn toggle(&mut self, effect: PostEffect) -> bool {
+        match self.passes.iter_mut().find(|pass| pass.effect == effect) {
+            Some(pass) => {
+                pass.enabled = !pass.enabled;
+                pass.enabled
+            }
+            None => false,
+        }
+    }
+
+    pub fn resize(&mut self, backend: &mut dyn RenderBackend, width: i32, height: i32) {
+        self.ping.resize(backend, width, height);
+        self.pong.resize(backend, width, height);
+    }
+
+    fn set_uniforms(&self, backend: &mut dyn RenderBackend, width: i32, height: i32) {
+        backend.set_uniform("image", Uniform::Int(0));
+        backend.set_uniform("texelSize", Uniform::Vec2(glm::vec2(1.0 / width as f32, 1.0 / height as f32)));
+        backend.set_uniform("exposure", Uniform::Float(self.settings.exposure));
+        backend.set_uniform("gamma", Uniform::Float(self.settings.gamma));
+        backend.set_uniform("vignetteStrength", Uniform::Float(self.settings.vignette_strength));
+        backend.set_uniform("vignetteRadius", Uniform::Float(self.settings.vignette_radius));
+    }
+
+    // Run every enabled pass on the color of `source`, which has to be single sampled, and write
+    // the result into `output`. Without an output, the result goes into the window with the
+    // given size.
+    pub fn run(
+        &mut self,
+        backend: &mut dyn RenderBackend,
+        source: &Framebuffer,
+        output: Option<&Framebuffer>,
+        width: i32,
+        height: i32,
+    ) {
+        let (output_id, width, height) = match output {
+            Some(target) => (target.id, target.width, target.height),
+            None => (0, width, height),
+        };
+
+        let enabled: Vec<&PostPass> = self.passes.iter().filter(|pass| pass.enabled).collect();
+        if enabled.is_empty() {
+            backend.blit(source, output_id, width, height, Filter::Linear);
+            return;
+        }
+
+        backend.set_capability(Capability::DepthTest, false);
+        backend.set_capability(Capability::Blending, false);
+        backend.bind_vao(self.empty_vao);
+
+        let mut input = source.color_texture;
+        for (i, pass) in enabled.iter().enumerate() {
+            let last = i + 1 == enabled.len();
+            let target = if i % 2 == 0 { &self.ping } else { &self.pong };
+            if last {
+                backend.bind_framebuffer(output_id, width, height);
+            } else {
+                target.bind(backend);
+            }
+
+            backend.use_program(&pass.shader);
+            self.set_uniforms(backend, source.width, source.height);
+            backend.bind_texture(0, TextureKind::Texture2d, input);
+            // A single triangle covering the screen, generated from gl_VertexID
+            backend.draw_arrays(3);
+            input = target.color_texture;
+        }
+
+        backend.bind_texture(0, TextureKind::Texture2d, 0);
+        backend.set_capability(Capability::Blending, true);
+        backend.set_capability(Capability::DepthTest, true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_backend::{Call, MockBackend};
+
+    fn chain(backend: &mut MockBackend, enabled: &[PostEffect]) -> PostChain {
+        let mut chain = PostChain::new(backend, 320, 200, "shaders");
+        for pass in chain.passes.iter_mut() {
+            pass.enabled = enabled.contains(&pass.effect);
+        }
+        chain
+    }
+
+    #[test]
+    fn passes_take_turns_drawing_into_the_two_targets() {
+        let mut backend = MockBackend::new();
+        let all = [PostEffect::Tonemap, PostEffect::Gamma, PostEffect::Fxaa, PostEffect::Vignette];
+        let mut chain = chain(&mut backend, &all);
+        chain.settings = PostSettings { exposure: 1.5, gamma: 2.4, vignette_strength: 0.5, vignette_radius: 0.6 };
+        let source = Framebuffer::new(&mut backend, 320, 200, 0);
+        let output = Framebuffer::new(&mut backend, 320, 200, 0);
+        backend.calls.clear();
+
+        chain.run(&mut backend, &source, Some(&output), 640, 480);
+
+        let draws = backend.draws();
+        let (ping, pong) = (&chain.ping, &chain.pong);
+        let targets: Vec<u32> = draws.iter().map(|draw| draw.framebuffer_id).collect();
+        assert_eq!(targets, vec![ping.id, pong.id, ping.id, output.id]);
+        // Each pass reads what the one before it wrote
+        let inputs: Vec<u32> = draws.iter().map(|draw| draw.textures[&0]).collect();
+        assert_eq!(inputs, vec![source.color_texture, ping.color_texture, pong.color_texture, ping.color_texture]);
+        let programs: Vec<u32> = draws.iter().map(|draw| draw.program_id).collect();
+        assert_eq!(programs, chain.passes.iter().map(|pass| pass.shader.program_id).collect::<Vec<_>>());
+
+        for draw in &draws {
+            assert!(!draw.indexed);
+            assert_eq!(draw.index_count, 3);
+            assert_eq!(draw.uniforms["exposure"], Uniform::Float(1.5));
+            assert_eq!(draw.uniforms["gamma"], Uniform::Float(2.4));
+            assert_eq!(draw.uniforms["vignetteStrength"], Uniform::Float(0.5));
+            assert_eq!(draw.uniforms["vignetteRadius"], Uniform::Float(0.6));
+        }
+        assert!(!backend.calls.iter().any(|call| matches!(call, Call::Blit { .. })));
+        // The depth test and blending are back on for the next frame
+        assert_eq!(backend.calls.last(), Some(&Call::SetCapability(Capability::DepthTest, true)));
+    }
+
+    #[test]
+    fn the_last_enabled_pass_draws_into_the_window() {
+        let mut backend = MockBackend::new();
+        let mut chain = chain(&mut backend, &[PostEffect::Gamma, PostEffect::Vignette]);
+        let source = Framebuffer::new(&mut backend, 320, 200, 0);
+        backend.calls.clear();
+
+        chain.run(&mut backend, &source, None, 640, 480);
+
+        let draws = backend.draws();
+        assert_eq!(draws.iter().map(|draw| draw.framebuffer_id).collect::<Vec<_>>(), vec![chain.ping.id, 0]);
+        assert_eq!(draws[1].textures[&0], chain.ping.color_texture);
+        assert!(backend.calls.contains(&Call::BindFramebuffer { framebuffer_id: 0, width: 640, height: 480 }));
+    }
+
+    #[test]
+    fn without_enabled_passes_the_source_is_copied() {
+        let mut backend = MockBackend::new();
+        let mut chain = chain(&mut backend, &[]);
+        let source = Framebuffer::new(&mut backend, 320, 200, 0);
+        let output = Framebuffer::new(&mut backend, 320, 200, 0);
+
+        backend.calls.clear();
+        chain.run(&mut backend, &source, Some(&output), 640, 480);
+        assert_eq!(backend.calls, vec![Call::Blit { source_id: source.id, target_id: output.id, filter: Filter::Linear }]);
+
+        backend.calls.clear();
+        chain.run(&mut backend, &source, None, 640, 480);
+        assert_eq!(backend.calls, vec![Call::Blit { source_id: source.id, target_id: 0, filter: Filter::Linear }]);
+    }
+}
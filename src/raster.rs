@@ -0,0 +1,417 @@
+extern crate nalgebra_glm as glm;
+
+use std::collections::HashMap;
+
+use crate::light::{LightKind, Lighting};
+use crate::mesh::Mesh;
+use crate::camera::Camera;
+use crate::scene_graph::SceneNode;
+
+// A vertex after the vertex shader, with everything the fragment shader interpolates
+#[derive(Clone, Copy)]
+struct ClipVertex {
+    clip: glm::Vec4,
+    position: glm::Vec3, // World space
+    normal: glm::Vec3,   // World space
+    color: glm::Vec4,
+}
+
+impl ClipVertex {
+    fn lerp(&self, other: &ClipVertex, t: f32) -> ClipVertex {
+        ClipVertex {
+            clip: glm::lerp(&self.clip, &other.clip, t),
+            position: glm::lerp(&self.position, &other.position, t),
+            normal: glm::lerp(&self.normal, &other.normal, t),
+            color: glm::lerp(&self.color, &other.color, t),
+        }
+    }
+}
+
+// A pure Rust stand-in for the OpenGL renderer. It draws the same scene graph with the same
+// matrices and lighting model as simple.vert and simple.frag, so it gives deterministic
+// reference images and works on machines without OpenGL. Shadows and post processing are left
+// out.
+pub struct SoftwareRenderer {
+    pub width: u32,
+    pub height: u32,
+    pub clear_color: [f32; 4],
+    pub cull_back_faces: bool,
+    meshes: HashMap<u32, Mesh>,
+    next_vao_id: u32,
+    color: Vec<glm::Vec4>,
+    depth: Vec<f32>,
+}
+
+impl SoftwareRenderer {
+    pub fn new(width: u32, height: u32) -> Self {
+        SoftwareRenderer {
+            width,
+            height,
+            clear_color: [0.6, 0.713_725_5, 0.949_019_6, 0.7],
+            cull_back_faces: true,
+            meshes: HashMap::new(),
+            next_vao_id: 1,
+            color: vec![glm::zero(); (width * height) as usize],
+            depth: vec![1.0; (width * height) as usize],
+        }
+    }
+
+    // Take a copy of the mesh and hand out an id to use in place of a VAO id
+    pub fn register_mesh(&mut self, mesh: &Mesh) -> u32 {
+        let id = self.next_vao_id;
+        self.next_vao_id += 1;
+        self.register_mesh_with_id(id, mesh);
+        id
+    }
+
+    // Use the mesh for nodes with the given VAO id, e.g. to mirror a scene uploaded to OpenGL
+    pub fn register_mesh_with_id(&mut self, vao_id: u32, mesh: &Mesh) {
+        self.meshes.insert(vao_id, mesh.clone());
+        self.next_vao_id = self.next_vao_id.max(vao_id + 1);
+    }
+
+    fn clear(&mut self) {
+        let [r, g, b, a] = self.clear_color;
+        self.color.iter_mut().for_each(|c| *c = glm::vec4(r, g, b, a));
+        self.depth.iter_mut().for_each(|d| *d = 1.0);
+    }
+
+    // Draw the scene as seen through the camera, and return the finished image. The node
+    // transformations must already be up to date for this frame.
+    pub fn render(&mut self, root_node: &SceneNode, lighting: &Lighting, camera: &Camera) -> image::RgbaImage {
+        let aspect = self.width as f32 / self.height as f32;
+        let view_projection = camera.projection(aspect) * camera.view();
+        let camera_position = camera.position();
+
+        self.clear();
+        self.draw_node(root_node, &view_projection, lighting, &camera_position);
+        self.to_image()
+    }
+
+    fn draw_node(&mut self, node: &SceneNode, view_projection: &glm::Mat4, lighting: &Lighting, camera_position: &glm::Vec3) {
+        if node.index_count > 0 {
+            if let Some(mesh) = self.meshes.remove(&node.vao_id) {
+                self.draw_mesh(&mesh, node, view_projection, lighting, camera_position);
+                self.meshes.insert(node.vao_id, mesh);
+            }
+        }
+        for &child in &node.children {
+            self.draw_node(unsafe { &*child }, view_projection, lighting, camera_position);
+        }
+    }
+
+    fn draw_mesh(
+        &mut self,
+        mesh: &Mesh,
+        node: &SceneNode,
+        view_projection: &glm::Mat4,
+        lighting: &Lighting,
+        camera_position: &glm::Vec3,
+    ) {
+        let model = node.current_transformation_matrix;
+        let mvp = view_projection * model;
+        let normal_matrix = glm::mat4_to_mat3(&model);
+
+        // The vertex shader
+        let vertices: Vec<ClipVertex> = (0..mesh.vertices.len() / 3)
+            .map(|i| {
+                let p = glm::vec4(mesh.vertices[3 * i], mesh.vertices[3 * i + 1], mesh.vertices[3 * i + 2], 1.0);
+                let n = if mesh.normals.len() >= 3 * i + 3 {
+                    glm::vec3(mesh.normals[3 * i], mesh.normals[3 * i + 1], mesh.normals[3 * i + 2])
+                } else {
+                    glm::vec3(0.0, 1.0, 0.0)
+                };
+                ClipVertex {
+                    clip: mvp * p,
+                    position: (model * p).xyz(),
+                    normal: glm::normalize(&(normal_matrix * n)),
+                    color: glm::vec4(
+                        mesh.colors[4 * i],
+                        mesh.colors[4 * i + 1],
+                        mesh.colors[4 * i + 2],
+                        mesh.colors[4 * i + 3],
+                    ),
+                }
+            })
+            .collect();
+
+        let count = (node.index_count as usize).min(mesh.indices.len());
+        for triangle in mesh.indices[..count].chunks_exact(3) {
+            let corners = [
+                vertices[triangle[0] as usize],
+                vertices[triangle[1] as usize],
+                vertices[triangle[2] as usize],
+            ];
+            let clipped = clip_near_plane(&corners);
+            for i in 1..clipped.len().saturating_sub(1) {
+                self.rasterize(&[clipped[0], clipped[i], clipped[i + 1]], lighting, camera_position);
+            }
+        }
+    }
+
+    fn rasterize(&mut self, corners: &[ClipVertex; 3], lighting: &Lighting, camera_position: &glm::Vec3) {
+        let (width, height) = (self.width as f32, self.height as f32);
+        // Perspective divide and viewport transform, with y pointing down like in the image
+        let screen: Vec<glm::Vec3> = corners
+            .iter()
+            .map(|v| {
+                let ndc = v.clip.xyz() / v.clip.w;
+                glm::vec3((ndc.x + 1.0) * 0.5 * width, (1.0 - ndc.y) * 0.5 * height, ndc.z * 0.5 + 0.5)
+            })
+            .collect();
+
+        // Counter clockwise triangles are front facing in OpenGL. The area is positive for those,
+        // since flipping y flips the winding as well as the sign of edge().
+        let area = edge(&screen[0], &screen[1], &screen[2]);
+        if area == 0.0 || (self.cull_back_faces && area < 0.0) {
+            return;
+        }
+
+        let min_x = screen.iter().map(|p| p.x).fold(f32::INFINITY, f32::min).floor().max(0.0) as u32;
+        let max_x = screen.iter().map(|p| p.x).fold(f32::NEG_INFINITY, f32::max).ceil().min(width - 1.0) as i64;
+        let min_y = screen.iter().map(|p| p.y).fold(f32::INFINITY, f32::min).floor().max(0.0) as u32;
+        let max_y = screen.iter().map(|p| p.y).fold(f32::NEG_INFINITY, f32::max).ceil().min(height - 1.0) as i64;
+        if max_x < 0 || max_y < 0 {
+            return;
+        }
+
+        for y in min_y..=max_y as u32 {
+            for x in min_x..=max_x as u32 {
+                let sample = glm::vec3(x as f32 + 0.5, y as f32 + 0.5, 0.0);
+                let w0 = edge(&screen[1], &screen[2], &sample) / area;
+                let w1 = edge(&screen[2], &screen[0], &sample) / area;
+                let w2 = edge(&screen[0], &screen[1], &sample) / area;
+                if w0 < 0.0 || w1 < 0.0 || w2 < 0.0 {
+                    continue;
+                }
+
+                let depth = w0 * screen[0].z + w1 * screen[1].z + w2 * screen[2].z;
+                let index = (y * self.width + x) as usize;
+                if depth < 0.0 || depth >= self.depth[index] {
+                    continue;
+                }
+
+                // Perspective correct interpolation of the vertex shader outputs
+                let (p0, p1, p2) = (w0 / corners[0].clip.w, w1 / corners[1].clip.w, w2 / corners[2].clip.w);
+                let sum = p0 + p1 + p2;
+                let (p0, p1, p2) = (p0 / sum, p1 / sum, p2 / sum);
+                let position = corners[0].position * p0 + corners[1].position * p1 + corners[2].position * p2;
+                let normal = corners[0].normal * p0 + corners[1].normal * p1 + corners[2].normal * p2;
+                let color = corners[0].color * p0 + corners[1].color * p1 + corners[2].color * p2;
+
+                let lit = shade(lighting, &position, &normal, &color, camera_position);
+                // Blend with SRC_ALPHA, ONE_MINUS_SRC_ALPHA like the OpenGL renderer
+                let destination = self.color[index];
+                let rgb = lit * color.w + destination.xyz() * (1.0 - color.w);
+                let alpha = color.w * color.w + destination.w * (1.0 - color.w);
+                self.color[index] = glm::vec4(rgb.x, rgb.y, rgb.z, alpha);
+                self.depth[index] = depth;
+            }
+        }
+    }
+
+    fn to_image(&self) -> image::RgbaImage {
+        let to_byte = |v: f32| (v.clamp(0.0, 1.0) * 255.0).round() as u8;
+        image::RgbaImage::from_fn(self.width, self.height, |x, y| {
+            let c = self.color[(y * self.width + x) as usize];
+            image::Rgba([to_byte(c.x), to_byte(c.y), to_byte(c.z), to_byte(c.w)])
+        })
+    }
+}
+
+// Twice the signed area of the triangle a, b, c in screen space
+fn edge(a: &glm::Vec3, b: &glm::Vec3, c: &glm::Vec3) -> f32 {
+    (c.x - a.x) * (b.y - a.y) - (c.y - a.y) * (b.x - a.x)
+}
+
+// Cut away the part of the triangle in front of the near plane (z < -w), which would otherwise
+// blow up in the perspective divide. Returns a convex polygon with zero to four corners.
+fn clip_near_plane(corners: &[ClipVertex; 3]) -> Vec<ClipVertex> {
+    let distance = |v: &ClipVertex| v.clip.z + v.clip.w;
+    let mut polygon = Vec::with_capacity(4);
+    for i in 0..3 {
+        let current = &corners[i];
+        let next = &corners[(i + 1) % 3];
+        let (d_current, d_next) = (distance(current), distance(next));
+        if d_current >= 0.0 {
+            polygon.push(*current);
+        }
+        if (d_current >= 0.0) != (d_next >= 0.0) {
+            polygon.push(current.lerp(next, d_current / (d_current - d_next)));
+        }
+    }
+    polygon
+}
+
+// The lighting of simple.frag, without the shadows
+fn shade(lighting: &Lighting, position: &glm::Vec3, normal: &glm::Vec3, color: &glm::Vec4, camera_position: &glm::Vec3) -> glm::Vec3 {
+    let normal = glm::normalize(normal);
+    let view_direction = glm::normalize(&(camera_position - position));
+    let albedo = color.xyz();
+
+    let mut lit = lighting.ambient.component_mul(&albedo);
+    for light in lighting.lights.iter().filter(|l| l.enabled).take(crate::light::MAX_LIGHTS) {
+        let light_direction = light.world_direction();
+        let (to_light, mut falloff) = match light.kind {
+            LightKind::Directional => (-light_direction, 1.0),
+            _ => {
+                let offset = light.world_position() - position;
+                let distance = glm::length(&offset);
+                let a = light.attenuation;
+                (offset / distance, 1.0 / (a.x + a.y * distance + a.z * distance * distance))
+            }
+        };
+        if let LightKind::Spot { inner_angle, outer_angle } = light.kind {
+            let theta = glm::dot(&-to_light, &light_direction);
+            let (inner, outer) = (inner_angle.cos(), outer_angle.cos());
+            falloff *= ((theta - outer) / (inner - outer).max(1e-4)).clamp(0.0, 1.0);
+        }
+
+        let diffuse = glm::dot(&normal, &to_light).max(0.0);
+        let halfway = glm::normalize(&(to_light + view_direction));
+        let specular = if diffuse > 0.0 {
+            glm::dot(&normal, &halfway).max(0.0).powf(lighting.shininess)
+        } else {
+            0.0
+        };
+        let radiance = light.color * light.intensity * falloff;
+        lit += radiance.component_mul(&(albedo * diffuse + glm::vec3(1.0, 1.0, 1.0) * lighting.specular_strength * specular));
+    }
+    lit
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::renderer::update_node_transformations;
+    use crate::scene_graph::SceneNode;
+
+    const RED: [f32; 4] = [1.0, 0.0, 0.0, 1.0];
+    const GREEN: [f32; 4] = [0.0, 1.0, 0.0, 1.0];
+    const BLUE: [f32; 4] = [0.0, 0.0, 1.0, 1.0];
+    const BLACK: [u8; 4] = [0, 0, 0, 255];
+
+    // Corners with one color each, facing up
+    fn mesh(corners: &[[f32; 3]], colors: &[[f32; 4]], indices: &[u32]) -> Mesh {
+        Mesh {
+            vertices: corners.iter().flatten().copied().collect(),
+            normals: corners.iter().flat_map(|_| vec![0.0, 1.0, 0.0]).collect(),
+            colors: colors.iter().flatten().copied().collect(),
+            indices: indices.to_vec(),
+            index_count: indices.len() as i32,
+        }
+    }
+
+    fn triangle(corners: [[f32; 3]; 3], color: [f32; 4]) -> Mesh {
+        mesh(&corners, &[color; 3], &[0, 1, 2])
+    }
+
+    fn quad(corners: [[f32; 3]; 4], colors: [[f32; 4]; 4]) -> Mesh {
+        mesh(&corners, &colors, &[0, 1, 2, 0, 2, 3])
+    }
+
+    // An 8 by 8 image of the meshes, drawn in order. Only a white ambient light shines on them, so
+    // every pixel shows the vertex colors as they are interpolated.
+    fn render(renderer: &mut SoftwareRenderer, view_projection: &glm::Mat4, meshes: &[Mesh]) -> image::RgbaImage {
+        let mut root = SceneNode::new();
+        for mesh in meshes {
+            let node = SceneNode::from_vao(renderer.register_mesh(mesh), mesh.index_count);
+            root.add_child(&node);
+        }
+        let mut lighting = Lighting::new();
+        lighting.ambient = glm::vec3(1.0, 1.0, 1.0);
+
+        unsafe { update_node_transformations(&mut root, &glm::identity()) };
+        renderer.clear();
+        renderer.draw_node(&root, view_projection, &lighting, &glm::zero());
+        renderer.to_image()
+    }
+
+    fn renderer() -> SoftwareRenderer {
+        let mut renderer = SoftwareRenderer::new(8, 8);
+        renderer.clear_color = [0.0, 0.0, 0.0, 1.0];
+        renderer
+    }
+
+    // Looking along -z from the origin with a field of view of 90 degrees, so a point at depth d
+    // and height h is at h / d in normalized device coordinates
+    fn perspective() -> glm::Mat4 {
+        glm::perspective(1.0, std::f32::consts::FRAC_PI_2, 0.1, 100.0)
+    }
+
+    #[test]
+    fn triangles_cover_the_pixels_with_their_centers_inside() {
+        // The lower left half of the image, the diagonal edge going right through the centers of
+        // the pixels on it, which count as inside
+        let lower_left = triangle([[-1.0, -1.0, 0.0], [1.0, -1.0, 0.0], [-1.0, 1.0, 0.0]], RED);
+        let image = render(&mut renderer(), &glm::identity(), &[lower_left]);
+        for (x, y, pixel) in image.enumerate_pixels() {
+            let expected = if x <= y { [255, 0, 0, 255] } else { BLACK };
+            assert_eq!(pixel.0, expected, "pixel {}, {}", x, y);
+        }
+
+        // The left half, with the edge between the columns
+        let left = quad([[-1.0, -1.0, 0.0], [0.0, -1.0, 0.0], [0.0, 1.0, 0.0], [-1.0, 1.0, 0.0]], [GREEN; 4]);
+        let image = render(&mut renderer(), &glm::identity(), &[left]);
+        for (x, y, pixel) in image.enumerate_pixels() {
+            let expected = if x < 4 { [0, 255, 0, 255] } else { BLACK };
+            assert_eq!(pixel.0, expected, "pixel {}, {}", x, y);
+        }
+    }
+
+    #[test]
+    fn back_faces_are_culled_unless_asked_not_to() {
+        let clockwise = || triangle([[-1.0, -1.0, 0.0], [-1.0, 1.0, 0.0], [1.0, -1.0, 0.0]], RED);
+        let image = render(&mut renderer(), &glm::identity(), &[clockwise()]);
+        assert!(image.pixels().all(|pixel| pixel.0 == BLACK));
+
+        let mut both_sides = renderer();
+        both_sides.cull_back_faces = false;
+        let image = render(&mut both_sides, &glm::identity(), &[clockwise()]);
+        for (x, y, pixel) in image.enumerate_pixels() {
+            let expected = if x <= y { [255, 0, 0, 255] } else { BLACK };
+            assert_eq!(pixel.0, expected, "pixel {}, {}", x, y);
+        }
+    }
+
+    #[test]
+    fn nearer_triangles_hide_farther_ones_in_either_order() {
+        let screen = |z: f32, color| quad([[-1.0, -1.0, z], [1.0, -1.0, z], [1.0, 1.0, z], [-1.0, 1.0, z]], [color; 4]);
+        let near_first = render(&mut renderer(), &glm::identity(), &[screen(-0.5, GREEN), screen(0.5, RED)]);
+        let far_first = render(&mut renderer(), &glm::identity(), &[screen(0.5, RED), screen(-0.5, GREEN)]);
+        assert!(near_first.pixels().all(|pixel| pixel.0 == [0, 255, 0, 255]));
+        assert!(far_first.pixels().all(|pixel| pixel.0 == [0, 255, 0, 255]));
+    }
+
+    #[test]
+    fn triangles_reaching_behind_the_camera_are_clipped_at_the_near_plane() {
+        // Ground under the camera, with a corner behind it. Below the horizon across the middle of
+        // the image there is nothing but ground, and above it nothing at all.
+        let ground = triangle([[-10.0, -1.0, -10.0], [0.0, -1.0, 10.0], [10.0, -1.0, -10.0]], BLUE);
+        let image = render(&mut renderer(), &perspective(), &[ground]);
+        for (x, y, pixel) in image.enumerate_pixels() {
+            let expected = if y >= 4 { [0, 0, 255, 255] } else { BLACK };
+            assert_eq!(pixel.0, expected, "pixel {}, {}", x, y);
+        }
+    }
+
+    #[test]
+    fn colors_are_interpolated_in_perspective() {
+        // Ground going from red right in front of the camera to blue 8 units further away. Each
+        // row below the horizon sees it at a depth of 1 over its height on the screen, and is as
+        // far from red to blue as that depth is from 1 to 9, which blending on the screen would
+        // get wrong.
+        let ground = quad(
+            [[-10.0, -1.0, -1.0], [10.0, -1.0, -1.0], [10.0, -1.0, -9.0], [-10.0, -1.0, -9.0]],
+            [RED, RED, BLUE, BLUE],
+        );
+        let image = render(&mut renderer(), &perspective(), &[ground]);
+        let expected = [[32, 0, 223, 255], [202, 0, 53, 255], [236, 0, 19, 255], [250, 0, 5, 255]];
+        for (row, expected) in (4..8).zip(&expected) {
+            for x in 0..8 {
+                assert_eq!(&image.get_pixel(x, row).0, expected, "pixel {}, {}", x, row);
+            }
+        }
+        assert!((0..4).all(|y| (0..8).all(|x| image.get_pixel(x, y).0 == BLACK)));
+    }
+}
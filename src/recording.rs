@@ -0,0 +1,253 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use glutin::event::{ElementState, VirtualKeyCode};
+
+use crate::flight_path::{self, FlightPath};
+use crate::gamepad::{self, GamepadEvent, ResponseCurve};
+use crate::input::{self, InputMap};
+
+// Everything the player did during one frame, as the render thread received it
+#[derive(Clone, Debug, Default)]
+pub struct FrameInput {
+    pub delta_time  : f32,
+    pub keys        : Vec<(VirtualKeyCode, ElementState)>,
+    pub mouse_delta : (f32, f32),                // Pixels
+    pub scroll      : f32,                       // Lines
+    pub gamepad     : Vec<(f32, GamepadEvent)>, // Polled during the simulation steps, at these times
+}
+
+// The start of a session. The scene itself has nothing random in it, so the models, the
+// simulation step, the key bindings, the gamepad curve and the flight paths are all it takes to
+// start over the same way. The paths are only kept as a fingerprint, to check that a replay flies
+// the same ones.
+pub struct RecordingHeader {
+    pub timestep      : f32,
+    pub terrain       : String,
+    pub helicopter    : String,
+    pub paths         : u64, // flight_path::fingerprint of the paths flown
+    pub input_map     : InputMap,
+    pub gamepad_curve : ResponseCurve,
+}
+
+// A session read back from a file, to be fed through the simulation again
+pub struct InputRecording {
+    pub header : RecordingHeader,
+    pub frames : Vec<FrameInput>,
+}
+
+const VERSION: u32 = 2;
+
+// Writes the input of every frame to a text file as it happens, so a crash loses nothing. The
+// file looks like
+//
+//     version 2
+//     timestep 0.008333334
+//     terrain ./resources/lunarsurface.obj
+//     helicopter ./resources/helicopter.obj
+//     paths 6f3a09c1d2e4b587
+//     gamepad_curve expo:0.4
+//     bind move_forward = W, -S
+//     ...
+//     frame 0.016
+//     key W pressed
+//     mouse 3 -2
+//     scroll 1
+//     gamepad 0.0166 right_stick_y 0.4
+pub struct InputRecorder {
+    writer: BufWriter<File>,
+    pub frames: usize,
+}
+
+impl InputRecorder {
+    pub fn create(path: &str, header: &RecordingHeader) -> Result<Self, String> {
+        let file = File::create(path).map_err(|e| format!("Failed to create {}: {}", path, e))?;
+        let mut recorder = InputRecorder { writer: BufWriter::new(file), frames: 0 };
+        let mut text = format!(
+            "# Input recorded by gloom-rs, replay it with --replay\nversion {}\ntimestep {}\nterrain {}\nhelicopter {}\npaths {:016x}\n\
+             gamepad_curve {}\n",
+            VERSION, header.timestep, header.terrain, header.helicopter, header.paths, header.gamepad_curve
+        );
+        for line in header.input_map.to_config().lines() {
+            text += &format!("bind {}\n", line);
+        }
+        recorder.write(&text).map_err(|e| format!("Failed to write {}: {}", path, e))?;
+        Ok(recorder)
+    }
+
+    fn write(&mut self, text: &str) -> std::io::Result<()> {
+        self.writer.write_all(text.as_bytes())?;
+        self.writer.flush()
+    }
+
+    pub fn record(&mut self, frame: &FrameInput) -> std::io::Result<()> {
+        let mut text = format!("frame {}\n", frame.delta_time);
+        for &(key, state) in &frame.keys {
+            // Keys without a name can't be bound, so they change nothing
+            if let Some(name) = input::key_name(key) {
+                let state = if state == ElementState::Pressed { "pressed" } else { "released" };
+                text += &format!("key {} {}\n", name, state);
+            }
+        }
+        if frame.mouse_delta != (0.0, 0.0) {
+            text += &format!("mouse {} {}\n", frame.mouse_delta.0, frame.mouse_delta.1);
+        }
+        if frame.scroll != 0.0 {
+            text += &format!("scroll {}\n", frame.scroll);
+        }
+        for (time, event) in &frame.gamepad {
+            text += &format!("gamepad {}\n", gamepad::format_event(*time, event));
+        }
+        self.frames += 1;
+        self.write(&text)
+    }
+}
+
+impl InputRecording {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut version = None;
+        let mut timestep = None;
+        let mut terrain = None;
+        let mut helicopter = None;
+        let mut paths = None;
+        // Recordings from before the curve could be changed were made with the default one
+        let mut gamepad_curve = ResponseCurve::default();
+        let mut bindings = String::new();
+        let mut frames: Vec<FrameInput> = vec![];
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = |message: String| format!("line {}: {}", number + 1, message);
+            let (keyword, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let rest = rest.trim();
+            let words: Vec<&str> = rest.split_whitespace().collect();
+            let number = |word: &str| word.parse::<f32>().map_err(|_| error(format!("Invalid number {}", word)));
+
+            // Events belong to the last frame line above them
+            if matches!(keyword, "key" | "mouse" | "scroll" | "gamepad") && frames.is_empty() {
+                return Err(error(format!("{} before the first frame", keyword)));
+            }
+            match (keyword, words.as_slice()) {
+                ("version", [v]) => version = Some(v.parse::<u32>().map_err(|_| error(format!("Invalid version {}", v)))?),
+                ("timestep", [t]) => timestep = Some(number(t)?),
+                ("terrain", _) if !rest.is_empty() => terrain = Some(rest.to_string()),
+                ("helicopter", _) if !rest.is_empty() => helicopter = Some(rest.to_string()),
+                ("paths", [hash]) => {
+                    paths = Some(u64::from_str_radix(hash, 16).map_err(|_| error(format!("Invalid paths fingerprint {}", hash)))?)
+                }
+                ("gamepad_curve", [curve]) => {
+                    gamepad_curve = ResponseCurve::from_name(curve).ok_or_else(|| error(format!("Invalid gamepad curve {}", curve)))?
+                }
+                ("bind", _) => bindings += &format!("{}\n", rest),
+                ("frame", [delta_time]) => frames.push(FrameInput { delta_time: number(delta_time)?, ..FrameInput::default() }),
+                ("key", [name, state]) => {
+                    let key = input::key_from_name(name).ok_or_else(|| error(format!("Unknown key {}", name)))?;
+                    let state = match *state {
+                        "pressed" => ElementState::Pressed,
+                        "released" => ElementState::Released,
+                        _ => return Err(error(format!("Expected pressed or released, not {}", state))),
+                    };
+                    frames.last_mut().unwrap().keys.push((key, state));
+                }
+                ("mouse", [x, y]) => frames.last_mut().unwrap().mouse_delta = (number(x)?, number(y)?),
+                ("scroll", [lines]) => frames.last_mut().unwrap().scroll = number(lines)?,
+                ("gamepad", _) => {
+                    let event = gamepad::parse_event(&words).map_err(error)?;
+                    frames.last_mut().unwrap().gamepad.push(event);
+                }
+                _ => return Err(error(format!("Unexpected {}", line))),
+            }
+        }
+
+        match version {
+            Some(VERSION) => {}
+            Some(version) => return Err(format!("Unsupported recording version {}", version)),
+            None => return Err("Missing version".to_string()),
+        }
+        let mut input_map = InputMap::new();
+        input_map.parse(&bindings).map_err(|e| format!("Key bindings, {}", e))?;
+        let missing = |what: &str| format!("Missing {}", what);
+        Ok(InputRecording {
+            header: RecordingHeader {
+                timestep: timestep.ok_or_else(|| missing("timestep"))?,
+                terrain: terrain.ok_or_else(|| missing("terrain"))?,
+                helicopter: helicopter.ok_or_else(|| missing("helicopter"))?,
+                paths: paths.ok_or_else(|| missing("paths"))?,
+                input_map,
+                gamepad_curve,
+            },
+            frames,
+        })
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+        InputRecording::parse(&text).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    // Replaying with other flight paths than the recording was made with would go out of sync
+    // right away, so rather not start
+    pub fn check_paths(&self, paths: &[FlightPath]) -> Result<(), String> {
+        if flight_path::fingerprint(paths) == self.header.paths {
+            Ok(())
+        } else {
+            Err("The recording was made with other flight paths, replay it with the --paths it was recorded with".to_string())
+        }
+    }
+
+    // Every gamepad event in the recording, to play back through a ScriptedSource
+    pub fn gamepad_events(&self) -> Vec<(f32, GamepadEvent)> {
+        self.frames.iter().flat_map(|frame| frame.gamepad.iter().copied()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(paths: &[FlightPath]) -> RecordingHeader {
+        RecordingHeader {
+            timestep      : 1.0 / 120.0,
+            terrain       : "./resources/lunarsurface.obj".to_string(),
+            helicopter    : "./resources/helicopter.obj".to_string(),
+            paths         : flight_path::fingerprint(paths),
+            input_map     : InputMap::new(),
+            gamepad_curve : ResponseCurve::Power(2.5),
+        }
+    }
+
+    #[test]
+    fn replays_only_start_with_the_paths_they_were_recorded_with() {
+        let paths = vec![FlightPath::figure_eight()];
+        let file = std::env::temp_dir().join(format!("gloom-rs-recording-{}.txt", std::process::id()));
+        let file = file.to_string_lossy().into_owned();
+        let mut recorder = InputRecorder::create(&file, &header(&paths)).unwrap();
+        recorder.record(&FrameInput { delta_time: 0.016, ..FrameInput::default() }).unwrap();
+        drop(recorder);
+        let recording = InputRecording::load(&file).unwrap();
+        std::fs::remove_file(&file).unwrap();
+
+        assert_eq!(recording.header.paths, flight_path::fingerprint(&paths));
+        assert_eq!(recording.header.gamepad_curve, ResponseCurve::Power(2.5));
+        assert_eq!(recording.frames.len(), 1);
+        assert!(recording.check_paths(&paths).is_ok());
+        assert!(recording.check_paths(&[]).is_err());
+        let mut faster = paths.clone();
+        faster[0].speed += 1.0;
+        assert!(recording.check_paths(&faster).is_err());
+        let mut later = paths;
+        later[0].start += 1.0;
+        assert!(recording.check_paths(&later).is_err());
+    }
+
+    #[test]
+    fn recordings_without_paths_are_rejected() {
+        let text = "version 1\ntimestep 0.01\nterrain a.obj\nhelicopter b.obj\n";
+        assert_eq!(InputRecording::parse(text).err().unwrap(), "Unsupported recording version 1");
+        let text = "version 2\ntimestep 0.01\nterrain a.obj\nhelicopter b.obj\n";
+        assert_eq!(InputRecording::parse(text).err().unwrap(), "Missing paths");
+    }
+}
@@ -0,0 +1,343 @@
+extern crate nalgebra_glm as glm;
+
+use crate::backend::{Capability, RenderBackend, Uniform};
+use crate::camera::Camera;
+use crate::framebuffer::Framebuffer;
+use crate::light::Lighting;
+use crate::postprocess::PostChain;
+use crate::scene_graph;
+use crate::shader;
+use crate::shadow::ShadowRenderer;
+use crate::util;
+
+// Set up the global OpenGL state and the debug output. Has to be called once the context is
+// current and the function pointers are loaded. The driver's messages go to `debug_sink`.
+pub unsafe fn init_gl(debug_sink: util::DebugSink) -> &'static util::DebugSink {
+    gl::Enable(gl::DEPTH_TEST);
+    gl::DepthFunc(gl::LESS);
+    gl::Enable(gl::CULL_FACE);
+    gl::Enable(gl::BLEND);
+    gl::BlendFunc(gl::SRC_ALPHA, gl::ONE_MINUS_SRC_ALPHA);
+
+    let debug_sink = util::install_debug_sink(debug_sink);
+
+    // Print some diagnostics
+    println!(
+        "{}: {}",
+        util::get_gl_string(gl::VENDOR),
+        util::get_gl_string(gl::RENDERER)
+    );
+    println!("OpenGL\t: {}", util::get_gl_string(gl::VERSION));
+    println!(
+        "GLSL\t: {}",
+        util::get_gl_string(gl::SHADING_LANGUAGE_VERSION)
+    );
+
+    debug_sink
+}
+
+// Draw the node and everything below it, using the uniforms of simple.vert. The node
+// transformations have to be up to date.
+pub fn draw_scene(
+    node: &scene_graph::SceneNode,
+    view_projection_matrix: &glm::Mat4,
+    backend: &mut dyn RenderBackend,
+) {
+    // Check if node is drawable, set uniforms, draw
+    if node.index_count > 0 {
+        backend.bind_vao(node.vao_id);
+        backend.set_uniform("mvp", Uniform::Mat4(view_projection_matrix * node.current_transformation_matrix));
+        backend.set_uniform("model", Uniform::Mat4(node.current_transformation_matrix));
+        backend.draw_elements(node.index_count);
+    }
+
+    // Recurse
+    for &child in &node.children {
+        draw_scene(unsafe { &*child }, view_projection_matrix, backend);
+    }
+}
+
+pub unsafe fn update_node_transformations(
+    node: &mut scene_graph::SceneNode,
+    transformation_so_far: &glm::Mat4,
+) {
+    // Construct the correct transformation matrix
+    let mut trans: glm::Mat4 = glm::identity();
+
+    // trans = glm::inverse(transformation_so_far) * trans;
+    trans = glm::translation(&-node.reference_point) * trans; // move to origin
+    trans = glm::scaling(&node.scale) * trans; // scale
+
+    let mut rotation: glm::Mat4 = glm::identity();
+    rotation = glm::rotation(
+        node.rotation.z,
+        &(rotation * glm::vec4(0.0, 0.0, 1.0, 1.0)).xyz(),
+    ) * rotation; // rotate around z
+    rotation = glm::rotation(
+        node.rotation.y,
+        &(rotation * glm::vec4(0.0, 1.0, 0.0, 1.0)).xyz(),
+    ) * rotation; // rotate around y
+    rotation = glm::rotation(
+        node.rotation.x,
+        &(rotation * glm::vec4(1.0, 0.0, 0.0, 1.0)).xyz(),
+    ) * rotation; // rotate around x
+
+    trans = rotation * trans; // apply rotation
+    trans = glm::translation(&node.reference_point) * trans; // move to back to reference point
+    trans = glm::translation(&node.position) * trans; // move to relative location
+
+    // Update the node's transformation matrix
+    node.current_transformation_matrix = transformation_so_far * trans;
+    // Recurse
+    for &child in &node.children {
+        update_node_transformations(&mut *child, &node.current_transformation_matrix);
+    }
+}
+
+// Owns everything needed to turn a scene into an image: the main shader, the shadow maps, the
+// offscreen targets and the post processing chain. Everything goes through the backend, which is
+// the OpenGL one unless the drawing is being checked.
+pub struct Renderer<B: RenderBackend> {
+    pub backend: B,
+    pub shader: shader::Shader,
+    pub shadows: ShadowRenderer,
+    pub post_chain: PostChain,
+    pub clear_color: [f32; 4],
+    pub width: i32,
+    pub height: i32,
+    scene_target: Framebuffer,
+    resolved_target: Framebuffer,
+}
+
+impl<B: RenderBackend> Renderer<B> {
+    // The shaders are read from `shader_directory`, and `samples` is the MSAA sample count of
+    // the scene target, with 0 for no multisampling
+    pub fn new(mut backend: B, width: i32, height: i32, samples: i32, shader_directory: &str) -> Self {
+        let shader = backend.create_program(&[
+            &shader::shader_path(shader_directory, "simple.vert"),
+            &shader::shader_path(shader_directory, "simple.frag"),
+        ]);
+        backend.set_capability(Capability::Multisampling, samples > 0);
+
+        // The scene is drawn into a multisampled offscreen target, which is resolved and then run
+        // through the post processing chain on its way to the output
+        Renderer {
+            shader,
+            shadows: ShadowRenderer::new(&mut backend, 2048, shader_directory),
+            post_chain: PostChain::new(&mut backend, width, height, shader_directory),
+            clear_color: [0.6, 0.713_725_5, 0.949_019_6, 0.7],
+            width,
+            height,
+            scene_target: Framebuffer::new(&mut backend, width, height, samples),
+            resolved_target: Framebuffer::new(&mut backend, width, height, 0),
+            backend,
+        }
+    }
+
+    // Resize the offscreen targets along with the window
+    pub fn resize(&mut self, width: i32, height: i32) {
+        if width == self.width && height == self.height {
+            return;
+        }
+        self.width = width;
+        self.height = height;
+        self.scene_target.resize(&mut self.backend, width, height);
+        self.resolved_target.resize(&mut self.backend, width, height);
+        self.post_chain.resize(&mut self.backend, width, height);
+    }
+
+    pub fn aspect_ratio(&self) -> f32 {
+        self.width as f32 / self.height as f32
+    }
+
+    // Draw the scene as seen through the camera into `output`, or into the window if there is
+    // none. The node transformations must already be up to date for this frame.
+    pub unsafe fn render(
+        &mut self,
+        root: &scene_graph::SceneNode,
+        lighting: &mut Lighting,
+        camera: &Camera,
+        output: Option<&Framebuffer>,
+    ) {
+        let aspect = self.aspect_ratio();
+        let matrix: glm::Mat4 = camera.projection(aspect) * camera.view();
+        let camera_position = camera.position();
+
+        // Render the shadow maps before drawing the scene as seen from the camera
+        let backend = &mut self.backend;
+        self.shadows.render(backend, lighting, camera, aspect, |backend, light_matrix| {
+            draw_scene(root, light_matrix, backend)
+        });
+
+        self.scene_target.bind(backend);
+        backend.clear(Some(self.clear_color), true);
+
+        // Draw elements
+        backend.use_program(&self.shader);
+        lighting.upload(backend, &camera_position);
+        self.shadows.upload(backend);
+        draw_scene(root, &matrix, backend);
+
+        self.scene_target.resolve_into(backend, &self.resolved_target);
+        self.post_chain.run(backend, &self.resolved_target, output, self.width, self.height);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::Filter;
+    use crate::mock_backend::{Call, MockBackend};
+    use crate::light::Light;
+    use crate::mesh::{Helicopter, Mesh};
+
+    // A mesh with nothing but its index count, which is all the mock backend looks at
+    fn part(index_count: i32) -> Mesh {
+        Mesh { vertices: vec![], normals: vec![], colors: vec![], indices: vec![0; index_count as usize], index_count }
+    }
+
+    fn helicopter_parts() -> Helicopter {
+        Helicopter { body: part(300), door: part(36), main_rotor: part(120), tail_rotor: part(60) }
+    }
+
+    fn assert_close(actual: &glm::Mat4, expected: &glm::Mat4) {
+        let difference = (actual - expected).abs().max();
+        assert!(difference < 1e-4, "{} differs from {} by {}", actual, expected, difference);
+    }
+
+    // A helicopter under the root, turned and with its rotors and door moved, along with the model
+    // matrix every part should be drawn with: body, main rotor, tail rotor and door
+    fn helicopter_scene(backend: &mut MockBackend) -> (scene_graph::Node, [(u32, i32, glm::Mat4); 4]) {
+        let mut root = scene_graph::SceneNode::new();
+        let mut helicopter = crate::build_helicopter(&helicopter_parts(), &mut |mesh| backend.create_vao(mesh));
+        helicopter.position = glm::vec3(5.0, 10.0, -3.0);
+        helicopter.rotation.y = 0.5;
+        helicopter[0].rotation.y = 1.2;
+        helicopter[1].rotation.x = 0.7;
+        helicopter[2].position.z = 0.5;
+        root.add_child(&helicopter);
+
+        let around = |reference: glm::Vec3, rotation: glm::Mat4| {
+            glm::translation(&reference) * rotation * glm::translation(&-reference)
+        };
+        let y = glm::vec3(0.0, 1.0, 0.0);
+        let body = glm::translation(&helicopter.position) * glm::rotation(0.5, &y);
+        let main_rotor = body * around(helicopter[0].reference_point, glm::rotation(1.2, &y));
+        let tail_rotor = body * around(helicopter[1].reference_point, glm::rotation(0.7, &glm::vec3(1.0, 0.0, 0.0)));
+        let door = body * glm::translation(&glm::vec3(0.0, 0.0, 0.5));
+        let expected = [
+            (helicopter.vao_id, 300, body),
+            (helicopter[0].vao_id, 120, main_rotor),
+            (helicopter[1].vao_id, 60, tail_rotor),
+            (helicopter[2].vao_id, 36, door),
+        ];
+        (root, expected)
+    }
+
+    #[test]
+    fn draw_scene_draws_each_helicopter_part_once_with_its_model_matrix() {
+        let mut backend = MockBackend::new();
+        let (mut root, expected) = helicopter_scene(&mut backend);
+        let view_projection = glm::perspective(1.5, 1.0, 0.1, 100.0) * glm::translation(&glm::vec3(0.0, -5.0, -40.0));
+
+        unsafe { update_node_transformations(&mut root, &glm::identity()) };
+        draw_scene(&root, &view_projection, &mut backend);
+
+        assert_eq!(backend.draws().len(), 4);
+        for (vao_id, index_count, model) in &expected {
+            let draws = backend.draws_of(*vao_id);
+            assert_eq!(draws.len(), 1);
+            assert_eq!(draws[0].index_count, *index_count);
+            assert_close(&draws[0].matrix("model").unwrap(), model);
+            assert_close(&draws[0].matrix("mvp").unwrap(), &(view_projection * model));
+        }
+    }
+
+    #[test]
+    fn render_draws_the_scene_into_every_shadow_map_and_the_scene_target() {
+        let mut renderer = Renderer::new(MockBackend::new(), 640, 480, 4, "shaders");
+        let (mut root, expected) = helicopter_scene(&mut renderer.backend);
+        let mut lighting = Lighting::new();
+        lighting.add(Light::directional(glm::vec3(-1.0, -1.0, 0.0)).with_shadows());
+        lighting.add(Light::point(glm::vec3(0.0, 20.0, 0.0)));
+        let spot = Light::spot(glm::vec3(0.0, 0.0, -2.0), glm::vec3(0.0, -1.0, -1.0), 0.3, 0.5);
+        lighting.add(spot.with_shadows().attach_to(&root[0]));
+        let camera = Camera::new();
+        // The sun's cascades, then the spot lights
+        let shadow_maps: Vec<u32> = renderer
+            .backend
+            .calls
+            .iter()
+            .filter_map(|call| match call {
+                Call::CreateShadowMap { framebuffer_id, .. } => Some(*framebuffer_id),
+                _ => None,
+            })
+            .collect();
+        renderer.backend.calls.clear();
+
+        unsafe {
+            update_node_transformations(&mut root, &glm::identity());
+            renderer.render(&root, &mut lighting, &camera, None);
+        }
+
+        let shader_id = renderer.shader.program_id;
+        let scene_target = renderer.scene_target.id;
+        let backend = &renderer.backend;
+        let scene_draws = backend.draws_into(scene_target);
+        assert_eq!(scene_draws.len(), 4);
+        // Every cascade of the sun, and the one layer of the spot light
+        assert_eq!(backend.draws_into(shadow_maps[0]).len(), 4 * crate::shadow::MAX_CASCADES);
+        assert_eq!(backend.draws_into(shadow_maps[1]).len(), 4);
+
+        let view_projection = camera.projection(renderer.aspect_ratio()) * camera.view();
+        for (vao_id, _, model) in &expected {
+            let draws: Vec<_> = scene_draws.iter().filter(|draw| draw.vao_id == *vao_id).collect();
+            assert_eq!(draws.len(), 1);
+            assert_eq!(draws[0].program_id, shader_id);
+            assert_close(&draws[0].matrix("model").unwrap(), model);
+            assert_close(&draws[0].matrix("mvp").unwrap(), &(view_projection * model));
+        }
+
+        // The lights and shadows are set up on the main program before it draws
+        let uniforms = &scene_draws[0].uniforms;
+        assert_eq!(uniforms["lightCount"], Uniform::Int(3));
+        assert_eq!(uniforms["lights[0].shadowLayer"], Uniform::Int(0));
+        assert_eq!(uniforms["lights[1].shadowLayer"], Uniform::Int(-1));
+        assert_eq!(uniforms["lights[2].shadowLayer"], Uniform::Int(0));
+        assert_eq!(uniforms["cascadeCount"], Uniform::Int(crate::shadow::MAX_CASCADES as i32));
+        let clear = Call::Clear { color: Some(renderer.clear_color), depth: true };
+        let bind = Call::BindFramebuffer { framebuffer_id: scene_target, width: 640, height: 480 };
+        let bound = backend.calls.iter().position(|call| *call == bind).unwrap();
+        assert_eq!(backend.calls[bound + 1], clear);
+
+        // Only the anti-aliasing pass is on, drawing the resolved image into the window
+        let window_draws = backend.draws_into(0);
+        assert_eq!(window_draws.len(), 1);
+        assert!(!window_draws[0].indexed);
+        assert_eq!(window_draws[0].textures[&0], renderer.resolved_target.color_texture);
+    }
+
+    #[test]
+    fn render_without_post_effects_copies_the_resolved_image_into_the_output() {
+        let mut renderer = Renderer::new(MockBackend::new(), 320, 200, 0, "shaders");
+        let output = Framebuffer::new(&mut renderer.backend, 320, 200, 0);
+        let (mut root, _) = helicopter_scene(&mut renderer.backend);
+        for pass in renderer.post_chain.passes.iter_mut() {
+            pass.enabled = false;
+        }
+        renderer.backend.calls.clear();
+
+        unsafe {
+            update_node_transformations(&mut root, &glm::identity());
+            renderer.render(&root, &mut Lighting::new(), &Camera::new(), Some(&output));
+        }
+
+        let blits: Vec<&Call> = renderer.backend.calls.iter().filter(|call| matches!(call, Call::Blit { .. })).collect();
+        assert_eq!(blits.len(), 2);
+        assert_eq!(
+            *blits[1],
+            Call::Blit { source_id: renderer.resolved_target.id, target_id: output.id, filter: Filter::Linear }
+        );
+        assert!(renderer.backend.draws().iter().all(|draw| draw.indexed));
+    }
+}
@@ -1,6 +1,11 @@
 use gl;
 use std::{ffi::CString, path::Path, ptr, str};
 
+// The path of a shader file in the given shader directory
+pub fn shader_path(directory: &str, name: &str) -> String {
+    Path::new(directory).join(name).to_string_lossy().into_owned()
+}
+
 pub struct Shader {
     pub program_id: u32,
 }
@@ -21,6 +26,7 @@ pub enum ShaderType {
 
 impl Shader {
     // Make sure the shader is active before calling this
+    #[allow(dead_code)]
     pub unsafe fn get_uniform_location(&self, name: &str) -> i32 {
         let name_cstr = CString::new(name).expect("CString::new failed");
         gl::GetUniformLocation(self.program_id, name_cstr.as_ptr())
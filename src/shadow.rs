@@ -0,0 +1,215 @@
+extern crate nalgebra_glm as glm;
+
+use crate::backend::{Capability, RenderBackend, TextureKind, Uniform};
+use crate::camera::Camera;
+use crate::light::{LightKind, Lighting};
+use crate::shader::{shader_path, Shader};
+
+// Have to match MAX_CASCADES and MAX_SPOT_SHADOWS in simple.frag
+pub const MAX_CASCADES: usize = 4;
+pub const MAX_SPOT_SHADOWS: usize = 4;
+
+// Texture units the shadow maps are bound to while drawing the main pass
+const SUN_SHADOW_UNIT: u32 = 1;
+const SPOT_SHADOW_UNIT: u32 = 2;
+
+// A layered depth texture along with the framebuffer used to render into it
+pub struct ShadowMap {
+    pub framebuffer_id: u32,
+    pub texture_id: u32,
+    pub size: i32,
+    pub layers: usize,
+}
+
+impl ShadowMap {
+    pub fn new(backend: &mut dyn RenderBackend, size: i32, layers: usize) -> Self {
+        backend.create_shadow_map(size, layers)
+    }
+
+    // Bind the framebuffer with the given layer as the depth attachment and clear it
+    fn begin_layer(&self, backend: &mut dyn RenderBackend, layer: usize) {
+        backend.bind_framebuffer(self.framebuffer_id, self.size, self.size);
+        backend.attach_shadow_layer(self, layer);
+        backend.clear(None, true);
+    }
+}
+
+// Renders depth maps for every shadow casting light. The directional sun gets a set of cascades
+// covering increasingly large slices of the view frustum, and each spot light gets one layer.
+pub struct ShadowRenderer {
+    pub cascade_count: usize,
+    pub shadow_distance: f32, // How far from the camera the cascades reach
+    pub split_lambda: f32,    // Blend between uniform (0.0) and logarithmic (1.0) cascade splits
+    pub pcf_radius: i32,      // Filter kernel is (2r + 1)^2 samples
+    pub spot_range: f32,      // Far plane of the spot light shadow frustums
+
+    shader: Shader,
+    sun_map: ShadowMap,
+    spot_map: ShadowMap,
+    cascade_matrices: Vec<glm::Mat4>,
+    spot_matrices: Vec<glm::Mat4>,
+}
+
+impl ShadowRenderer {
+    pub fn new(backend: &mut dyn RenderBackend, resolution: i32, shader_directory: &str) -> Self {
+        let shader = backend.create_program(&[
+            &shader_path(shader_directory, "shadow.vert"),
+            &shader_path(shader_directory, "shadow.frag"),
+        ]);
+
+        ShadowRenderer {
+            cascade_count: MAX_CASCADES,
+            shadow_distance: 400.0,
+            split_lambda: 0.75,
+            pcf_radius: 1,
+            spot_range: 300.0,
+            shader,
+            sun_map: ShadowMap::new(backend, resolution, MAX_CASCADES),
+            spot_map: ShadowMap::new(backend, resolution, MAX_SPOT_SHADOWS),
+            cascade_matrices: vec![],
+            spot_matrices: vec![],
+        }
+    }
+
+    // View space distances at which each cascade ends
+    fn cascade_splits(&self, near: f32) -> Vec<f32> {
+        let far = self.shadow_distance;
+        let count = self.cascade_count.clamp(1, self.sun_map.layers);
+        (1..=count)
+            .map(|i| {
+                let fraction = i as f32 / count as f32;
+                let logarithmic = near * (far / near).powf(fraction);
+                let uniform = near + (far - near) * fraction;
+                self.split_lambda * logarithmic + (1.0 - self.split_lambda) * uniform
+            })
+            .collect()
+    }
+
+    // Fit an orthographic projection around a slice of the view frustum, as seen from the sun
+    fn cascade_matrix(
+        &self,
+        direction: &glm::Vec3,
+        view: &glm::Mat4,
+        fovy: f32,
+        aspect: f32,
+        near: f32,
+        far: f32,
+    ) -> glm::Mat4 {
+        let inverse = glm::inverse(&(glm::perspective(aspect, fovy, near, far) * view));
+        let mut corners = Vec::with_capacity(8);
+        for &x in &[-1.0, 1.0] {
+            for &y in &[-1.0, 1.0] {
+                for &z in &[-1.0, 1.0] {
+                    let corner = inverse * glm::vec4(x, y, z, 1.0);
+                    corners.push(corner.xyz() / corner.w);
+                }
+            }
+        }
+        let center = corners.iter().fold(glm::Vec3::zeros(), |sum, c| sum + c) / 8.0;
+        // Using the bounding sphere keeps the size constant while the camera rotates
+        let radius = corners.iter().map(|c| glm::distance(c, &center)).fold(0.0, f32::max).ceil();
+
+        // Leave room behind the slice for casters outside of the view, like the helicopters above
+        let caster_margin = self.shadow_distance;
+        let eye = center - direction * (radius + caster_margin);
+        let light_view = glm::look_at(&eye, &center, &up_vector(direction));
+        let projection = glm::ortho(-radius, radius, -radius, radius, 0.0, 2.0 * radius + caster_margin);
+        let mut matrix = projection * light_view;
+
+        // Snap to whole texels so the shadows don't shimmer when the camera moves
+        let texels = self.sun_map.size as f32 / 2.0;
+        let origin = matrix * glm::vec4(0.0, 0.0, 0.0, 1.0) * texels;
+        matrix[(0, 3)] += (origin.x.round() - origin.x) / texels;
+        matrix[(1, 3)] += (origin.y.round() - origin.y) / texels;
+        matrix
+    }
+
+    // Render the shadow maps for the current frame, with the cascades fit to the camera's view at
+    // the given aspect ratio. `draw` should draw the whole scene with the given view projection
+    // matrix, and the node transformations have to be up to date. Leaves the last shadow map
+    // bound, so bind the target to draw into next afterwards.
+    pub fn render<F>(
+        &mut self,
+        backend: &mut dyn RenderBackend,
+        lighting: &mut Lighting,
+        camera: &Camera,
+        aspect: f32,
+        mut draw: F,
+    ) where
+        F: FnMut(&mut dyn RenderBackend, &glm::Mat4),
+    {
+        let (view, fovy, near) = (camera.view(), camera.field_of_view, camera.near);
+        backend.use_program(&self.shader);
+        backend.set_capability(Capability::FaceCulling, false);
+        backend.set_capability(Capability::DepthBias, true);
+        backend.set_depth_bias(2.0, 4.0);
+
+        self.cascade_matrices.clear();
+        self.spot_matrices.clear();
+        let mut has_sun = false;
+
+        for light in lighting.lights.iter_mut() {
+            light.shadow_layer = None;
+            if !light.enabled || !light.casts_shadows {
+                continue;
+            }
+            match light.kind {
+                LightKind::Directional if !has_sun => {
+                    has_sun = true;
+                    light.shadow_layer = Some(0);
+                    let direction = light.world_direction();
+                    let mut slice_near = near;
+                    for (layer, split) in self.cascade_splits(near).into_iter().enumerate() {
+                        let matrix = self.cascade_matrix(&direction, &view, fovy, aspect, slice_near, split);
+                        self.sun_map.begin_layer(backend, layer);
+                        draw(backend, &matrix);
+                        self.cascade_matrices.push(matrix);
+                        slice_near = split;
+                    }
+                }
+                LightKind::Spot { outer_angle, .. } if self.spot_matrices.len() < self.spot_map.layers => {
+                    let layer = self.spot_matrices.len();
+                    light.shadow_layer = Some(layer);
+                    let position = light.world_position();
+                    let direction = light.world_direction();
+                    let light_view = glm::look_at(&position, &(position + direction), &up_vector(&direction));
+                    let projection = glm::perspective(1.0, 2.0 * outer_angle, 0.5, self.spot_range);
+                    let matrix = projection * light_view;
+                    self.spot_map.begin_layer(backend, layer);
+                    draw(backend, &matrix);
+                    self.spot_matrices.push(matrix);
+                }
+                _ => {} // Point lights and extra suns don't cast shadows
+            }
+        }
+
+        backend.set_capability(Capability::DepthBias, false);
+        backend.set_capability(Capability::FaceCulling, true);
+    }
+
+    // Bind the shadow maps and upload the light space matrices to the program in use
+    pub fn upload(&self, backend: &mut dyn RenderBackend) {
+        backend.bind_texture(SUN_SHADOW_UNIT, TextureKind::Texture2dArray, self.sun_map.texture_id);
+        backend.bind_texture(SPOT_SHADOW_UNIT, TextureKind::Texture2dArray, self.spot_map.texture_id);
+
+        backend.set_uniform("sunShadowMap", Uniform::Int(SUN_SHADOW_UNIT as i32));
+        backend.set_uniform("spotShadowMap", Uniform::Int(SPOT_SHADOW_UNIT as i32));
+        backend.set_uniform("cascadeCount", Uniform::Int(self.cascade_matrices.len() as i32));
+        backend.set_uniform("pcfRadius", Uniform::Int(self.pcf_radius));
+        for (i, matrix) in self.cascade_matrices.iter().enumerate() {
+            backend.set_uniform(&format!("cascadeMatrices[{}]", i), Uniform::Mat4(*matrix));
+        }
+        for (i, matrix) in self.spot_matrices.iter().enumerate() {
+            backend.set_uniform(&format!("spotShadowMatrices[{}]", i), Uniform::Mat4(*matrix));
+        }
+    }
+}
+
+// Any vector that isn't parallel to the given direction, for building look-at matrices
+fn up_vector(direction: &glm::Vec3) -> glm::Vec3 {
+    if direction.y.abs() > 0.99 {
+        glm::vec3(0.0, 0.0, 1.0)
+    } else {
+        glm::vec3(0.0, 1.0, 0.0)
+    }
+}
@@ -0,0 +1,223 @@
+extern crate nalgebra_glm as glm;
+
+use crate::animation;
+use crate::scene_graph::SceneNode;
+
+// The simulation always moves forward in steps of this many seconds, however long the frames are
+pub const TIMESTEP: f32 = 1.0 / 120.0;
+
+// Turns the varying frame times into a whole number of fixed simulation steps. The time left over
+// is carried into the next frame, and tells how far the rendered frame is between two steps.
+pub struct FixedTimestep {
+    pub timestep       : f32,
+    pub max_frame_time : f32, // Longer frames are cut short, so a stall doesn't take many frames to catch up on
+    pub steps          : u64, // Steps taken so far
+
+    accumulator: f64,
+}
+
+impl FixedTimestep {
+    pub fn new(timestep: f32) -> Self {
+        FixedTimestep {
+            timestep,
+            max_frame_time : 0.25,
+            steps          : 0,
+            accumulator    : 0.0,
+        }
+    }
+
+    // Add the duration of a frame to be simulated
+    pub fn accumulate(&mut self, frame_time: f32) {
+        self.accumulator += frame_time.clamp(0.0, self.max_frame_time) as f64;
+    }
+
+    // Take a step if a whole one has accumulated. Call until it returns false, and update the
+    // simulation to `time()` every time it returns true.
+    pub fn step(&mut self) -> bool {
+        if self.accumulator < self.timestep as f64 {
+            return false;
+        }
+        self.accumulator -= self.timestep as f64;
+        self.steps += 1;
+        true
+    }
+
+    // Simulated seconds after the last step. Counted in steps, so it doesn't drift in long runs.
+    pub fn time(&self) -> f32 {
+        (self.steps as f64 * self.timestep as f64) as f32
+    }
+
+    // How far the time left over is into the next step, from 0 to 1
+    pub fn alpha(&self) -> f32 {
+        (self.accumulator / self.timestep as f64) as f32
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct NodeState {
+    position : glm::Vec3,
+    rotation : glm::Vec3,
+    scale    : glm::Vec3,
+}
+
+// The position, rotation and scale of every node in a scene graph, in depth first order. Taken
+// before and after a simulation step, so frames can be drawn somewhere in between.
+#[derive(Clone, Debug)]
+pub struct SceneSnapshot {
+    states: Vec<NodeState>,
+}
+
+impl SceneSnapshot {
+    pub fn capture(root: &SceneNode) -> Self {
+        let mut states = vec![];
+        visit(root, &mut |node| {
+            states.push(NodeState {
+                position : node.position,
+                rotation : node.rotation,
+                scale    : node.scale,
+            })
+        });
+        SceneSnapshot { states }
+    }
+
+    // Put every node back the way it was. The graph must not have changed since the capture.
+    pub fn apply(&self, root: &mut SceneNode) {
+        let mut states = self.states.iter();
+        visit_mut(root, &mut |node| {
+            let state = states.next().expect("Scene graph changed since the snapshot");
+            node.position = state.position;
+            node.rotation = state.rotation;
+            node.scale = state.scale;
+        });
+    }
+
+    // The scene `alpha` of the way from this snapshot to the next one. Rotations turn along the
+    // shortest arc, as the same orientation can have very different angles, e.g. headings wrap
+    // from pi to -pi.
+    pub fn interpolate(&self, next: &SceneSnapshot, alpha: f32) -> SceneSnapshot {
+        let states = self
+            .states
+            .iter()
+            .zip(&next.states)
+            .map(|(from, to)| NodeState {
+                position : glm::lerp(&from.position, &to.position, alpha),
+                rotation : interpolate_rotation(&from.rotation, &to.rotation, alpha),
+                scale    : glm::lerp(&from.scale, &to.scale, alpha),
+            })
+            .collect();
+        SceneSnapshot { states }
+    }
+}
+
+// Move the scene graph `alpha` of the way from the previous step to where it is now, for drawing.
+// Returns where it is now, to be applied again once the frame is drawn.
+pub fn interpolate_scene(root: &mut SceneNode, previous: &SceneSnapshot, alpha: f32) -> SceneSnapshot {
+    let current = SceneSnapshot::capture(root);
+    previous.interpolate(&current, alpha).apply(root);
+    current
+}
+
+fn interpolate_rotation(from: &glm::Vec3, to: &glm::Vec3, alpha: f32) -> glm::Vec3 {
+    if from == to {
+        return *to;
+    }
+    let rotation = glm::quat_slerp(&animation::euler_to_quat(from), &animation::euler_to_quat(to), alpha);
+    animation::quat_to_euler(&rotation)
+}
+
+fn visit(node: &SceneNode, f: &mut dyn FnMut(&SceneNode)) {
+    f(node);
+    for &child in &node.children {
+        visit(unsafe { &*child }, f);
+    }
+}
+
+fn visit_mut(node: &mut SceneNode, f: &mut dyn FnMut(&mut SceneNode)) {
+    f(node);
+    for &child in &node.children {
+        visit_mut(unsafe { &mut *child }, f);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Steps taken over frames of the given lengths, and how far into the next step it ends
+    fn run(frame_times: &[f32]) -> (u64, f32) {
+        let mut timestep = FixedTimestep::new(TIMESTEP);
+        for &frame_time in frame_times {
+            timestep.accumulate(frame_time);
+            while timestep.step() {}
+        }
+        (timestep.steps, timestep.alpha())
+    }
+
+    #[test]
+    fn frame_times_adding_up_to_the_same_take_the_same_steps() {
+        // 24.6 steps, well away from a step boundary, however the sums round
+        let (steps, alpha) = run(&[0.205]);
+        assert_eq!(steps, 24);
+        for frames in &[vec![0.1025, 0.1025], vec![0.041; 5], vec![0.001, 0.2, 0.004], vec![0.017, 0.033, 0.005, 0.15]] {
+            let (split_steps, split_alpha) = run(frames);
+            assert_eq!(split_steps, steps, "{:?}", frames);
+            assert!((split_alpha - alpha).abs() < 1e-3, "{:?}", frames);
+        }
+    }
+
+    #[test]
+    fn long_frames_are_cut_short() {
+        let mut stalled = FixedTimestep::new(TIMESTEP);
+        stalled.accumulate(3.0);
+        let mut longest = FixedTimestep::new(TIMESTEP);
+        longest.accumulate(stalled.max_frame_time);
+        while stalled.step() {
+            assert!(longest.step());
+        }
+        assert!(!longest.step());
+        assert_eq!(stalled.steps, (0.25 / TIMESTEP) as u64);
+        assert_eq!(stalled.alpha(), longest.alpha());
+
+        // Time doesn't go backwards either
+        stalled.accumulate(-1.0);
+        assert!(!stalled.step());
+    }
+
+    #[test]
+    fn alpha_stays_between_0_and_1() {
+        let mut timestep = FixedTimestep::new(TIMESTEP);
+        let mut frame_time = 0.0;
+        for _ in 0..1000 {
+            // Frame times all over the place, from none at all to more than the longest allowed
+            frame_time = (frame_time + 0.0137) % 0.3;
+            timestep.accumulate(frame_time);
+            while timestep.step() {}
+            let alpha = timestep.alpha();
+            assert!((0.0..1.0).contains(&alpha), "alpha {} after {} steps", alpha, timestep.steps);
+        }
+    }
+
+    #[test]
+    fn scenes_are_drawn_between_the_last_two_steps() {
+        let mut root = SceneNode::new();
+        let mut child = SceneNode::new();
+        root.add_child(&child);
+
+        child.position = glm::vec3(0.0, 0.0, 0.0);
+        child.rotation = glm::vec3(0.0, 3.0, 0.0);
+        let previous = SceneSnapshot::capture(&root);
+        child.position = glm::vec3(2.0, 4.0, -8.0);
+        child.rotation = glm::vec3(0.0, -3.0, 0.0);
+
+        let current = interpolate_scene(&mut root, &previous, 0.25);
+        assert_eq!(child.position, glm::vec3(0.5, 1.0, -2.0));
+        // From 3 to -3 is the short way round through pi, not back through 0
+        let heading = glm::quat_angle(&animation::euler_to_quat(&child.rotation));
+        let expected = glm::quat_angle(&animation::euler_to_quat(&glm::vec3(0.0, 3.0 + 0.25 * (std::f32::consts::TAU - 6.0), 0.0)));
+        assert!((heading - expected).abs() < 1e-4, "{} turned to {}", child.rotation, heading);
+
+        current.apply(&mut root);
+        assert_eq!(child.position, glm::vec3(2.0, 4.0, -8.0));
+        assert_eq!(child.rotation, glm::vec3(0.0, -3.0, 0.0));
+    }
+}
@@ -0,0 +1,133 @@
+extern crate nalgebra_glm as glm;
+
+// Keeps the AI helicopters out of each other's way. Each is steered away from anything closer
+// than `radius`, as an offset from where its path puts it, and springs back onto the path once
+// the way is clear.
+pub struct Separation {
+    pub radius     : f32, // How close others can come before they're steered away from
+    pub strength   : f32, // Acceleration away from something right on top, none at the radius
+    pub stiffness  : f32, // How hard the offset is pulled back to nothing
+    pub damping    : f32,
+    pub max_offset : f32, // The furthest a helicopter is taken from its path
+
+    offsets: Vec<glm::Vec3>,
+    velocities: Vec<glm::Vec3>,
+}
+
+impl Separation {
+    pub fn new(count: usize) -> Self {
+        Separation {
+            radius     : 25.0,
+            strength   : 40.0,
+            stiffness  : 1.0,
+            damping    : 2.0, // Critically damped, so it settles back without swinging past
+            max_offset : 15.0,
+            offsets: vec![glm::zero(); count],
+            velocities: vec![glm::zero(); count],
+        }
+    }
+
+    // Where the helicopter is steered to, relative to where its path puts it
+    pub fn offset(&self, index: usize) -> glm::Vec3 {
+        self.offsets[index]
+    }
+
+    // Move the offsets one step further. `positions` are where the steered helicopters are now,
+    // and `obstacles` anything else they should keep away from. While disabled nothing pushes
+    // them apart, and they ease back onto their paths.
+    pub fn step(&mut self, positions: &[glm::Vec3], obstacles: &[glm::Vec3], enabled: bool, timestep: f32) {
+        for (i, position) in positions.iter().enumerate() {
+            let mut acceleration = -self.offsets[i] * self.stiffness - self.velocities[i] * self.damping;
+            if enabled {
+                let others = positions.iter().enumerate().filter(|&(j, _)| j != i).map(|(_, other)| other);
+                for other in others.chain(obstacles) {
+                    acceleration += self.push(position, other, i);
+                }
+            }
+            self.velocities[i] += acceleration * timestep;
+            self.offsets[i] += self.velocities[i] * timestep;
+            if glm::length(&self.offsets[i]) > self.max_offset {
+                self.offsets[i] = glm::normalize(&self.offsets[i]) * self.max_offset;
+                self.velocities[i] = glm::zero();
+            }
+        }
+    }
+
+    // The acceleration away from something at `other`, stronger the closer it is
+    fn push(&self, position: &glm::Vec3, other: &glm::Vec3, index: usize) -> glm::Vec3 {
+        push_apart(position, other, index, self.radius, self.strength)
+    }
+}
+
+// The acceleration away from something at `other`, from nothing at the radius up to `strength`
+// right on top of it. `index` tells apart the two of a pair, so they're pushed opposite ways.
+pub fn push_apart(position: &glm::Vec3, other: &glm::Vec3, index: usize, radius: f32, strength: f32) -> glm::Vec3 {
+    let away = position - other;
+    let distance = glm::length(&away);
+    if distance >= radius {
+        return glm::zero();
+    }
+    // Two right on top of each other have no way apart, so split them up and down
+    let direction = if distance > 1e-4 {
+        away / distance
+    } else if index % 2 == 0 {
+        glm::vec3(0.0, 1.0, 0.0)
+    } else {
+        glm::vec3(0.0, -1.0, 0.0)
+    };
+    direction * strength * (1.0 - distance / radius)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pushes_are_equal_and_opposite() {
+        let points = [glm::vec3(0.0, 10.0, 0.0), glm::vec3(3.0, 12.0, -4.0), glm::vec3(-1.0, 9.0, 20.0)];
+        for (i, a) in points.iter().enumerate() {
+            for (j, b) in points.iter().enumerate().filter(|&(j, _)| j != i) {
+                let push = push_apart(a, b, i, 25.0, 40.0);
+                assert!(glm::distance(&push, &-push_apart(b, a, j, 25.0, 40.0)) < 1e-5);
+                // Straight away from the other
+                assert!(glm::dot(&push, &(a - b)) > 0.0);
+                assert!(glm::length(&glm::cross(&push, &(a - b))) < 1e-3);
+            }
+        }
+        // Even right on top of each other
+        let same = glm::vec3(5.0, 5.0, 5.0);
+        assert_eq!(push_apart(&same, &same, 0, 25.0, 40.0), -push_apart(&same, &same, 1, 25.0, 40.0));
+        assert_eq!(glm::length(&push_apart(&same, &same, 0, 25.0, 40.0)), 40.0);
+    }
+
+    #[test]
+    fn nothing_pushes_from_the_radius_out() {
+        let origin = glm::vec3(0.0, 0.0, 0.0);
+        for &distance in &[25.0, 25.001, 30.0, 1000.0] {
+            assert_eq!(push_apart(&origin, &glm::vec3(0.0, 0.0, distance), 0, 25.0, 40.0), glm::Vec3::zeros());
+        }
+        // And less the further out
+        let near = glm::length(&push_apart(&origin, &glm::vec3(5.0, 0.0, 0.0), 0, 25.0, 40.0));
+        let far = glm::length(&push_apart(&origin, &glm::vec3(20.0, 0.0, 0.0), 0, 25.0, 40.0));
+        assert!((near - 32.0).abs() < 1e-4 && (far - 8.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn helicopters_are_steered_apart_evenly_and_back_once_clear() {
+        let mut separation = Separation::new(3);
+        let positions = [glm::vec3(0.0, 50.0, 0.0), glm::vec3(10.0, 50.0, 0.0), glm::vec3(200.0, 50.0, 0.0)];
+        for _ in 0..60 {
+            separation.step(&positions, &[], true, 1.0 / 60.0);
+        }
+        let (first, second) = (separation.offset(0), separation.offset(1));
+        assert!(first.x < 0.0 && second.x > 0.0);
+        assert!(glm::distance(&first, &-second) < 1e-5);
+        // The third is too far from either to be steered at all
+        assert_eq!(separation.offset(2), glm::Vec3::zeros());
+
+        for _ in 0..600 {
+            separation.step(&positions, &[], false, 1.0 / 60.0);
+        }
+        assert!(glm::length(&separation.offset(0)) < 0.05 && glm::length(&separation.offset(1)) < 0.05);
+    }
+}
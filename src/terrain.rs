@@ -0,0 +1,249 @@
+extern crate nalgebra_glm as glm;
+
+use crate::bvh::Bvh;
+
+// Where a ray or a vertical line meets the terrain
+#[derive(Clone, Copy, Debug)]
+pub struct TerrainHit {
+    pub distance : f32, // Along the ray, in units of its direction
+    pub point    : glm::Vec3,
+    pub normal   : glm::Vec3, // Facing up
+}
+
+// The triangles of the terrain sorted into a grid of columns seen from above, so the ground
+// under a point is found by testing the few triangles in the column over it. Rays go through the
+// bounding volume hierarchy of the terrain instead, which the grid shares its triangles with.
+// The terrain is drawn untransformed, so its vertices are in world space.
+pub struct TerrainIndex {
+    min       : glm::Vec2, // Corner of the grid, in x and z
+    cell_size : f32,
+    columns   : usize,
+    rows      : usize,
+
+    bvh: Bvh,
+    cell_start: Vec<u32>, // Where the triangles of each cell start in `cell_triangles`, and one past the end
+    cell_triangles: Vec<u32>,
+}
+
+impl TerrainIndex {
+    pub fn new(bvh: Bvh) -> Self {
+        let bounds = bvh.bounds();
+        let (mut min, mut max) = (bounds.min.xz(), bounds.max.xz());
+        if bvh.triangle_count() == 0 {
+            min = glm::zero();
+            max = glm::zero();
+        }
+
+        // Around two triangles to a cell, if they were spread out evenly
+        let size = (max - min).max().max(1e-3);
+        let cell_size = size / ((bvh.triangle_count() as f32 / 2.0).sqrt().ceil().max(1.0));
+        let columns = (((max.x - min.x) / cell_size).ceil() as usize).max(1);
+        let rows = (((max.y - min.y) / cell_size).ceil() as usize).max(1);
+
+        let mut index = TerrainIndex {
+            min,
+            cell_size,
+            columns,
+            rows,
+            cell_start: vec![0; columns * rows + 1],
+            cell_triangles: vec![],
+            bvh,
+        };
+
+        // Count the triangles overlapping each cell, then fill them in
+        let cell_ranges: Vec<_> = (0..index.bvh.triangle_count() as u32)
+            .map(|triangle| index.cell_range(index.bvh.triangle(triangle)))
+            .collect();
+        for &(first, last) in &cell_ranges {
+            for row in first.1..=last.1 {
+                for column in first.0..=last.0 {
+                    index.cell_start[row * columns + column + 1] += 1;
+                }
+            }
+        }
+        for cell in 0..columns * rows {
+            index.cell_start[cell + 1] += index.cell_start[cell];
+        }
+        let mut filled = index.cell_start.clone();
+        index.cell_triangles = vec![0; *index.cell_start.last().unwrap() as usize];
+        for (triangle, &(first, last)) in cell_ranges.iter().enumerate() {
+            for row in first.1..=last.1 {
+                for column in first.0..=last.0 {
+                    let cell = row * columns + column;
+                    index.cell_triangles[filled[cell] as usize] = triangle as u32;
+                    filled[cell] += 1;
+                }
+            }
+        }
+        index
+    }
+
+    // The cells a triangle overlaps seen from above, as the first and last column and row
+    fn cell_range(&self, triangle: &[glm::Vec3; 3]) -> ((usize, usize), (usize, usize)) {
+        let min = glm::min2(&glm::min2(&triangle[0].xz(), &triangle[1].xz()), &triangle[2].xz());
+        let max = glm::max2(&glm::max2(&triangle[0].xz(), &triangle[1].xz()), &triangle[2].xz());
+        (self.cell(&min), self.cell(&max))
+    }
+
+    // The column and row of the cell over the point, clamped to the grid
+    fn cell(&self, point: &glm::Vec2) -> (usize, usize) {
+        let cell = (point - self.min) / self.cell_size;
+        (
+            (cell.x.max(0.0) as usize).min(self.columns - 1),
+            (cell.y.max(0.0) as usize).min(self.rows - 1),
+        )
+    }
+
+    fn cell_triangles(&self, column: usize, row: usize) -> impl Iterator<Item = &[glm::Vec3; 3]> {
+        let cell = row * self.columns + column;
+        let range = self.cell_start[cell] as usize..self.cell_start[cell + 1] as usize;
+        self.cell_triangles[range].iter().map(move |&triangle| self.bvh.triangle(triangle))
+    }
+
+    // The highest ground straight above or below the point, None off the edge of the terrain
+    pub fn ground_at(&self, x: f32, z: f32) -> Option<TerrainHit> {
+        let point = glm::vec2(x, z);
+        let (column, row) = self.cell(&point);
+        let mut ground: Option<TerrainHit> = None;
+        for triangle in self.cell_triangles(column, row) {
+            let [a, b, c] = [triangle[0].xz(), triangle[1].xz(), triangle[2].xz()];
+            // Barycentric coordinates of the point in the triangle seen from above
+            let area = (b - a).perp(&(c - a));
+            if area.abs() < 1e-12 {
+                continue;
+            }
+            let u = (c - b).perp(&(point - b)) / area;
+            let v = (a - c).perp(&(point - c)) / area;
+            let w = 1.0 - u - v;
+            if u < -1e-6 || v < -1e-6 || w < -1e-6 {
+                continue;
+            }
+            let height = u * triangle[0].y + v * triangle[1].y + w * triangle[2].y;
+            if ground.map_or(true, |ground| height > ground.point.y) {
+                let normal = glm::normalize(&glm::cross(&(triangle[1] - triangle[0]), &(triangle[2] - triangle[0])));
+                ground = Some(TerrainHit {
+                    distance : 0.0,
+                    point    : glm::vec3(x, height, z),
+                    normal   : if normal.y < 0.0 { -normal } else { normal },
+                });
+            }
+        }
+        ground
+    }
+
+    pub fn height_at(&self, x: f32, z: f32) -> Option<f32> {
+        self.ground_at(x, z).map(|ground| ground.point.y)
+    }
+
+    // The first place the ray hits the terrain within `max_distance` units of its direction
+    pub fn raycast(&self, origin: &glm::Vec3, direction: &glm::Vec3, max_distance: f32) -> Option<TerrainHit> {
+        self.bvh.raycast(origin, direction, max_distance).map(|hit| TerrainHit {
+            distance : hit.distance,
+            point    : hit.point,
+            normal   : if hit.normal.y < 0.0 { -hit.normal } else { hit.normal },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh::Mesh;
+
+    // Hills of two triangles per square, skewed so the triangles don't line up with the cells,
+    // and a ledge hanging over them
+    fn test_terrain() -> TerrainIndex {
+        let (mut vertices, mut indices) = (vec![], vec![]);
+        let size = 16;
+        for z in 0..=size {
+            for x in 0..=size {
+                let (x, z) = (x as f32 * 5.0 - 40.0 + z as f32 * 0.7, z as f32 * 5.0 - 40.0);
+                vertices.extend_from_slice(&[x, (x * 0.13).sin() * 6.0 + (z * 0.05).cos() * 4.0, z]);
+            }
+        }
+        for z in 0..size {
+            for x in 0..size {
+                let corner = z * (size + 1) + x;
+                let (a, b, c, d) = (corner, corner + 1, corner + size + 1, corner + size + 2);
+                indices.extend_from_slice(&[a, c, b, b, c, d]);
+            }
+        }
+        let first = (vertices.len() / 3) as u32;
+        vertices.extend_from_slice(&[-10.0, 20.0, -10.0, 12.0, 22.0, -10.0, -10.0, 20.0, 7.0, 12.0, 22.0, 7.0]);
+        indices.extend_from_slice(&[first, first + 2, first + 1, first + 1, first + 2, first + 3]);
+
+        let index_count = indices.len() as i32;
+        let mesh = Mesh { normals: vec![0.0; vertices.len()], colors: vec![], vertices, indices, index_count };
+        TerrainIndex::new(Bvh::build(&mesh))
+    }
+
+    // The highest ground over the point, by testing every triangle of the terrain
+    fn brute_force_height(terrain: &TerrainIndex, x: f32, z: f32) -> Option<f32> {
+        let point = glm::vec2(x, z);
+        let mut highest: Option<f32> = None;
+        for i in 0..terrain.bvh.triangle_count() as u32 {
+            let triangle = terrain.bvh.triangle(i);
+            let [a, b, c] = [triangle[0].xz(), triangle[1].xz(), triangle[2].xz()];
+            let area = (b - a).perp(&(c - a));
+            if area.abs() < 1e-12 {
+                continue;
+            }
+            let weights = [(c - b).perp(&(point - b)) / area, (a - c).perp(&(point - c)) / area];
+            let weights = [weights[0], weights[1], 1.0 - weights[0] - weights[1]];
+            if weights.iter().any(|&weight| weight < -1e-6) {
+                continue;
+            }
+            let height = weights[0] * triangle[0].y + weights[1] * triangle[1].y + weights[2] * triangle[2].y;
+            highest = Some(highest.map_or(height, |highest| highest.max(height)));
+        }
+        highest
+    }
+
+    fn check(terrain: &TerrainIndex, x: f32, z: f32) -> bool {
+        let expected = brute_force_height(terrain, x, z);
+        let ground = terrain.ground_at(x, z);
+        assert_eq!(ground.is_some(), expected.is_some(), "ground at {}, {}", x, z);
+        assert_eq!(terrain.height_at(x, z), ground.map(|ground| ground.point.y));
+        if let (Some(ground), Some(expected)) = (ground, expected) {
+            assert!((ground.point.y - expected).abs() < 1e-4, "{} instead of {} at {}, {}", ground.point.y, expected, x, z);
+            assert_eq!((ground.point.x, ground.point.z), (x, z));
+            assert!(ground.normal.y > 0.0);
+        }
+        ground.is_some()
+    }
+
+    #[test]
+    fn ground_is_the_highest_of_every_triangle_under_the_point() {
+        let terrain = test_terrain();
+        let mut state = 0x2545f491u32;
+        let mut random = move || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            (state >> 8) as f32 / (1 << 24) as f32
+        };
+
+        // Well past the edges too, where there is no ground
+        let mut found = 0;
+        for _ in 0..3000 {
+            if check(&terrain, random() * 110.0 - 55.0, random() * 110.0 - 55.0) {
+                found += 1;
+            }
+        }
+        assert!(found > 1000 && found < 3000, "ground under {} of the points", found);
+
+        // Points exactly on the borders of the cells, and along the edges of the grid
+        let (columns, rows) = (terrain.columns as i32, terrain.rows as i32);
+        for column in -1..=columns + 1 {
+            for row in -1..=rows + 1 {
+                let corner = terrain.min + glm::vec2(column as f32, row as f32) * terrain.cell_size;
+                check(&terrain, corner.x, corner.y);
+                check(&terrain, corner.x + terrain.cell_size * random(), corner.y);
+                check(&terrain, corner.x, corner.y + terrain.cell_size * random());
+            }
+        }
+        // The ledge is above the hills
+        assert!(terrain.height_at(0.0, 0.0).unwrap() > 20.0);
+        assert!(terrain.height_at(0.0, 10.0).unwrap() < 12.0);
+    }
+}
@@ -1,6 +1,8 @@
 extern crate nalgebra_glm as glm;
 use std::f64::consts::PI;
 
+const GRAVITY: f32 = 9.81;
+
 pub struct Heading {
     pub x     : f32,
     pub z     : f32,
@@ -34,3 +36,31 @@ pub fn simple_heading_animation(time: f32) -> Heading {
         yaw   : yaw   as f32,
     }
 }
+
+// The heading of something moving with the given velocity, facing along it. It banks like a
+// coordinated turn, where lift balances the pull towards the inside, and leans forward with the
+// speed, as a helicopter leans into its flight. The acceleration is what turns the velocity, per
+// second.
+pub fn heading_from_motion(
+    position: &glm::Vec3,
+    velocity: &glm::Vec3,
+    acceleration: &glm::Vec3,
+    max_bank: f32,
+    nose_down: f32,
+) -> Heading {
+    let speed      = glm::length(velocity);
+    let forward    = if speed > 1e-6 { velocity / speed } else { glm::zero() };
+    let horizontal = (forward.x * forward.x + forward.z * forward.z).sqrt();
+
+    let right    = glm::vec3(-forward.z, 0.0, forward.x);
+    let right    = if glm::length(&right) > 1e-6 { glm::normalize(&right) } else { right };
+    let sideways = glm::dot(acceleration, &right);
+
+    Heading {
+        x     : position.x,
+        z     : position.z,
+        roll  : (sideways / GRAVITY).atan().clamp(-max_bank, max_bank),
+        pitch : forward.y.atan2(horizontal) - nose_down * speed,
+        yaw   : (-forward.x).atan2(-forward.z),
+    }
+}
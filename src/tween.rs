@@ -0,0 +1,368 @@
+extern crate nalgebra_glm as glm;
+
+use std::f32::consts::PI;
+
+use crate::animation::Property;
+use crate::scene_graph::SceneNode;
+
+// How a tween speeds up and slows down on its way. In starts slowly, Out comes to a slow stop,
+// and InOut does both.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Easing {
+    Linear,
+    QuadIn,
+    QuadOut,
+    QuadInOut,
+    CubicIn,
+    CubicOut,
+    CubicInOut,
+    ElasticIn, // Winds up like a spring before it lets go
+    ElasticOut, // Overshoots and wobbles into place
+    ElasticInOut,
+    BounceIn,
+    BounceOut, // Bounces to a stop, like something dropped
+    BounceInOut,
+}
+
+impl Easing {
+    // How far along the way the tween is when `t` of its time has passed. Both go from 0 to 1,
+    // though the elastic curves go past either end along the way.
+    pub fn ease(self, t: f32) -> f32 {
+        let t = t.clamp(0.0, 1.0);
+        match self {
+            Easing::Linear => t,
+            Easing::QuadIn => t * t,
+            Easing::QuadOut => 1.0 - (1.0 - t) * (1.0 - t),
+            Easing::QuadInOut => {
+                if t < 0.5 {
+                    2.0 * t * t
+                } else {
+                    1.0 - (2.0 - 2.0 * t).powi(2) / 2.0
+                }
+            }
+            Easing::CubicIn => t * t * t,
+            Easing::CubicOut => 1.0 - (1.0 - t).powi(3),
+            Easing::CubicInOut => {
+                if t < 0.5 {
+                    4.0 * t * t * t
+                } else {
+                    1.0 - (2.0 - 2.0 * t).powi(3) / 2.0
+                }
+            }
+            Easing::ElasticIn => 1.0 - Easing::ElasticOut.ease(1.0 - t),
+            Easing::ElasticOut => {
+                if t == 0.0 || t == 1.0 {
+                    t
+                } else {
+                    2f32.powf(-10.0 * t) * ((10.0 * t - 0.75) * 2.0 * PI / 3.0).sin() + 1.0
+                }
+            }
+            Easing::ElasticInOut => {
+                if t < 0.5 {
+                    Easing::ElasticIn.ease(2.0 * t) / 2.0
+                } else {
+                    0.5 + Easing::ElasticOut.ease(2.0 * t - 1.0) / 2.0
+                }
+            }
+            Easing::BounceIn => 1.0 - Easing::BounceOut.ease(1.0 - t),
+            Easing::BounceOut => {
+                // Four arcs, each a lower bounce than the one before
+                let (n, d) = (7.5625, 2.75);
+                if t < 1.0 / d {
+                    n * t * t
+                } else if t < 2.0 / d {
+                    let t = t - 1.5 / d;
+                    n * t * t + 0.75
+                } else if t < 2.5 / d {
+                    let t = t - 2.25 / d;
+                    n * t * t + 0.9375
+                } else {
+                    let t = t - 2.625 / d;
+                    n * t * t + 0.984375
+                }
+            }
+            Easing::BounceInOut => {
+                if t < 0.5 {
+                    Easing::BounceIn.ease(2.0 * t) / 2.0
+                } else {
+                    0.5 + Easing::BounceOut.ease(2.0 * t - 1.0) / 2.0
+                }
+            }
+        }
+    }
+}
+
+// Moves one property of a node to a new value over some time, like
+//
+//     Tween::new(&mut door, Property::Position, glm::vec3(0.0, 0.0, 2.0))
+//         .over(1.2, Easing::CubicInOut)
+//         .on_complete(|| println!("Door open"))
+//         .then(Tween::new(&mut door, Property::Rotation, glm::vec3(0.0, 0.0, 0.3)))
+pub struct Tween {
+    pub property : Property,
+    pub from     : Option<glm::Vec3>, // Where the property is when the tween starts, unless given
+    pub to       : glm::Vec3,
+    pub duration : f32,
+    pub delay    : f32, // Seconds to wait before starting
+    pub easing   : Easing,
+
+    node: *mut SceneNode, // The node has to outlive the tween, like for animation players
+    on_complete: Option<Box<dyn FnOnce()>>,
+    next: Option<Box<Tween>>,
+}
+
+impl Tween {
+    pub fn new(node: &mut SceneNode, property: Property, to: glm::Vec3) -> Self {
+        Tween {
+            property,
+            from     : None,
+            to,
+            duration : 1.0,
+            delay    : 0.0,
+            easing   : Easing::Linear,
+            node     : node as *mut SceneNode,
+            on_complete: None,
+            next: None,
+        }
+    }
+
+    pub fn over(mut self, duration: f32, easing: Easing) -> Self {
+        self.duration = duration;
+        self.easing = easing;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn from(mut self, from: glm::Vec3) -> Self {
+        self.from = Some(from);
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn after(mut self, delay: f32) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    // Called once the property has reached its new value
+    pub fn on_complete(mut self, callback: impl FnOnce() + 'static) -> Self {
+        self.on_complete = Some(Box::new(callback));
+        self
+    }
+
+    // Start the given tween when this one, and any chained to it before, is done
+    pub fn then(mut self, tween: Tween) -> Self {
+        let mut last = &mut self;
+        while last.next.is_some() {
+            last = last.next.as_mut().unwrap();
+        }
+        last.next = Some(Box::new(tween));
+        self
+    }
+
+    fn value(&self) -> glm::Vec3 {
+        let node = unsafe { &*self.node };
+        match self.property {
+            Property::Position => node.position,
+            Property::Rotation => node.rotation,
+            Property::Scale => node.scale,
+        }
+    }
+
+    fn set_value(&self, value: glm::Vec3) {
+        let node = unsafe { &mut *self.node };
+        match self.property {
+            Property::Position => node.position = value,
+            Property::Rotation => node.rotation = value,
+            Property::Scale => node.scale = value,
+        }
+    }
+}
+
+struct RunningTween {
+    tween      : Tween,
+    start_time : f32,
+    from       : Option<glm::Vec3>, // Taken from the property once the delay is over
+}
+
+// Runs tweens against the simulation time. A tween replaces any other running on the same
+// property of the same node.
+pub struct Tweener {
+    running: Vec<RunningTween>,
+}
+
+impl Tweener {
+    pub fn new() -> Self {
+        Tweener { running: vec![] }
+    }
+
+    pub fn start(&mut self, tween: Tween, time: f32) {
+        self.running.retain(|running| running.tween.node != tween.node || running.tween.property != tween.property);
+        let from = tween.from;
+        self.running.push(RunningTween { tween, start_time: time, from });
+    }
+
+    // Stop every tween on the node, and whatever was chained to them, where they are
+    pub fn stop(&mut self, node: &SceneNode) {
+        let node = node as *const SceneNode as *mut SceneNode;
+        self.running.retain(|running| running.tween.node != node);
+    }
+
+    #[allow(dead_code)]
+    pub fn is_running(&self) -> bool {
+        !self.running.is_empty()
+    }
+
+    // Move every tween to the given time. Tweens that finish call back and start the next in
+    // their chain from the moment they finished, so a chain keeps its timing however long the
+    // steps are.
+    pub fn update(&mut self, time: f32) {
+        let mut i = 0;
+        while i < self.running.len() {
+            let running = &mut self.running[i];
+            let start = running.start_time + running.tween.delay;
+            if time < start {
+                i += 1;
+                continue;
+            }
+            let tween = &running.tween;
+            let from = *running.from.get_or_insert_with(|| tween.value());
+            let t = if tween.duration > 0.0 { (time - start) / tween.duration } else { 1.0 };
+            tween.set_value(from + (tween.to - from) * tween.easing.ease(t));
+            if t < 1.0 {
+                i += 1;
+                continue;
+            }
+
+            let mut finished = self.running.remove(i).tween;
+            if let Some(callback) = finished.on_complete.take() {
+                callback();
+            }
+            // Starting the next tween may replace others, so go over them all again. Updating a
+            // tween twice to the same time changes nothing.
+            if let Some(next) = finished.next.take() {
+                self.start(*next, start + finished.duration);
+                i = 0;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    const EASINGS: [Easing; 13] = [
+        Easing::Linear,
+        Easing::QuadIn,
+        Easing::QuadOut,
+        Easing::QuadInOut,
+        Easing::CubicIn,
+        Easing::CubicOut,
+        Easing::CubicInOut,
+        Easing::ElasticIn,
+        Easing::ElasticOut,
+        Easing::ElasticInOut,
+        Easing::BounceIn,
+        Easing::BounceOut,
+        Easing::BounceInOut,
+    ];
+
+    #[test]
+    fn every_easing_starts_at_0_and_ends_at_1() {
+        for &easing in &EASINGS {
+            assert!(easing.ease(0.0).abs() < 1e-6, "{:?} starts at {}", easing, easing.ease(0.0));
+            assert!((easing.ease(1.0) - 1.0).abs() < 1e-6, "{:?} ends at {}", easing, easing.ease(1.0));
+            // Times outside the tween are held at the ends
+            assert_eq!(easing.ease(-1.0), easing.ease(0.0));
+            assert_eq!(easing.ease(2.0), easing.ease(1.0));
+        }
+    }
+
+    #[test]
+    fn easings_without_springs_or_bounces_never_go_back() {
+        for &easing in &EASINGS[..7] {
+            let mut previous = easing.ease(0.0);
+            for i in 1..=1000 {
+                let eased = easing.ease(i as f32 / 1000.0);
+                assert!(eased >= previous, "{:?} goes back at {}", easing, i as f32 / 1000.0);
+                assert!(eased <= 1.0);
+                previous = eased;
+            }
+        }
+        // The springs go past the ends, the bounces only come back towards where they started
+        assert!((0..100).any(|i| Easing::ElasticOut.ease(i as f32 / 100.0) > 1.0));
+        assert!((0..100).any(|i| Easing::ElasticIn.ease(i as f32 / 100.0) < 0.0));
+        assert!((0..=100).all(|i| (0.0..=1.0 + 1e-6).contains(&Easing::BounceOut.ease(i as f32 / 100.0))));
+    }
+
+    #[test]
+    fn chained_tweens_start_when_the_one_before_ends() {
+        let mut node = SceneNode::new();
+        let mut tweener = Tweener::new();
+        let tween = Tween::new(&mut node, Property::Position, glm::vec3(4.0, 0.0, 0.0))
+            .over(1.0, Easing::Linear)
+            .then(Tween::new(&mut node, Property::Position, glm::vec3(4.0, 2.0, 0.0)).over(2.0, Easing::Linear))
+            .then(Tween::new(&mut node, Property::Scale, glm::vec3(3.0, 3.0, 3.0)).over(0.5, Easing::Linear));
+        tweener.start(tween, 10.0);
+
+        tweener.update(10.5);
+        assert_eq!(node.position, glm::vec3(2.0, 0.0, 0.0));
+        // Well past the end of the first, so the second has been going for a quarter of its time
+        tweener.update(11.5);
+        assert_eq!(node.position, glm::vec3(4.0, 0.5, 0.0));
+        tweener.update(13.0);
+        assert_eq!(node.position, glm::vec3(4.0, 2.0, 0.0));
+        assert_eq!(node.scale, glm::vec3(1.0, 1.0, 1.0));
+        tweener.update(13.25);
+        assert_eq!(node.scale, glm::vec3(2.0, 2.0, 2.0));
+        assert!(tweener.is_running());
+
+        tweener.update(20.0);
+        assert_eq!(node.scale, glm::vec3(3.0, 3.0, 3.0));
+        assert!(!tweener.is_running());
+    }
+
+    #[test]
+    fn a_long_step_runs_the_whole_chain_at_once() {
+        let mut node = SceneNode::new();
+        let mut tweener = Tweener::new();
+        let tween = Tween::new(&mut node, Property::Position, glm::vec3(1.0, 0.0, 0.0))
+            .then(Tween::new(&mut node, Property::Position, glm::vec3(1.0, 1.0, 0.0)).after(0.5))
+            .then(Tween::new(&mut node, Property::Position, glm::vec3(1.0, 1.0, 1.0)));
+        tweener.start(tween, 0.0);
+
+        tweener.update(100.0);
+        assert_eq!(node.position, glm::vec3(1.0, 1.0, 1.0));
+        assert!(!tweener.is_running());
+    }
+
+    #[test]
+    fn completion_is_reported_exactly_once() {
+        let mut node = SceneNode::new();
+        let mut tweener = Tweener::new();
+        let (first, second) = (Rc::new(Cell::new(0)), Rc::new(Cell::new(0)));
+        let (first_done, second_done) = (Rc::clone(&first), Rc::clone(&second));
+        let tween = Tween::new(&mut node, Property::Rotation, glm::vec3(0.0, 1.0, 0.0))
+            .over(0.5, Easing::ElasticOut)
+            .on_complete(move || first_done.set(first_done.get() + 1))
+            .then(
+                Tween::new(&mut node, Property::Rotation, glm::zero())
+                    .from(glm::vec3(0.0, 2.0, 0.0))
+                    .on_complete(move || second_done.set(second_done.get() + 1)),
+            );
+        tweener.start(tween, 0.0);
+
+        for step in 0..=60 {
+            tweener.update(step as f32 / 20.0);
+            assert_eq!(first.get(), (step >= 10) as i32, "at step {}", step);
+            assert_eq!(second.get(), (step >= 30) as i32, "at step {}", step);
+        }
+        // Going over the same time again doesn't report anything new either
+        tweener.update(3.0);
+        assert_eq!((first.get(), second.get()), (1, 1));
+    }
+}
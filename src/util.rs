@@ -1,32 +1,365 @@
-use std::ffi::CString;
+use std::collections::HashSet;
+use std::ffi::CStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
 
 pub unsafe fn get_gl_string(name: gl::types::GLenum) -> String {
     std::ffi::CStr::from_ptr(gl::GetString(name) as *mut i8).to_string_lossy().to_string()
 }
 
-// Debug callback to panic upon enountering any OpenGL error
+// How severe a debug message is, ordered from least to most severe
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DebugSeverity {
+    Notification,
+    Low,
+    Medium,
+    High,
+}
+
+impl DebugSeverity {
+    pub fn from_gl(severity: u32) -> Self {
+        match severity {
+            gl::DEBUG_SEVERITY_HIGH => DebugSeverity::High,
+            gl::DEBUG_SEVERITY_MEDIUM => DebugSeverity::Medium,
+            gl::DEBUG_SEVERITY_LOW => DebugSeverity::Low,
+            _ => DebugSeverity::Notification,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            DebugSeverity::High => "high",
+            DebugSeverity::Medium => "medium",
+            DebugSeverity::Low => "low",
+            DebugSeverity::Notification => "notification",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "high" => Some(DebugSeverity::High),
+            "medium" => Some(DebugSeverity::Medium),
+            "low" => Some(DebugSeverity::Low),
+            "notification" => Some(DebugSeverity::Notification),
+            _ => None,
+        }
+    }
+}
+
+// Where a debug message came from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DebugSource {
+    Api,
+    WindowSystem,
+    ShaderCompiler,
+    ThirdParty,
+    Application,
+    Other,
+}
+
+impl DebugSource {
+    pub fn from_gl(source: u32) -> Self {
+        match source {
+            gl::DEBUG_SOURCE_API => DebugSource::Api,
+            gl::DEBUG_SOURCE_WINDOW_SYSTEM => DebugSource::WindowSystem,
+            gl::DEBUG_SOURCE_SHADER_COMPILER => DebugSource::ShaderCompiler,
+            gl::DEBUG_SOURCE_THIRD_PARTY => DebugSource::ThirdParty,
+            gl::DEBUG_SOURCE_APPLICATION => DebugSource::Application,
+            _ => DebugSource::Other,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            DebugSource::Api => "api",
+            DebugSource::WindowSystem => "window system",
+            DebugSource::ShaderCompiler => "shader compiler",
+            DebugSource::ThirdParty => "third party",
+            DebugSource::Application => "application",
+            DebugSource::Other => "other",
+        }
+    }
+}
+
+// What a debug message is about
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DebugKind {
+    Error,
+    DeprecatedBehavior,
+    UndefinedBehavior,
+    Portability,
+    Performance,
+    Marker,
+    PushGroup,
+    PopGroup,
+    Other,
+}
+
+impl DebugKind {
+    pub fn from_gl(e_type: u32) -> Self {
+        match e_type {
+            gl::DEBUG_TYPE_ERROR => DebugKind::Error,
+            gl::DEBUG_TYPE_DEPRECATED_BEHAVIOR => DebugKind::DeprecatedBehavior,
+            gl::DEBUG_TYPE_UNDEFINED_BEHAVIOR => DebugKind::UndefinedBehavior,
+            gl::DEBUG_TYPE_PORTABILITY => DebugKind::Portability,
+            gl::DEBUG_TYPE_PERFORMANCE => DebugKind::Performance,
+            gl::DEBUG_TYPE_MARKER => DebugKind::Marker,
+            gl::DEBUG_TYPE_PUSH_GROUP => DebugKind::PushGroup,
+            gl::DEBUG_TYPE_POP_GROUP => DebugKind::PopGroup,
+            _ => DebugKind::Other,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            DebugKind::Error => "error",
+            DebugKind::DeprecatedBehavior => "deprecated behavior",
+            DebugKind::UndefinedBehavior => "undefined behavior",
+            DebugKind::Portability => "portability",
+            DebugKind::Performance => "performance",
+            DebugKind::Marker => "marker",
+            DebugKind::PushGroup => "push group",
+            DebugKind::PopGroup => "pop group",
+            DebugKind::Other => "other",
+        }
+    }
+}
+
+// A single message reported by the OpenGL driver
+#[derive(Clone, Debug)]
+pub struct DebugMessage {
+    pub source   : DebugSource,
+    pub kind     : DebugKind,
+    pub id       : u32,
+    pub severity : DebugSeverity,
+    pub message  : String,
+}
+
+impl std::fmt::Display for DebugMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "[GL {}] {} {} from {}: {}",
+            self.severity.name(), self.kind.name(), self.id, self.source.name(), self.message)
+    }
+}
+
+// What the sink should do with a message that passes its filters
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DebugAction {
+    Log,   // Print the message and keep going
+    Count, // Silently count the message
+    Panic, // Take the render thread down on the next call to DebugSink::check
+}
+
+impl DebugAction {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "log" => Some(DebugAction::Log),
+            "count" => Some(DebugAction::Count),
+            "panic" => Some(DebugAction::Panic),
+            _ => None,
+        }
+    }
+}
+
+// Receives the messages from debug_callback. Install it with install_debug_sink, after which the
+// counters and suppression list can be used from the rendering thread.
+pub struct DebugSink {
+    pub action       : DebugAction,
+    pub min_severity : DebugSeverity,
+    suppressed       : Mutex<HashSet<u32>>,
+    received         : AtomicUsize,
+    errors           : AtomicUsize,
+    fatal            : Mutex<Option<DebugMessage>>,
+}
+
+impl DebugSink {
+    pub fn new(action: DebugAction) -> Self {
+        DebugSink {
+            action,
+            min_severity : DebugSeverity::Low,
+            suppressed   : Mutex::new(HashSet::new()),
+            received     : AtomicUsize::new(0),
+            errors       : AtomicUsize::new(0),
+            fatal        : Mutex::new(None),
+        }
+    }
+
+    pub fn with_min_severity(mut self, severity: DebugSeverity) -> Self {
+        self.min_severity = severity;
+        self
+    }
+
+    // Ignore every future message with the given id
+    pub fn suppress(&self, id: u32) {
+        if let Ok(mut suppressed) = self.suppressed.lock() {
+            suppressed.insert(id);
+        }
+    }
+
+    // Number of messages that passed the filters so far
+    pub fn received(&self) -> usize {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    // Number of messages of type DEBUG_TYPE_ERROR that passed the filters so far
+    pub fn errors(&self) -> usize {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    pub fn handle(&self, message: DebugMessage) {
+        if message.severity < self.min_severity {
+            return;
+        }
+        if let Ok(suppressed) = self.suppressed.lock() {
+            if suppressed.contains(&message.id) {
+                return;
+            }
+        }
+
+        self.received.fetch_add(1, Ordering::Relaxed);
+        if message.kind == DebugKind::Error {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+
+        match self.action {
+            DebugAction::Log => println!("{}", message),
+            DebugAction::Count => {}
+            DebugAction::Panic => {
+                // Unwinding out of the callback would abort the whole process, so the panic is
+                // deferred until the rendering thread calls check()
+                println!("{}", message);
+                if let Ok(mut fatal) = self.fatal.lock() {
+                    fatal.get_or_insert(message);
+                }
+            }
+        }
+    }
+
+    // Panic if a message was received while the action is DebugAction::Panic
+    pub fn check(&self) {
+        if let Ok(mut fatal) = self.fatal.lock() {
+            if let Some(message) = fatal.take() {
+                panic!("{}", message);
+            }
+        }
+    }
+}
+
+impl Default for DebugSink {
+    fn default() -> Self {
+        DebugSink::new(DebugAction::Log)
+    }
+}
+
+// Hand the sink to OpenGL. The sink is leaked, since the driver may call back into it for as long
+// as the context lives.
+pub unsafe fn install_debug_sink(sink: DebugSink) -> &'static DebugSink {
+    let sink: &'static DebugSink = Box::leak(Box::new(sink));
+    gl::Enable(gl::DEBUG_OUTPUT);
+    gl::Enable(gl::DEBUG_OUTPUT_SYNCHRONOUS);
+    gl::DebugMessageCallback(
+        Some(debug_callback),
+        sink as *const DebugSink as *const std::ffi::c_void,
+    );
+    sink
+}
+
+// Debug callback forwarding every OpenGL message to the installed DebugSink. Without a sink, the
+// messages are simply logged.
 pub extern "system" fn debug_callback(
     source: u32, e_type: u32, id: u32,
-    severity: u32, _length: i32,
-    msg: *const i8, _data: *mut std::ffi::c_void
+    severity: u32, length: i32,
+    msg: *const i8, data: *mut std::ffi::c_void
 ) {
-    if e_type != gl::DEBUG_TYPE_ERROR { return }
-    if severity == gl::DEBUG_SEVERITY_HIGH ||
-       severity == gl::DEBUG_SEVERITY_MEDIUM ||
-       severity == gl::DEBUG_SEVERITY_LOW
-    {
-        let severity_string = match severity {
-            gl::DEBUG_SEVERITY_HIGH => "high",
-            gl::DEBUG_SEVERITY_MEDIUM => "medium",
-            gl::DEBUG_SEVERITY_LOW => "low",
-            _ => "unknown",
-        };
-        unsafe {
-            let string = CString::from_raw(msg as *mut i8);
-            let error_message = String::from_utf8_lossy(string.as_bytes()).to_string();
-            panic!("{}: Error of severity {} raised from {}: {}\n",
-                id, severity_string, source, error_message);
+    // The message is owned by the driver, so only borrow it. The length excludes the null
+    // terminator, and a negative length means we have to look for the terminator ourselves.
+    let text = unsafe {
+        if msg.is_null() {
+            String::new()
+        } else if length >= 0 {
+            let bytes = std::slice::from_raw_parts(msg as *const u8, length as usize + 1);
+            match CStr::from_bytes_with_nul(bytes) {
+                Ok(string) => string.to_string_lossy().to_string(),
+                Err(_) => String::from_utf8_lossy(&bytes[..length as usize]).to_string(),
+            }
+        } else {
+            CStr::from_ptr(msg).to_string_lossy().to_string()
         }
+    };
+
+    let message = DebugMessage {
+        source   : DebugSource::from_gl(source),
+        kind     : DebugKind::from_gl(e_type),
+        id,
+        severity : DebugSeverity::from_gl(severity),
+        message  : text.trim_end().to_string(),
+    };
+
+    if data.is_null() {
+        DebugSink::default().handle(message);
+    } else {
+        let sink = unsafe { &*(data as *const DebugSink) };
+        sink.handle(message);
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: u32, kind: DebugKind, severity: DebugSeverity) -> DebugMessage {
+        DebugMessage { source: DebugSource::Api, kind, id, severity, message: format!("message {}", id) }
+    }
+
+    #[test]
+    fn messages_below_the_minimum_severity_are_ignored() {
+        let sink = DebugSink::new(DebugAction::Count).with_min_severity(DebugSeverity::Medium);
+        sink.handle(message(1, DebugKind::Error, DebugSeverity::Notification));
+        sink.handle(message(2, DebugKind::Error, DebugSeverity::Low));
+        assert_eq!((sink.received(), sink.errors()), (0, 0));
+
+        sink.handle(message(3, DebugKind::Performance, DebugSeverity::Medium));
+        sink.handle(message(4, DebugKind::Error, DebugSeverity::High));
+        assert_eq!((sink.received(), sink.errors()), (2, 1));
+    }
+
+    #[test]
+    fn suppressed_ids_are_ignored() {
+        let sink = DebugSink::new(DebugAction::Count);
+        sink.suppress(131185);
+        sink.handle(message(131185, DebugKind::Other, DebugSeverity::High));
+        sink.handle(message(131185, DebugKind::Error, DebugSeverity::High));
+        assert_eq!((sink.received(), sink.errors()), (0, 0));
+
+        sink.handle(message(1282, DebugKind::Error, DebugSeverity::High));
+        assert_eq!((sink.received(), sink.errors()), (1, 1));
+    }
+
+    #[test]
+    fn panics_wait_for_check_and_report_the_first_message() {
+        let sink = DebugSink::new(DebugAction::Panic);
+        sink.check();
+        sink.handle(message(1, DebugKind::Error, DebugSeverity::High));
+        sink.handle(message(2, DebugKind::Error, DebugSeverity::High));
+        assert_eq!(sink.received(), 2);
+
+        let panic = std::panic::catch_unwind(|| sink.check()).expect_err("check should panic");
+        let text = panic.downcast_ref::<String>().unwrap();
+        assert_eq!(text, "[GL high] error 1 from api: message 1");
+        // The message is only reported once
+        sink.check();
+    }
+
+    #[test]
+    fn names_round_trip() {
+        for &action in &[DebugAction::Log, DebugAction::Count, DebugAction::Panic] {
+            let name = format!("{:?}", action).to_lowercase();
+            assert_eq!(DebugAction::from_name(&name), Some(action));
+        }
+        for &severity in &[DebugSeverity::Notification, DebugSeverity::Low, DebugSeverity::Medium, DebugSeverity::High] {
+            assert_eq!(DebugSeverity::from_name(severity.name()), Some(severity));
+        }
+        assert_eq!(DebugSeverity::from_name("severe"), None);
+        assert_eq!(DebugAction::from_name("abort"), None);
+    }
+}